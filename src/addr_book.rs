@@ -0,0 +1,246 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env::current_dir;
+use std::fs;
+
+const PEERS_FILE: &str = "peers_{}.dat";
+const MAX_ADDRESSES: usize = 1000;
+const ADDRESS_HORIZON: i64 = 30 * 24 * 60 * 60; // addresses not seen for this long are forgotten
+const MAX_FAILURES: u32 = 3; // failed connections in a row after which an address is forgotten
+
+// an address of a node that accepts connections, as gossiped in addr messages
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NetAddress {
+    pub addr: String,
+    pub timestamp: i64, // unix time the node was last known to be up
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct KnownAddress {
+    timestamp: i64,
+    last_attempt: i64, // unix time of the last connection attempt, 0 if never tried
+    failures: u32,
+}
+
+/*The nodes we know of, learned from version and addr messages. The
+table is bounded, forgets stale addresses, and is kept in the data dir
+so a restarted node does not depend on the seed node alone. */
+#[derive(Serialize, Deserialize, Default)]
+pub struct AddrBook {
+    addresses: HashMap<String, KnownAddress>,
+}
+
+// creates an AddrBook and fills it from a file if it exists
+pub fn new_addr_book(node_id: &str) -> AddrBook {
+    let mut addr_book: AddrBook = fs::read(peers_path(node_id))
+        .ok()
+        .and_then(|data| bincode::deserialize(&data).ok())
+        .unwrap_or_default();
+    addr_book.expire();
+    addr_book
+}
+
+impl AddrBook {
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    pub fn get_addresses(&self) -> Vec<String> {
+        self.addresses.keys().cloned().collect()
+    }
+
+    /*adds an address or refreshes its timestamp, making room by dropping
+    the least recently seen address. Returns whether the address is new. */
+    pub fn add(&mut self, addr: String, timestamp: i64) -> bool {
+        if let Some(known) = self.addresses.get_mut(&addr) {
+            known.timestamp = known.timestamp.max(timestamp);
+            return false;
+        }
+        if self.addresses.len() >= MAX_ADDRESSES {
+            self.expire();
+        }
+        if self.addresses.len() >= MAX_ADDRESSES {
+            let oldest = self
+                .addresses
+                .iter()
+                .min_by_key(|(_, known)| known.timestamp)
+                .map(|(addr, _)| addr.clone());
+            if let Some(oldest) = oldest {
+                self.addresses.remove(&oldest);
+            }
+        }
+        self.addresses.insert(
+            addr,
+            KnownAddress {
+                timestamp,
+                last_attempt: 0,
+                failures: 0,
+            },
+        );
+        true
+    }
+
+    pub fn mark_attempt(&mut self, addr: &str, now: i64) {
+        if let Some(known) = self.addresses.get_mut(addr) {
+            known.last_attempt = now;
+        }
+    }
+
+    // records a successful connection
+    pub fn mark_good(&mut self, addr: &str, now: i64) {
+        if let Some(known) = self.addresses.get_mut(addr) {
+            known.timestamp = now;
+            known.failures = 0;
+        }
+    }
+
+    // records a failed connection, forgetting the address after too many
+    pub fn mark_failed(&mut self, addr: &str) {
+        if let Some(known) = self.addresses.get_mut(addr) {
+            known.failures += 1;
+            if known.failures >= MAX_FAILURES {
+                self.addresses.remove(addr);
+            }
+        }
+    }
+
+    /*picks a random address that is not excluded and was not tried
+    in the last retry_interval seconds */
+    pub fn select(&self, exclude: &[String], now: i64, retry_interval: i64) -> Option<String> {
+        let candidates: Vec<&String> = self
+            .addresses
+            .iter()
+            .filter(|(addr, known)| {
+                !exclude.contains(addr) && now - known.last_attempt >= retry_interval
+            })
+            .map(|(addr, _)| addr)
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(candidates[random_index(candidates.len())].clone())
+    }
+
+    // returns up to max random addresses with their timestamps, for an addr message
+    pub fn sample(&self, max: usize) -> Vec<NetAddress> {
+        let mut addresses: Vec<NetAddress> = self
+            .addresses
+            .iter()
+            .map(|(addr, known)| NetAddress {
+                addr: addr.clone(),
+                timestamp: known.timestamp,
+            })
+            .collect();
+        // partial Fisher-Yates shuffle of the first max entries
+        let count = max.min(addresses.len());
+        for i in 0..count {
+            let j = i + random_index(addresses.len() - i);
+            addresses.swap(i, j);
+        }
+        addresses.truncate(count);
+        addresses
+    }
+
+    // saves the address book to a file
+    pub fn save_to_file(&self, node_id: &str) -> Result<(), String> {
+        let data = bincode::serialize(self).map_err(|e| e.to_string())?;
+        fs::write(peers_path(node_id), data).map_err(|e| e.to_string())
+    }
+
+    fn expire(&mut self) {
+        let horizon = chrono::Utc::now().timestamp() - ADDRESS_HORIZON;
+        self.addresses.retain(|_, known| known.timestamp > horizon);
+    }
+}
+
+fn peers_path(node_id: &str) -> std::path::PathBuf {
+    current_dir()
+        .unwrap()
+        .join(PEERS_FILE.replace("{}", node_id))
+}
+
+// returns a random index below len, which must not be 0
+fn random_index(len: usize) -> usize {
+    let mut bytes = [0u8; 8];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    (u64::from_le_bytes(bytes) % len as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+
+    #[test]
+    fn addresses_are_tried_again_after_the_retry_interval() {
+        let mut addr_book = AddrBook::default();
+        assert!(addr_book.add("127.0.0.1:3001".to_string(), now()));
+        assert!(!addr_book.add("127.0.0.1:3001".to_string(), now()));
+        assert_eq!(
+            addr_book.select(&[], now(), 60).as_deref(),
+            Some("127.0.0.1:3001")
+        );
+        assert_eq!(
+            addr_book.select(&["127.0.0.1:3001".to_string()], now(), 60),
+            None
+        );
+
+        addr_book.mark_attempt("127.0.0.1:3001", now());
+        assert_eq!(addr_book.select(&[], now(), 60), None);
+        assert!(addr_book.select(&[], now() + 60, 60).is_some());
+    }
+
+    #[test]
+    fn addresses_failing_in_a_row_are_forgotten() {
+        let mut addr_book = AddrBook::default();
+        addr_book.add("127.0.0.1:3001".to_string(), now());
+        for _ in 1..MAX_FAILURES {
+            addr_book.mark_failed("127.0.0.1:3001");
+        }
+        addr_book.mark_good("127.0.0.1:3001", now());
+        addr_book.mark_failed("127.0.0.1:3001");
+        assert_eq!(addr_book.len(), 1);
+        for _ in 1..MAX_FAILURES {
+            addr_book.mark_failed("127.0.0.1:3001");
+        }
+        assert!(addr_book.is_empty());
+    }
+
+    #[test]
+    fn a_full_book_drops_the_least_recently_seen_address() {
+        let mut addr_book = AddrBook::default();
+        let now = now();
+        for port in 0..MAX_ADDRESSES as i64 {
+            addr_book.add(format!("127.0.0.1:{}", port), now - port);
+        }
+        let oldest = format!("127.0.0.1:{}", MAX_ADDRESSES - 1);
+        assert!(addr_book.get_addresses().contains(&oldest));
+
+        addr_book.add("127.0.0.2:3000".to_string(), now);
+        assert_eq!(addr_book.len(), MAX_ADDRESSES);
+        assert!(!addr_book.get_addresses().contains(&oldest));
+        assert_eq!(addr_book.sample(10).len(), 10);
+    }
+
+    #[test]
+    fn the_book_is_saved_without_stale_addresses() {
+        test_util::enter_data_dir();
+        let mut addr_book = AddrBook::default();
+        addr_book.add("127.0.0.1:3001".to_string(), now());
+        addr_book.add("127.0.0.1:3002".to_string(), now() - ADDRESS_HORIZON - 1);
+        addr_book.save_to_file("addr_book_saved").unwrap();
+
+        let loaded = new_addr_book("addr_book_saved");
+        assert_eq!(loaded.get_addresses(), vec!["127.0.0.1:3001".to_string()]);
+        assert!(new_addr_book("addr_book_missing").is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env::current_dir;
+use std::fs;
+use std::net::IpAddr;
+
+const BAN_LIST_FILE: &str = "banlist_{}.dat";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Ban {
+    pub until: i64, // unix time at which the ban expires
+    pub reason: String,
+}
+
+/*Hosts that misbehaved are refused for a while. The list is kept in
+the data dir, so bans survive restarts and can be edited from the CLI
+while the node runs. */
+#[derive(Serialize, Deserialize, Default)]
+pub struct BanList {
+    bans: HashMap<IpAddr, Ban>,
+}
+
+// creates a BanList and fills it from a file if it exists, dropping expired bans
+pub fn new_ban_list(node_id: &str) -> BanList {
+    let mut ban_list: BanList = fs::read(ban_list_path(node_id))
+        .ok()
+        .and_then(|data| bincode::deserialize(&data).ok())
+        .unwrap_or_default();
+    let now = chrono::Utc::now().timestamp();
+    ban_list.bans.retain(|_, ban| ban.until > now);
+    ban_list
+}
+
+impl BanList {
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.bans
+            .get(ip)
+            .is_some_and(|ban| ban.until > chrono::Utc::now().timestamp())
+    }
+
+    // bans ip for duration seconds, extending an existing ban if needed
+    pub fn ban(&mut self, ip: IpAddr, duration: i64, reason: String) {
+        let until = chrono::Utc::now().timestamp() + duration;
+        let ban = self.bans.entry(ip).or_insert(Ban {
+            until,
+            reason: reason.clone(),
+        });
+        if ban.until < until {
+            *ban = Ban { until, reason };
+        }
+    }
+
+    // returns the banned hosts, the ones expiring first first
+    pub fn get_bans(&self) -> Vec<(IpAddr, Ban)> {
+        let mut bans: Vec<(IpAddr, Ban)> = self
+            .bans
+            .iter()
+            .map(|(ip, ban)| (*ip, ban.clone()))
+            .collect();
+        bans.sort_by_key(|(_, ban)| ban.until);
+        bans
+    }
+
+    pub fn clear(&mut self) {
+        self.bans.clear();
+    }
+
+    // saves the ban list to a file
+    pub fn save_to_file(&self, node_id: &str) -> Result<(), String> {
+        let data = bincode::serialize(self).map_err(|e| e.to_string())?;
+        fs::write(ban_list_path(node_id), data).map_err(|e| e.to_string())
+    }
+}
+
+fn ban_list_path(node_id: &str) -> std::path::PathBuf {
+    current_dir()
+        .unwrap()
+        .join(BAN_LIST_FILE.replace("{}", node_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    #[test]
+    fn a_ban_is_only_extended() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut ban_list = BanList::default();
+        assert!(!ban_list.is_banned(&ip));
+        ban_list.ban(ip, 60, "first".to_string());
+        ban_list.ban(ip, 10, "shorter".to_string());
+        assert!(ban_list.is_banned(&ip));
+        assert_eq!(ban_list.get_bans()[0].1.reason, "first");
+        ban_list.ban(ip, 120, "longer".to_string());
+        assert_eq!(ban_list.get_bans()[0].1.reason, "longer");
+        ban_list.clear();
+        assert!(!ban_list.is_banned(&ip));
+    }
+
+    #[test]
+    fn bans_are_saved_until_they_expire() {
+        test_util::enter_data_dir();
+        let (banned, expired): (IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "::1".parse().unwrap());
+        let mut ban_list = BanList::default();
+        ban_list.ban(banned, 60, "misbehaved".to_string());
+        ban_list.ban(expired, -1, "misbehaved".to_string());
+        assert!(!ban_list.is_banned(&expired));
+        ban_list.save_to_file("ban_list_saved").unwrap();
+
+        let loaded = new_ban_list("ban_list_saved");
+        assert!(loaded.is_banned(&banned));
+        assert_eq!(loaded.get_bans().len(), 1);
+        assert!(new_ban_list("ban_list_missing").get_bans().is_empty());
+    }
+}
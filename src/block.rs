@@ -4,6 +4,8 @@ use crate::Transaction;
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 
+pub const MAX_BLOCK_SIZE: usize = 1_000_000; // of the serialized transactions, in bytes
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Block {
     timestamp: i64,                 // current timestamp(when the block is created)
@@ -14,6 +16,44 @@ pub struct Block {
     height: usize,                  // block height
 }
 
+/*The header carries everything proof of work commits to, so a
+node can validate a chain of headers before downloading any bodies. */
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    timestamp: i64,
+    prev_block_hash: Vec<u8>,
+    merkle_root: Vec<u8>, // hash of the block transactions
+    hash: Vec<u8>,
+    nonce: i64,
+    height: usize,
+}
+
+impl BlockHeader {
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn get_prev_block_hash(&self) -> Vec<u8> {
+        self.prev_block_hash.clone()
+    }
+
+    pub fn get_merkle_root(&self) -> Vec<u8> {
+        self.merkle_root.clone()
+    }
+
+    pub fn get_hash(&self) -> Vec<u8> {
+        self.hash.clone()
+    }
+
+    pub fn get_nonce(&self) -> i64 {
+        self.nonce
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+}
+
 impl Block {
     pub fn new_block(
         transactions: Vec<Transaction>,
@@ -33,6 +73,20 @@ impl Block {
         block
     }
 
+    /*puts a block back together from its header and transactions, as
+    received in a compact block. Proof of work validation reveals wrong
+    transactions, since the header commits to their merkle root. */
+    pub fn from_header(header: &BlockHeader, transactions: Vec<Transaction>) -> Block {
+        Block {
+            timestamp: header.get_timestamp(),
+            transactions,
+            prev_block_hash: header.get_prev_block_hash(),
+            hash: header.get_hash(),
+            nonce: header.get_nonce(),
+            height: header.get_height(),
+        }
+    }
+
     pub fn new_genesis_block(coinbase: Vec<Transaction>) -> Block {
         Block::new_block(coinbase, vec![], 0)
     }
@@ -56,6 +110,17 @@ impl Block {
         bincode::deserialize(&data).unwrap()
     }
 
+    pub fn get_header(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp,
+            prev_block_hash: self.prev_block_hash.clone(),
+            merkle_root: self.hash_transactions(),
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+            height: self.height,
+        }
+    }
+
     pub fn get_prev_block_hash(&self) -> Vec<u8> {
         self.prev_block_hash.clone()
     }
@@ -1,12 +1,21 @@
 use crate::transaction;
+use crate::transaction::RelativeLock;
 use crate::Block;
+use crate::BlockHeader;
 use crate::TXOutput;
 use crate::Transaction;
+use crate::UtxoSet;
 use sled::Db;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 
 const DB_FILE: &str = "blockchain_{}.db";
 const TIP_BLOCK_HASH: &str = "blocks"; // key for the last block hash
+const FORMAT_KEY: &str = "format"; // key for the version of the block and transaction encoding
+const FORMAT_VERSION: u32 = 2; // transactions with scripts and lock times
+const LOCATOR_DENSE_LENGTH: usize = 10; // number of tip hashes included one by one
+const MEDIAN_TIME_SPAN: usize = 11; // number of last blocks whose timestamps give the median time past
+pub const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60 * 1000; // how far ahead of our clock a block timestamp may be, in ms
 const GENESIS_COINBASE_DATA: &str =
     "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
 
@@ -20,24 +29,28 @@ impl Blockchain {
     pub fn get_db(&self) -> &Db {
         &self.db
     }
-    pub fn create_blockchain(address: String, node_id: String) -> Blockchain {
+    pub fn create_blockchain(address: String, node_id: String) -> Result<Blockchain, String> {
         let path = DB_FILE.replace("{}", &node_id);
         let db = sled::open(path).expect("open");
         let data = db.get(TIP_BLOCK_HASH).unwrap();
         let tip;
         if data.is_none() {
             println!("No existing blockchain found. Creating a new one...");
-            let coinbase = transaction::new_coinbase_tx(address, GENESIS_COINBASE_DATA.to_string());
+            let coinbase =
+                transaction::new_coinbase_tx(address, GENESIS_COINBASE_DATA.to_string(), 0);
             let genesis = Block::new_genesis_block(vec![coinbase]);
             let genesis_hash = genesis.get_hash();
             db.insert(genesis_hash.clone(), genesis.serialize())
                 .unwrap();
             db.insert(TIP_BLOCK_HASH, genesis_hash.clone()).unwrap();
+            db.insert(FORMAT_KEY, &FORMAT_VERSION.to_be_bytes())
+                .unwrap();
             tip = genesis_hash;
         } else {
+            check_format(&db, &node_id)?;
             tip = data.unwrap().to_vec();
         }
-        Blockchain { tip, db }
+        Ok(Blockchain { tip, db })
     }
 
     pub fn new_blockchain(node_id: String) -> Result<Blockchain, String> {
@@ -48,6 +61,7 @@ impl Blockchain {
         if data.is_none() {
             return Err("No existing blockchain found. Create one first.".to_string());
         } else {
+            check_format(&db, &node_id)?;
             tip = data.unwrap().to_vec();
         }
         Ok(Blockchain { tip, db })
@@ -61,21 +75,26 @@ impl Blockchain {
         tip_block.get_height()
     }
 
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Block {
-        for tx in &transactions {
-            if !self.verify_transaction(tx) {
-                panic!("ERROR: Invalid transaction");
-            }
-        }
+    pub fn get_tip_hash(&self) -> Vec<u8> {
+        self.tip.clone()
+    }
+
+    /*mines a block of transactions on top of the tip, if they pass the
+    checks of a block received from a peer */
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block, String> {
         let best_height = self.get_best_height();
+        self.check_transactions(&transactions, best_height + 1, &self.tip)?;
         let block = Block::new_block(transactions, self.tip.clone(), best_height + 1);
+        if block.get_timestamp() <= self.get_median_time_past(&self.tip) {
+            return Err("our clock is behind the median time past".to_string());
+        }
         let block_hash = block.get_hash();
         self.db
             .insert(block_hash.clone(), block.serialize())
             .unwrap();
         self.db.insert(TIP_BLOCK_HASH, block_hash.clone()).unwrap();
         self.tip = block_hash;
-        block
+        Ok(block)
     }
 
     pub fn add_block(&mut self, block: Block) {
@@ -104,6 +123,59 @@ impl Blockchain {
         Some(Block::deserialize_block(data.unwrap().to_vec()))
     }
 
+    pub fn has_block(&self, block_hash: &[u8]) -> bool {
+        self.db.contains_key(block_hash).unwrap()
+    }
+
+    /*A block locator describes our main chain to a peer: the last
+    hashes one by one, then exponentially sparser back to genesis.
+    The peer answers from the first locator hash it has in its chain. */
+    pub fn get_block_locator(&self) -> Vec<Vec<u8>> {
+        let hashes = self.get_block_hashes();
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut index = 0;
+        while index < hashes.len() {
+            locator.push(hashes[index].clone());
+            if locator.len() >= LOCATOR_DENSE_LENGTH {
+                step *= 2;
+            }
+            index += step;
+        }
+        if let Some(genesis_hash) = hashes.last() {
+            if locator.last() != Some(genesis_hash) {
+                locator.push(genesis_hash.clone());
+            }
+        }
+        locator
+    }
+
+    // returns up to max main chain headers after the fork point described by locator,
+    // stopping at stop_hash
+    pub fn find_headers(
+        &self,
+        locator: &[Vec<u8>],
+        stop_hash: &[u8],
+        max: usize,
+    ) -> Vec<BlockHeader> {
+        let mut hashes = self.get_block_hashes();
+        hashes.reverse();
+        let start = locator
+            .iter()
+            .find_map(|hash| hashes.iter().position(|h| h == hash))
+            .map_or(0, |fork| fork + 1);
+
+        let mut headers = vec![];
+        for hash in hashes.iter().skip(start).take(max) {
+            let block = self.get_block(hash).unwrap();
+            headers.push(block.get_header());
+            if hash == stop_hash {
+                break;
+            }
+        }
+        headers
+    }
+
     pub fn get_block_hashes(&self) -> Vec<Vec<u8>> {
         let mut blocks: Vec<Vec<u8>> = vec![];
         let mut blockchain_iterator = BlockchainIterator {
@@ -116,35 +188,42 @@ impl Blockchain {
         blocks
     }
 
-    // finds all unspent transaction outputs and returns transactions with spent outputs removed
-    pub fn find_utxo(&self) -> HashMap<String, Vec<TXOutput>> {
-        let mut utxo: HashMap<String, Vec<TXOutput>> = HashMap::new();
+    /*finds all unspent transaction outputs. Spent outputs are None,
+    so the unspent ones keep their index for the inputs spending them. */
+    pub fn find_utxo(&self) -> HashMap<String, Vec<Option<TXOutput>>> {
+        self.find_utxo_at(&self.tip)
+    }
+
+    // finds the unspent transaction outputs of the chain ending with block_hash
+    fn find_utxo_at(&self, block_hash: &[u8]) -> HashMap<String, Vec<Option<TXOutput>>> {
+        let mut utxo: HashMap<String, Vec<Option<TXOutput>>> = HashMap::new();
 
         // spend transaction outputs
         // transaction id -> transaciton vout index
         let mut spent_txos: HashMap<String, Vec<i64>> = HashMap::new();
 
         let mut blockchain_iterator = BlockchainIterator {
-            current_hash: self.tip.clone(),
+            current_hash: block_hash.to_vec(),
             db: self.db.clone(),
         };
         while let Some(block) = blockchain_iterator.next() {
-            for tx in block.get_transactions() {
+            // backwards, so spends within the block are seen before the outputs they spend
+            for tx in block.get_transactions().into_iter().rev() {
                 let txid = hex::encode(tx.get_id());
 
-                'Outputs: for (tx_output_index, tx_output) in tx.get_vout().iter().enumerate() {
-                    if let Some(spent_txo) = spent_txos.get(&txid) {
-                        for spent_out in spent_txo {
-                            if spent_out.clone() == tx_output_index as i64 {
-                                println!("continue");
-                                continue 'Outputs;
-                            }
+                let mut outs: Vec<Option<TXOutput>> = tx.get_vout().into_iter().map(Some).collect();
+                if let Some(spent_txo) = spent_txos.get(&txid) {
+                    for spent_out in spent_txo {
+                        if let Some(out) = usize::try_from(*spent_out)
+                            .ok()
+                            .and_then(|index| outs.get_mut(index))
+                        {
+                            *out = None;
                         }
                     }
-
-                    utxo.entry(txid.clone())
-                        .or_insert(vec![])
-                        .push(tx_output.clone());
+                }
+                if outs.iter().any(|out| out.is_some()) {
+                    utxo.insert(txid.clone(), outs);
                 }
 
                 if tx.is_coinbase() == false {
@@ -162,28 +241,320 @@ impl Blockchain {
         utxo
     }
 
+    /*returns the blocks that left the main chain since old_tip was the
+    tip, newest first, and the blocks that joined it, oldest first */
+    pub fn find_fork(&self, old_tip: &Vec<u8>) -> (Vec<Block>, Vec<Block>) {
+        let mut disconnected = vec![];
+        let mut connected = vec![];
+        let (mut old, mut new) = match (self.get_block(old_tip), self.get_block(&self.tip)) {
+            (Some(old), Some(new)) => (old, new),
+            _ => return (disconnected, connected),
+        };
+        while old.get_hash() != new.get_hash() {
+            let old_height = old.get_height();
+            let new_height = new.get_height();
+            if old_height >= new_height {
+                let prev = self.get_block(&old.get_prev_block_hash());
+                disconnected.push(old);
+                old = match prev {
+                    Some(prev) => prev,
+                    None => break,
+                };
+            }
+            if new_height >= old_height {
+                let prev = self.get_block(&new.get_prev_block_hash());
+                connected.push(new);
+                new = match prev {
+                    Some(prev) => prev,
+                    None => break,
+                };
+            }
+        }
+        connected.reverse();
+        (disconnected, connected)
+    }
+
     pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &Vec<u8>) {
         let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
         for vin in &tx.get_vin() {
-            let prev_tx = self.find_transaction(vin.get_txid());
+            let prev_tx = self
+                .find_transaction(vin.get_txid())
+                .expect("Transaction does not exist");
             prev_txs.insert(hex::encode(prev_tx.get_id()), prev_tx);
         }
         tx.sign(private_key, &prev_txs);
     }
 
     pub fn verify_transaction(&self, tx: &Transaction) -> bool {
+        self.verify_transaction_in_block(tx, &HashMap::new())
+    }
+
+    // verifies a transaction whose inputs may spend block_txs, the transactions before it in its block
+    pub fn verify_transaction_in_block(
+        &self,
+        tx: &Transaction,
+        block_txs: &HashMap<String, Transaction>,
+    ) -> bool {
         if tx.is_coinbase() {
             return true;
         }
         let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
         for vin in &tx.get_vin() {
-            let prev_tx = self.find_transaction(vin.get_txid());
-            prev_txs.insert(hex::encode(prev_tx.get_id()), prev_tx);
+            let prev_tx = block_txs
+                .get(&hex::encode(vin.get_txid()))
+                .cloned()
+                .or_else(|| self.find_transaction(vin.get_txid()));
+            match prev_tx {
+                Some(prev_tx) => prev_txs.insert(hex::encode(prev_tx.get_id()), prev_tx),
+                None => return false,
+            };
         }
         tx.verify(&prev_txs)
     }
 
-    pub fn find_transaction(&self, id: Vec<u8>) -> Transaction {
+    /*checks a block received from a peer, on top of its parent in the chain.
+    Its timestamp is after the median time past of the parent and not too far
+    in the future, so a miner cannot move the time timelocks are checked at. */
+    pub fn check_block(&self, block: &Block) -> Result<(), String> {
+        let prev_hash = block.get_prev_block_hash();
+        // the proof of work does not cover the height, so it is checked against the parent
+        let parent = self
+            .get_block(&prev_hash)
+            .ok_or_else(|| format!("unknown parent {}", hex::encode(&prev_hash)))?;
+        if block.get_height() != parent.get_height() + 1 {
+            return Err(format!(
+                "height {} on top of height {}",
+                block.get_height(),
+                parent.get_height()
+            ));
+        }
+        let median_time_past = self.get_median_time_past(&prev_hash);
+        if block.get_timestamp() <= median_time_past {
+            return Err(format!(
+                "timestamp {} is not after the median time past {}",
+                block.get_timestamp(),
+                median_time_past
+            ));
+        }
+        if block.get_timestamp() > chrono::Utc::now().timestamp_millis() + MAX_FUTURE_BLOCK_TIME {
+            return Err(format!(
+                "timestamp {} is too far in the future",
+                block.get_timestamp()
+            ));
+        }
+        self.check_transactions(&block.get_transactions(), block.get_height(), &prev_hash)
+    }
+
+    /*the median timestamp in ms of the last MEDIAN_TIME_SPAN blocks up to
+    block_hash, which only moves forward as blocks are added. 0 below genesis. */
+    pub fn get_median_time_past(&self, block_hash: &[u8]) -> i64 {
+        let mut blockchain_iterator = BlockchainIterator {
+            current_hash: block_hash.to_vec(),
+            db: self.db.clone(),
+        };
+        let mut timestamps = vec![];
+        while timestamps.len() < MEDIAN_TIME_SPAN {
+            match blockchain_iterator.next() {
+                Some(block) => timestamps.push(block.get_timestamp()),
+                None => break,
+            }
+        }
+        if timestamps.is_empty() {
+            return 0;
+        }
+        timestamps.sort();
+        timestamps[timestamps.len() / 2]
+    }
+
+    /*checks the transactions of a block at height on top of prev_hash.
+    The first one is the coinbase, and the only one. Every other transaction
+    spends outputs unspent in the chain below or created earlier in the block,
+    at most once, with unlocking scripts that run and timelocks that passed.
+    The coinbase pays at most the subsidy plus the fees of the others.
+    On top of the block the UTXO set is at, the outputs are looked up in it,
+    below a block on a side chain they are collected from the blocks. */
+    fn check_transactions(
+        &self,
+        txs: &[Transaction],
+        height: usize,
+        prev_hash: &[u8],
+    ) -> Result<(), String> {
+        let coinbase = match txs.first() {
+            Some(tx) if tx.is_coinbase() => tx,
+            _ => return Err("the first transaction is not a coinbase".to_string()),
+        };
+        let utxo_set = UtxoSet::new(self.clone());
+        let on_utxo_set = utxo_set.get_tip().as_deref() == Some(prev_hash);
+        let mut utxo = if on_utxo_set {
+            HashMap::new()
+        } else {
+            self.find_utxo_at(prev_hash)
+        };
+        let mut fees: i64 = 0;
+        for (index, tx) in txs.iter().enumerate() {
+            let id = hex::encode(tx.get_id());
+            if index > 0 {
+                if tx.is_coinbase() {
+                    return Err(format!("transaction {} is a second coinbase", id));
+                }
+                if on_utxo_set {
+                    for vin in tx.get_vin() {
+                        if let Entry::Vacant(entry) = utxo.entry(hex::encode(vin.get_txid())) {
+                            if let Some(outs) = utxo_set.find_outputs(&vin.get_txid()) {
+                                entry.insert(outs);
+                            }
+                        }
+                    }
+                }
+                let fee = self
+                    .check_transaction(tx, &mut utxo, height, prev_hash)
+                    .map_err(|e| format!("transaction {}: {}", id, e))?;
+                fees = fees.checked_add(fee).ok_or("fees overflow")?;
+            }
+            utxo.entry(id)
+                .or_insert(tx.get_vout().into_iter().map(Some).collect());
+        }
+        let mut reward: i64 = 0;
+        for out in coinbase.get_vout() {
+            if out.get_value() < 0 {
+                return Err("coinbase output with negative value".to_string());
+            }
+            reward = reward
+                .checked_add(out.get_value())
+                .ok_or("coinbase value overflows")?;
+        }
+        if reward > transaction::SUBSIDY + fees {
+            return Err(format!(
+                "coinbase pays {}, more than the subsidy of {} plus {} in fees",
+                reward,
+                transaction::SUBSIDY,
+                fees
+            ));
+        }
+        Ok(())
+    }
+
+    /*checks a transaction of a block, taking the outputs it spends out of
+    utxo, and returns its fee */
+    fn check_transaction(
+        &self,
+        tx: &Transaction,
+        utxo: &mut HashMap<String, Vec<Option<TXOutput>>>,
+        height: usize,
+        prev_hash: &[u8],
+    ) -> Result<i64, String> {
+        if !tx.has_valid_id() {
+            return Err("id is not its hash".to_string());
+        }
+        if tx.get_vin().is_empty() || tx.get_vout().is_empty() {
+            return Err("no inputs or no outputs".to_string());
+        }
+        let mut prev_outs = vec![];
+        let mut input_value: i64 = 0;
+        for vin in tx.get_vin() {
+            let prev_out = utxo
+                .get_mut(&hex::encode(vin.get_txid()))
+                .and_then(|outs| {
+                    usize::try_from(vin.get_vout())
+                        .ok()
+                        .and_then(|index| outs.get_mut(index))
+                })
+                .and_then(Option::take)
+                .ok_or(format!(
+                    "output {} of {} is missing or spent",
+                    vin.get_vout(),
+                    hex::encode(vin.get_txid())
+                ))?;
+            input_value = input_value
+                .checked_add(prev_out.get_value())
+                .ok_or("input value overflows")?;
+            prev_outs.push(prev_out);
+        }
+        let mut output_value: i64 = 0;
+        for out in tx.get_vout() {
+            if out.get_value() <= 0 {
+                return Err("output without value".to_string());
+            }
+            output_value = output_value
+                .checked_add(out.get_value())
+                .ok_or("output value overflows")?;
+        }
+        if output_value > input_value {
+            return Err("spends more than its inputs".to_string());
+        }
+        tx.verify_scripts(&prev_outs)?;
+        self.check_locks(tx, height, prev_hash)
+            .map_err(|e| format!("not final, {}", e))?;
+        Ok(input_value - output_value)
+    }
+
+    /*whether tx can go into a block at height on top of prev_hash: its lock
+    time has passed, and so have the relative locks of its inputs since the
+    blocks confirming the outputs they spend. Times are median times past in
+    seconds, the block's of its parent, which miners cannot set at will.
+    Outputs not confirmed below count as confirmed in the same block, as if
+    they came before tx in it or in the mempool. */
+    pub fn check_locks(
+        &self,
+        tx: &Transaction,
+        height: usize,
+        prev_hash: &[u8],
+    ) -> Result<(), String> {
+        let time = self.get_median_time_past(prev_hash) / 1000;
+        if !tx.is_final(height, time) {
+            return Err(if tx.get_lock_time() < transaction::LOCK_TIME_THRESHOLD {
+                format!("locked until after block {}", tx.get_lock_time())
+            } else {
+                format!("locked until after time {}", tx.get_lock_time())
+            });
+        }
+        for (in_id, vin) in tx.get_vin().iter().enumerate() {
+            let relative_lock = match vin.get_relative_lock() {
+                Some(relative_lock) => relative_lock,
+                None => continue,
+            };
+            let (confirmed_height, confirmed_time) = self
+                .find_confirmation(prev_hash, &vin.get_txid())
+                .unwrap_or((height, time));
+            match relative_lock {
+                RelativeLock::Blocks(blocks) if height < confirmed_height + blocks as usize => {
+                    return Err(format!(
+                        "input {} is locked until block {}",
+                        in_id,
+                        confirmed_height + blocks as usize
+                    ))
+                }
+                RelativeLock::Seconds(seconds) if time < confirmed_time + seconds => {
+                    return Err(format!(
+                        "input {} is locked until time {}",
+                        in_id,
+                        confirmed_time + seconds
+                    ))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /*the height of the block confirming transaction id, at or below block_hash,
+    and the time in seconds it counts as confirmed at: the median time past
+    of its parent, as for the timelocks of the block itself */
+    fn find_confirmation(&self, block_hash: &[u8], id: &[u8]) -> Option<(usize, i64)> {
+        let mut blockchain_iterator = BlockchainIterator {
+            current_hash: block_hash.to_vec(),
+            db: self.db.clone(),
+        };
+        while let Some(block) = blockchain_iterator.next() {
+            if block.get_transactions().iter().any(|tx| tx.get_id() == id) {
+                let time = self.get_median_time_past(&block.get_prev_block_hash()) / 1000;
+                return Some((block.get_height(), time));
+            }
+        }
+        None
+    }
+
+    pub fn find_transaction(&self, id: Vec<u8>) -> Option<Transaction> {
         let mut blockchain_iterator = BlockchainIterator {
             current_hash: self.tip.clone(),
             db: self.db.clone(),
@@ -191,12 +562,30 @@ impl Blockchain {
         while let Some(block) = blockchain_iterator.next() {
             for tx in block.get_transactions() {
                 if tx.get_id() == id {
-                    return tx;
+                    return Some(tx);
                 }
             }
         }
-        panic!("Transaction does not exist")
+        None
+    }
+}
+
+/*blocks written by a version encoding them differently cannot be read,
+so the chain of an older version has to be deleted and created or
+downloaded again */
+fn check_format(db: &Db, node_id: &str) -> Result<(), String> {
+    let version = db
+        .get(FORMAT_KEY)
+        .unwrap()
+        .and_then(|data| <[u8; 4]>::try_from(data.as_ref()).ok())
+        .map(u32::from_be_bytes);
+    if version != Some(FORMAT_VERSION) {
+        return Err(format!(
+            "{} was written by another version of the node in an incompatible format, delete it and create or sync the chain again",
+            DB_FILE.replace("{}", node_id)
+        ));
     }
+    Ok(())
 }
 
 pub struct BlockchainIterator {
@@ -222,3 +611,193 @@ impl BlockchainIterator {
         Some(block)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script;
+    use crate::test_util;
+    use crate::UtxoSet;
+    use crate::Wallet;
+
+    #[test]
+    fn blocks_are_checked_before_they_are_added() {
+        let (wallet, bc) = test_util::new_chain("chain_check_block");
+        test_util::wait_for_block_time();
+        let utxo_set = UtxoSet::new(bc.clone());
+        let to = test_util::address(&Wallet::new_wallet());
+        let tx = transaction::new_utxo_transaction(
+            &wallet,
+            to,
+            3,
+            1,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &utxo_set,
+        );
+        let block = |txs: Vec<Transaction>, reward: i64| {
+            let coinbase = transaction::new_coinbase_tx(
+                test_util::address(&wallet),
+                "Height 1".to_string(),
+                reward - transaction::SUBSIDY,
+            );
+            let txs = [vec![coinbase], txs].concat();
+            Block::new_block(txs, bc.get_tip_hash(), 1)
+        };
+
+        // the coinbase may claim the fee of 1
+        assert!(bc.check_block(&block(vec![tx.clone()], 11)).is_ok());
+        assert!(bc.check_block(&block(vec![tx.clone()], 12)).is_err());
+        // an output is spent once
+        assert!(bc
+            .check_block(&block(vec![tx.clone(), tx.clone()], 10))
+            .is_err());
+        // the unlocking script is run
+        let mut forged = tx.clone();
+        forged.set_script_sig(0, script::p2pkh_unlock(&[0; 64], &wallet.public_key));
+        assert!(bc.check_block(&block(vec![forged], 10)).is_err());
+    }
+
+    #[test]
+    fn a_block_has_one_coinbase_and_it_comes_first() {
+        let (wallet, bc) = test_util::new_chain("chain_coinbase_first");
+        test_util::wait_for_block_time();
+        let utxo_set = UtxoSet::new(bc.clone());
+        let to = test_util::address(&Wallet::new_wallet());
+        let tx = transaction::new_utxo_transaction(
+            &wallet,
+            to,
+            3,
+            0,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &utxo_set,
+        );
+        let coinbase = |data: &str| {
+            transaction::new_coinbase_tx(test_util::address(&wallet), data.to_string(), 0)
+        };
+        let block = |txs: Vec<Transaction>| Block::new_block(txs, bc.get_tip_hash(), 1);
+
+        assert!(bc
+            .check_block(&block(vec![coinbase("Height 1"), tx.clone()]))
+            .is_ok());
+        assert!(bc
+            .check_block(&block(vec![tx.clone(), coinbase("Height 1")]))
+            .is_err());
+        assert!(bc
+            .check_block(&block(vec![coinbase("Height 1"), coinbase("again")]))
+            .is_err());
+        assert!(bc.check_block(&block(vec![tx])).is_err());
+    }
+
+    #[test]
+    fn headers_start_after_the_first_locator_hash_we_have() {
+        let (wallet, mut bc) = test_util::new_chain("chain_headers");
+        for _ in 0..4 {
+            test_util::mine(&mut bc, vec![], &wallet);
+        }
+        let hashes = bc.get_block_hashes();
+        let locator = bc.get_block_locator();
+        assert_eq!(locator, hashes);
+
+        // a peer at height 1 is sent heights 2 to 4
+        let heights =
+            |headers: Vec<BlockHeader>| headers.iter().map(|h| h.get_height()).collect::<Vec<_>>();
+        let peer_locator = vec![vec![7; 32], hashes[3].clone(), hashes[4].clone()];
+        assert_eq!(
+            heights(bc.find_headers(&peer_locator, &[], 10)),
+            vec![2, 3, 4]
+        );
+        assert_eq!(
+            heights(bc.find_headers(&peer_locator, &hashes[1], 10)),
+            vec![2, 3]
+        );
+        assert_eq!(heights(bc.find_headers(&peer_locator, &[], 1)), vec![2]);
+        // a locator we know nothing of is answered with the whole chain
+        assert_eq!(
+            heights(bc.find_headers(&[vec![7; 32]], &[], 10)),
+            vec![0, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn a_block_is_one_higher_than_its_parent() {
+        let (wallet, bc) = test_util::new_chain("chain_block_height");
+        test_util::wait_for_block_time();
+        let block = |height: usize, prev_hash: Vec<u8>| {
+            let coinbase = transaction::new_coinbase_tx(
+                test_util::address(&wallet),
+                format!("Height {}", height),
+                0,
+            );
+            Block::new_block(vec![coinbase], prev_hash, height)
+        };
+
+        assert_eq!(bc.check_block(&block(1, bc.get_tip_hash())), Ok(()));
+        assert!(bc.check_block(&block(2, bc.get_tip_hash())).is_err());
+        assert!(bc.check_block(&block(0, bc.get_tip_hash())).is_err());
+        assert!(bc.check_block(&block(1, vec![7; 32])).is_err());
+    }
+
+    #[test]
+    fn blocks_off_the_tip_are_checked_against_their_own_chain() {
+        let (wallet, mut bc) = test_util::new_chain("chain_side_block");
+        let genesis_hash = bc.get_tip_hash();
+        let spend = |fee: i64| {
+            let to = test_util::address(&Wallet::new_wallet());
+            let utxo_set = UtxoSet::new(bc.clone());
+            transaction::new_utxo_transaction(
+                &wallet,
+                to,
+                3,
+                fee,
+                transaction::SEQUENCE_FINAL,
+                0,
+                &utxo_set,
+            )
+        };
+        let (tx, conflict) = (spend(0), spend(1));
+        test_util::mine(&mut bc, vec![tx], &wallet);
+        let block = |prev_hash: Vec<u8>, height: usize| {
+            let coinbase = transaction::new_coinbase_tx(
+                test_util::address(&wallet),
+                format!("Height {}", height),
+                0,
+            );
+            Block::new_block(vec![coinbase, conflict.clone()], prev_hash, height)
+        };
+
+        test_util::wait_for_block_time();
+        assert!(bc.check_block(&block(bc.get_tip_hash(), 2)).is_err());
+        assert!(bc.check_block(&block(genesis_hash, 1)).is_ok());
+    }
+
+    #[test]
+    fn relative_lock_counts_blocks_since_the_spent_output() {
+        let (wallet, mut bc) = test_util::new_chain("chain_relative_lock");
+        let utxo_set = UtxoSet::new(bc.clone());
+        let sequence = transaction::input_sequence(false, 0, Some(RelativeLock::Blocks(2)));
+        let to = test_util::address(&Wallet::new_wallet());
+        let tx = transaction::new_utxo_transaction(&wallet, to, 3, 0, sequence, 0, &utxo_set);
+
+        // the genesis output is confirmed at height 0, the next block is 1
+        assert!(bc.check_locks(&tx, 1, &bc.get_tip_hash()).is_err());
+        test_util::mine(&mut bc, vec![], &wallet);
+        assert!(bc.check_locks(&tx, 2, &bc.get_tip_hash()).is_ok());
+        test_util::mine(&mut bc, vec![tx], &wallet);
+        assert_eq!(bc.get_best_height(), 2);
+    }
+
+    #[test]
+    fn height_lock_time_keeps_a_transaction_out_of_earlier_blocks() {
+        let (wallet, mut bc) = test_util::new_chain("chain_lock_time");
+        let utxo_set = UtxoSet::new(bc.clone());
+        let sequence = transaction::input_sequence(false, 1, None);
+        let to = test_util::address(&Wallet::new_wallet());
+        let tx = transaction::new_utxo_transaction(&wallet, to, 3, 0, sequence, 1, &utxo_set);
+
+        assert!(bc.check_locks(&tx, 1, &bc.get_tip_hash()).is_err());
+        test_util::mine(&mut bc, vec![], &wallet);
+        assert!(bc.check_locks(&tx, 2, &bc.get_tip_hash()).is_ok());
+    }
+}
@@ -1,17 +1,42 @@
+use crate::ban_list;
+use crate::script;
 use crate::server;
+use crate::shutdown;
 use crate::transaction;
+use crate::transaction::RelativeLock;
+use crate::transport;
 use crate::utxo_set;
 use crate::wallet;
 use crate::wallets;
 use crate::wallets::new_wallets;
 use crate::Blockchain;
 use crate::BlockchainIterator;
+use crate::Node;
+use crate::NodeConfig;
 use crate::ProofOfWork;
+use crate::Psbt;
 use crate::Transaction;
+use std::collections::HashMap;
 use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
 
 pub struct Cli {}
 
+// how send pays and delivers a transaction
+#[derive(Default)]
+pub struct SendOptions {
+    pub fee: i64,
+    pub replaceable: bool, // opts in to replace-by-fee, for bumpfee
+    pub mine_now: bool,    // mines it on this node instead of submitting it
+    pub encrypt: bool,
+    pub lock_time: u32, // the block height or unix time it cannot be mined before
+    pub relative_lock: Option<u32>, // blocks the spent outputs must be deep before it is mined
+    pub out_file: Option<String>, // writes it there to broadcast later instead of submitting it
+}
+
 impl Cli {
     fn print_usage() {
         println!("Usage:");
@@ -19,12 +44,49 @@ impl Cli {
         println!(" createblockchain -address ADDRESS - Create a blockchain and send genesis block reward to ADDRESS");
         println!(" createwallet - Generates a new key-pair and saves it into the wallet file");
         println!(" listaddresses - Lists all addresses from the wallet file");
+        println!(" listbanned - Lists the hosts banned by the node for misbehaving, local peers are only disconnected");
+        println!(" clearbanned - Lifts all bans of the node");
+        println!(
+            " nodekey - Prints the public key the node authenticates with on encrypted sessions"
+        );
         println!(" printchain - Print all the blocks of the blockchain");
         println!(" reindexutxo - Rebuilds the UTXO set");
         println!(
-"  send -from FROM -to TO -amount AMOUNT -mine - Send AMOUNT of coins from FROM address to TO. Mine on the same node, when -mine is set."
+"  send -from FROM -to TO -amount AMOUNT -fee FEE -rbf -locktime LOCK -relativelock BLOCKS -out FILE -mine -encrypt - Send AMOUNT of coins from FROM address to TO. Mine on the same node, when -mine is set."
+        );
+        println!("   -fee pays FEE to the miner, 0 by default. Miners prefer transactions paying more per byte");
+        println!(
+            "   -rbf lets the transaction be replaced by one paying a higher fee until it is mined"
+        );
+        println!("   -locktime keeps the transaction out of blocks up to height LOCK, or until the median time of the last 11 blocks passes unix time LOCK from 500000000 on");
+        println!("   -relativelock keeps it out of blocks until the coins it spends are BLOCKS blocks deep");
+        println!("   -out writes the transaction to FILE instead of submitting it, for broadcasttx once it is final");
+        println!("   -encrypt submits the transaction over an encrypted session");
+        println!(" bumpfee -txid TXID -fee FEE -encrypt - Replaces the unconfirmed transaction TXID sent with -rbf by one paying FEE in total, by default the least a replacement must pay");
+        println!(" getpubkey -address ADDRESS - Prints the public key of ADDRESS, to share for a multisig address");
+        println!(" createmultisig -required M -pubkeys KEY,KEY,... - Creates an address whose coins need the signatures of M of the keys");
+        println!(" spendmultisig -from ADDRESS -to TO -amount AMOUNT -fee FEE -out FILE - Writes a partially signed transaction spending from the multisig ADDRESS to FILE, signed with the keys of this wallet file");
+        println!(" signmultisig -in FILE -out FILE - Same as signpsbt, adds the signatures of this wallet file");
+        println!(" combinemultisig -in FILE,FILE,... -out FILE - Same as combinepsbt, merges copies signed by different wallet files");
+        println!(" sendmultisig -in FILE -encrypt - Finalizes a partially signed transaction with enough signatures and submits it");
+        println!(" createpsbt -from ADDRESS -to TO -amount AMOUNT -fee FEE -out FILE - Writes an unsigned transaction to FILE with what signing it needs, no key is needed");
+        println!(" signpsbt -in FILE -out FILE - Signs a transaction of createpsbt with the keys of this wallet file, without the blockchain");
+        println!(" combinepsbt -in FILE,FILE,... -out FILE - Merges the signatures of copies of a transaction signed with signpsbt");
+        println!(" finalizepsbt -in FILE -out FILE - Writes the signed transaction once all signatures are there");
+        println!(" broadcasttx -in FILE -encrypt - Submits a signed transaction of finalizepsbt");
+        println!(" startnode -miner ADDRESS -mineinterval SECS -maxmempool MB -mempoolexpiry HOURS -bind ADDR -externalip ADDR -seed ADDR -encrypt -allowpeer KEY - Start a node with ID specified in NODE_ID env. var. -miner enables mining");
+        println!("   -mineinterval mines a block of the pending transactions every SECS seconds, 10 by default");
+        println!("   -maxmempool keeps at most MB megabytes of pending transactions, 300 by default. The lowest fee rates are evicted first");
+        println!(
+            "   -mempoolexpiry drops transactions still pending after HOURS hours, 336 by default"
+        );
+        println!("   -bind listens on the socket address ADDR instead of 127.0.0.1:NODE_ID, and can be repeated");
+        println!("   -externalip announces ADDR to other nodes instead of the first bind address");
+        println!("   -seed connects to ADDR instead of 127.0.0.1:3000 while no other node is known, can be repeated");
+        println!(
+            "   -encrypt encrypts and authenticates all sessions, every peer must encrypt as well"
         );
-        println!(" startnode -miner ADDRESS - Start a node with ID specified in NODE_ID env. var. -miner enables mining");
+        println!("   -allowpeer only accepts the peer with the node key KEY, can be repeated and implies -encrypt");
     }
 
     fn validate_args() {
@@ -35,7 +97,7 @@ impl Cli {
     }
 
     fn print_chain(&self, node_id: String) {
-        let bc = Blockchain::new_blockchain(node_id).unwrap();
+        let bc = Cli::open_blockchain(&node_id);
         let mut blockchain_iter = BlockchainIterator::iterator(&bc);
         loop {
             let block = blockchain_iter.next();
@@ -56,20 +118,41 @@ impl Cli {
     }
 
     pub fn print_transaction(tx: &Transaction) {
+        if tx.get_lock_time() != 0 {
+            println!("Lock time: {}", tx.get_lock_time());
+        }
         for tx_in in tx.get_vin() {
             println!("TXInput:");
             println!("  TXID: {}", hex::encode(tx_in.get_txid()));
             println!("  Out: {}", tx_in.get_vout());
-            let pub_key_hash = wallet::hash_pub_key(&tx_in.get_pub_key());
-            println!("address:{}", wallet::calc_address(&pub_key_hash));
+            if tx_in.get_sequence() != transaction::SEQUENCE_FINAL {
+                println!("  Sequence: {:#x}", tx_in.get_sequence());
+            }
+            if tx.is_coinbase() {
+                println!(
+                    "  Data: {}",
+                    String::from_utf8_lossy(&tx_in.get_script_sig())
+                );
+            } else if let Some(pub_key) = tx_in.get_pub_key() {
+                let pub_key_hash = wallet::hash_pub_key(&pub_key);
+                println!("address:{}", wallet::calc_address(&pub_key_hash));
+            } else {
+                println!("script:{}", script::disassemble(&tx_in.get_script_sig()));
+            }
         }
 
         for tx_out in tx.get_vout() {
             println!("TXOutput:");
             println!("  TXID: {}", hex::encode(tx.get_id()));
             println!("  Value: {}", tx_out.get_value());
-            let pub_key_hash = tx_out.get_pub_key_hash();
-            println!("address:{}", wallet::calc_address(&pub_key_hash));
+            let script_pubkey = tx_out.get_script_pubkey();
+            if let Some(pub_key_hash) = tx_out.get_pub_key_hash() {
+                println!("address:{}", wallet::calc_address(&pub_key_hash));
+            } else if let Some(script_hash) = script::parse_p2sh(&script_pubkey) {
+                println!("address:{}", wallet::calc_script_address(&script_hash));
+            } else {
+                println!("script:{}", script::disassemble(&script_pubkey));
+            }
         }
     }
 
@@ -105,36 +188,236 @@ impl Cli {
             "listaddresses" => {
                 Cli::list_address(node_id);
             }
+            "listbanned" => {
+                Cli::list_banned(node_id);
+            }
+            "clearbanned" => {
+                Cli::clear_banned(node_id);
+            }
+            "nodekey" => {
+                Cli::node_key(node_id);
+            }
             "printchain" => {
                 self.print_chain(node_id);
             }
             "send" => {
                 if args[3].is_empty() || args[5].is_empty() || args[7].is_empty() {
-                    println!("  send -from FROM -to TO -amount AMOUNT -mine");
+                    println!(
+                        "  send -from FROM -to TO -amount AMOUNT -fee FEE -rbf -locktime LOCK -relativelock BLOCKS -out FILE -mine -encrypt"
+                    );
                 }
-                let mine: bool;
-                if args.len() == 9 && args[8] == "-mine" {
-                    mine = true;
-                } else {
-                    mine = false;
+                let mut options = SendOptions::default();
+                let mut args_left = args[8..].iter();
+                while let Some(option) = args_left.next() {
+                    match option.as_str() {
+                        "-mine" => options.mine_now = true,
+                        "-encrypt" => options.encrypt = true,
+                        "-rbf" => options.replaceable = true,
+                        "-fee" => options.fee = Cli::parse_fee(args_left.next()),
+                        "-locktime" => {
+                            options.lock_time =
+                                Cli::parse_lock(args_left.next(), "-locktime", u32::MAX)
+                        }
+                        "-relativelock" => {
+                            options.relative_lock =
+                                Some(Cli::parse_lock(args_left.next(), "-relativelock", 0xffff))
+                        }
+                        "-out" => match args_left.next() {
+                            Some(file) => options.out_file = Some(file.clone()),
+                            None => {
+                                println!("Usage: send -from FROM -to TO -amount AMOUNT -fee FEE -rbf -locktime LOCK -relativelock BLOCKS -out FILE -mine -encrypt");
+                                std::process::exit(1);
+                            }
+                        },
+                        _ => {
+                            println!("Usage: send -from FROM -to TO -amount AMOUNT -fee FEE -rbf -locktime LOCK -relativelock BLOCKS -out FILE -mine -encrypt");
+                            std::process::exit(1);
+                        }
+                    }
                 }
                 Cli::send(
                     args[3].clone(),
                     args[5].clone(),
                     args[7].parse::<i64>().unwrap(),
                     node_id,
-                    mine,
+                    options,
+                );
+            }
+            "bumpfee" => {
+                if args.len() < 4 || args[2] != "-txid" {
+                    println!("Usage: bumpfee -txid TXID -fee FEE -encrypt");
+                    std::process::exit(1);
+                }
+                let mut fee = None;
+                let mut encrypt = false;
+                let mut args_left = args[4..].iter();
+                while let Some(option) = args_left.next() {
+                    match option.as_str() {
+                        "-encrypt" => encrypt = true,
+                        "-fee" => fee = Some(Cli::parse_fee(args_left.next())),
+                        _ => {
+                            println!("Usage: bumpfee -txid TXID -fee FEE -encrypt");
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                Cli::bump_fee(&args[3], fee, node_id, encrypt);
+            }
+            "getpubkey" => {
+                if args.len() != 4 || args[2] != "-address" {
+                    println!("Usage: getpubkey -address ADDRESS");
+                    std::process::exit(1);
+                }
+                Cli::get_pub_key(&args[3], node_id);
+            }
+            "createmultisig" => {
+                let usage = "Usage: createmultisig -required M -pubkeys KEY,KEY,...";
+                let options =
+                    Cli::parse_options(&args[2..], &["-required", "-pubkeys"], &[], usage);
+                Cli::create_multisig(
+                    Cli::parse_count(&options["-required"]) as usize,
+                    &options["-pubkeys"],
+                    node_id,
+                );
+            }
+            "spendmultisig" => {
+                let usage =
+                    "Usage: spendmultisig -from ADDRESS -to TO -amount AMOUNT -fee FEE -out FILE";
+                let options = Cli::parse_options(
+                    &args[2..],
+                    &["-from", "-to", "-amount", "-out"],
+                    &["-fee"],
+                    usage,
+                );
+                Cli::spend_multisig(
+                    &options["-from"],
+                    &options["-to"],
+                    Cli::parse_count(&options["-amount"]) as i64,
+                    options
+                        .get("-fee")
+                        .map_or(0, |fee| Cli::parse_fee(Some(fee))),
+                    &options["-out"],
+                    node_id,
+                );
+            }
+            "signmultisig" => {
+                let usage = "Usage: signmultisig -in FILE -out FILE";
+                let options = Cli::parse_options(&args[2..], &["-in", "-out"], &[], usage);
+                Cli::sign_psbt(&options["-in"], &options["-out"], node_id);
+            }
+            "combinemultisig" => {
+                let usage = "Usage: combinemultisig -in FILE,FILE,... -out FILE";
+                let options = Cli::parse_options(&args[2..], &["-in", "-out"], &[], usage);
+                Cli::combine_psbt(&options["-in"], &options["-out"]);
+            }
+            "sendmultisig" => {
+                if args.len() < 4
+                    || args[2] != "-in"
+                    || args[4..].iter().any(|arg| arg != "-encrypt")
+                {
+                    println!("Usage: sendmultisig -in FILE -encrypt");
+                    std::process::exit(1);
+                }
+                Cli::send_multisig(&args[3], node_id, args.len() > 4);
+            }
+            "createpsbt" => {
+                let usage =
+                    "Usage: createpsbt -from ADDRESS -to TO -amount AMOUNT -fee FEE -out FILE";
+                let options = Cli::parse_options(
+                    &args[2..],
+                    &["-from", "-to", "-amount", "-out"],
+                    &["-fee"],
+                    usage,
+                );
+                Cli::create_psbt(
+                    &options["-from"],
+                    &options["-to"],
+                    Cli::parse_count(&options["-amount"]) as i64,
+                    options
+                        .get("-fee")
+                        .map_or(0, |fee| Cli::parse_fee(Some(fee))),
+                    &options["-out"],
+                    node_id,
                 );
             }
+            "signpsbt" => {
+                let usage = "Usage: signpsbt -in FILE -out FILE";
+                let options = Cli::parse_options(&args[2..], &["-in", "-out"], &[], usage);
+                Cli::sign_psbt(&options["-in"], &options["-out"], node_id);
+            }
+            "combinepsbt" => {
+                let usage = "Usage: combinepsbt -in FILE,FILE,... -out FILE";
+                let options = Cli::parse_options(&args[2..], &["-in", "-out"], &[], usage);
+                Cli::combine_psbt(&options["-in"], &options["-out"]);
+            }
+            "finalizepsbt" => {
+                let usage = "Usage: finalizepsbt -in FILE -out FILE";
+                let options = Cli::parse_options(&args[2..], &["-in", "-out"], &[], usage);
+                Cli::finalize_psbt(&options["-in"], &options["-out"]);
+            }
+            "broadcasttx" => {
+                if args.len() < 4
+                    || args[2] != "-in"
+                    || args[4..].iter().any(|arg| arg != "-encrypt")
+                {
+                    println!("Usage: broadcasttx -in FILE -encrypt");
+                    std::process::exit(1);
+                }
+                Cli::broadcast_tx(&args[3], node_id, args.len() > 4);
+            }
             "reindexutxo" => {
                 Cli::reindex_utxo(node_id);
             }
             "startnode" => {
-                let mut miner_address = String::new();
-                if args.len() == 4 {
-                    miner_address = args[3].clone();
+                let mut config = NodeConfig::new(node_id, String::new());
+                let mut listen_addrs = vec![];
+                let mut seed_nodes = vec![];
+                let mut options = args[2..].iter();
+                while let Some(name) = options.next() {
+                    if name == "-encrypt" {
+                        config.encrypt = true;
+                        continue;
+                    }
+                    let value = match options.next() {
+                        Some(value) => value,
+                        None => Cli::exit_with_startnode_usage(),
+                    };
+                    match name.as_str() {
+                        "-miner" => config.mining_address = value.clone(),
+                        "-mineinterval" => {
+                            config.mining_interval = Duration::from_secs(Cli::parse_count(value))
+                        }
+                        "-maxmempool" => {
+                            config.mempool_limits.max_size =
+                                Cli::parse_count(value) as usize * 1_000_000
+                        }
+                        "-mempoolexpiry" => {
+                            config.mempool_limits.expiry =
+                                Duration::from_secs(Cli::parse_count(value) * 60 * 60)
+                        }
+                        "-bind" => listen_addrs.push(Cli::parse_socket_addr(value)),
+                        "-externalip" => config.external_addr = Some(Cli::parse_socket_addr(value)),
+                        "-seed" => seed_nodes.push(Cli::parse_socket_addr(value).to_string()),
+                        "-allowpeer" => match hex::decode(value) {
+                            Ok(key) => {
+                                config.encrypt = true;
+                                config.allowed_peers.push(key);
+                            }
+                            Err(_) => {
+                                eprintln!("Error: {} is not a hex encoded node key", value);
+                                std::process::exit(1);
+                            }
+                        },
+                        _ => Cli::exit_with_startnode_usage(),
+                    }
+                }
+                if !listen_addrs.is_empty() {
+                    config.listen_addrs = listen_addrs;
+                }
+                if !seed_nodes.is_empty() {
+                    config.seed_nodes = seed_nodes;
                 }
-                Cli::start_node(node_id, miner_address);
+                Cli::start_node(config);
             }
             _ => {
                 Cli::print_usage();
@@ -148,7 +431,13 @@ impl Cli {
             eprintln!("Error: Address is not valid");
             std::process::exit(1);
         }
-        let bc = Blockchain::create_blockchain(address.clone(), node_id);
+        let bc = match Blockchain::create_blockchain(address.clone(), node_id) {
+            Ok(bc) => bc,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        };
         let utxo_set = utxo_set::UtxoSet::new(bc);
         utxo_set.reindex();
         println!("Done!");
@@ -159,12 +448,10 @@ impl Cli {
             eprintln!("Error: Address is not valid");
             std::process::exit(1);
         }
-        let bc = Blockchain::new_blockchain(node_id.clone()).unwrap();
+        let bc = Cli::open_blockchain(&node_id);
         let utxo_set = utxo_set::UtxoSet::new(bc);
 
-        let mut pub_key_hash = bs58::decode(&address).into_vec().unwrap();
-        pub_key_hash = pub_key_hash[1..pub_key_hash.len() - wallet::ADDRESS_CHECK_SUM_LEN].to_vec();
-        let utxo = utxo_set.find_utxo(&pub_key_hash);
+        let utxo = utxo_set.find_utxo(&wallet::locking_script(&address));
 
         let mut balance = 0;
         for out in utxo {
@@ -181,14 +468,49 @@ impl Cli {
     }
 
     pub fn list_address(node_id: String) {
-        let wallets = wallets::new_wallets(node_id);
+        let wallets = wallets::new_wallets(node_id.clone());
         let addresses = wallets.get_addresses();
         for address in addresses {
             println!("{}", address);
         }
+        for (address, redeem_script) in wallets::get_redeem_scripts(&node_id) {
+            if let Some((required, pub_keys)) = script::parse_multisig(&redeem_script) {
+                println!("{} (multisig, {} of {})", address, required, pub_keys.len());
+            }
+        }
+    }
+
+    pub fn list_banned(node_id: String) {
+        let ban_list = ban_list::new_ban_list(&node_id);
+        for (ip, ban) in ban_list.get_bans() {
+            let until = chrono::DateTime::from_timestamp(ban.until, 0)
+                .map(|until| until.with_timezone(&chrono::Local).to_string())
+                .unwrap_or_default();
+            println!("{} banned until {}: {}", ip, until, ban.reason);
+        }
+    }
+
+    pub fn clear_banned(node_id: String) {
+        let mut ban_list = ban_list::new_ban_list(&node_id);
+        ban_list.clear();
+        if let Err(e) = ban_list.save_to_file(&node_id) {
+            eprintln!("Error: failed to save the ban list: {}", e);
+            std::process::exit(1);
+        }
+        println!("Done!");
+    }
+
+    pub fn node_key(node_id: String) {
+        match transport::load_node_key(&node_id) {
+            Ok(node_key) => println!("{}", hex::encode(node_key.get_public_key())),
+            Err(e) => {
+                eprintln!("Error: failed to load the node key: {}", e);
+                std::process::exit(1);
+            }
+        }
     }
 
-    pub fn send(from: String, to: String, amount: i64, node_id: String, mine_now: bool) {
+    pub fn send(from: String, to: String, amount: i64, node_id: String, options: SendOptions) {
         if !wallet::validate_address(from.clone()) {
             eprintln!("Error: sender Address is not valid");
             std::process::exit(1);
@@ -198,38 +520,406 @@ impl Cli {
             std::process::exit(1);
         }
 
-        let mut blockchain = Blockchain::new_blockchain(node_id.clone()).unwrap();
+        let mut blockchain = Cli::open_blockchain(&node_id);
         let utxo_set = utxo_set::UtxoSet::new(blockchain.clone());
 
-        let wallets = new_wallets(node_id);
+        let wallets = new_wallets(node_id.clone());
         let wallet = wallets.get_wallet(&from).unwrap();
 
-        let transaction = transaction::new_utxo_transaction(&wallet, to.clone(), amount, &utxo_set);
+        let sequence = transaction::input_sequence(
+            options.replaceable,
+            options.lock_time,
+            options.relative_lock.map(RelativeLock::Blocks),
+        );
+        let transaction = transaction::new_utxo_transaction(
+            &wallet,
+            to.clone(),
+            amount,
+            options.fee,
+            sequence,
+            options.lock_time,
+            &utxo_set,
+        );
 
-        if mine_now {
-            let cbtx = transaction::new_coinbase_tx(from.clone(), "".to_string());
+        if options.mine_now {
+            let height = blockchain.get_best_height() + 1;
+            if let Err(e) = blockchain.check_locks(&transaction, height, &blockchain.get_tip_hash())
+            {
+                eprintln!("Error: the transaction cannot be mined yet, {}", e);
+                std::process::exit(1);
+            }
+            let cbtx = transaction::new_coinbase_tx(
+                from.clone(),
+                format!("Height {}", height),
+                options.fee,
+            );
             let transactions = vec![cbtx, transaction];
-            let block = blockchain.mine_block(transactions);
-            utxo_set.update(block);
+            match blockchain.mine_block(transactions) {
+                Ok(block) => utxo_set.update(block),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        } else if let Some(out_file) = options.out_file {
+            Cli::write_tx_file(&out_file, &transaction);
+            println!("{} is signed, send it with broadcasttx", out_file);
+            return;
         } else {
-            unsafe {
-                server::send_tx(server::KNOWN_NODES[0].clone(), &transaction);
+            Cli::submit(&node_id, &transaction, options.encrypt);
+        }
+
+        println!("Success!");
+    }
+
+    pub fn bump_fee(txid: &str, fee: Option<i64>, node_id: String, encrypt: bool) {
+        let tx = match wallets::find_sent_tx(&node_id, txid) {
+            Some(tx) => tx,
+            None => {
+                eprintln!("Error: transaction {} was not sent from this wallet", txid);
+                std::process::exit(1);
+            }
+        };
+        let wallets = new_wallets(node_id.clone());
+        let pub_key = tx.get_vin()[0].get_pub_key().unwrap_or_default();
+        let wallet = match wallets.find_wallet(&pub_key) {
+            Some(wallet) => wallet,
+            None => {
+                eprintln!("Error: the wallet of transaction {} is missing", txid);
+                std::process::exit(1);
+            }
+        };
+        let blockchain = Cli::open_blockchain(&node_id);
+        let utxo_set = utxo_set::UtxoSet::new(blockchain);
+        let sent_txs = wallets::get_sent_txs(&node_id);
+        let bumped = match transaction::bump_fee(&tx, &wallet, fee, &utxo_set, &sent_txs) {
+            Ok(bumped) => bumped,
+            Err(e) => {
+                eprintln!("Error: cannot bump the fee of {}: {}", txid, e);
+                std::process::exit(1);
+            }
+        };
+        Cli::submit(&node_id, &bumped, encrypt);
+        println!("Replaced by {}", hex::encode(bumped.get_id()));
+    }
+
+    pub fn get_pub_key(address: &str, node_id: String) {
+        match new_wallets(node_id).get_wallet(address) {
+            Some(wallet) => println!("{}", hex::encode(wallet.public_key)),
+            None => {
+                eprintln!("Error: {} is not an address of this wallet file", address);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    pub fn create_multisig(required: usize, pub_keys: &str, node_id: String) {
+        let pub_keys: Vec<Vec<u8>> = match pub_keys.split(',').map(hex::decode).collect() {
+            Ok(pub_keys) => pub_keys,
+            Err(_) => {
+                eprintln!("Error: -pubkeys needs hex encoded public keys separated by commas");
+                std::process::exit(1);
+            }
+        };
+        let redeem_script = match script::multisig(required, &pub_keys) {
+            Ok(redeem_script) => redeem_script,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let address = wallet::calc_script_address(&wallet::hash_pub_key(&redeem_script));
+        if let Err(e) = wallets::save_redeem_script(&node_id, &address, &redeem_script) {
+            eprintln!("Error: failed to save the redeem script: {}", e);
+            std::process::exit(1);
+        }
+        println!("Your new multisig address is: {}", address);
+        println!("Redeem script: {}", hex::encode(redeem_script));
+    }
+
+    /*creates a partially signed transaction spending from the multisig
+    address from and signs it with the keys of this wallet file */
+    pub fn spend_multisig(
+        from: &str,
+        to: &str,
+        amount: i64,
+        fee: i64,
+        out_file: &str,
+        node_id: String,
+    ) {
+        if !wallet::validate_address(to.to_string()) {
+            eprintln!("Error: receiver Address is not valid");
+            std::process::exit(1);
+        }
+        let redeem_script = match wallets::get_redeem_scripts(&node_id).remove(from) {
+            Some(redeem_script) => redeem_script,
+            None => {
+                eprintln!("Error: {} is not a multisig address of this wallet file, create it with createmultisig", from);
+                std::process::exit(1);
+            }
+        };
+        let blockchain = Cli::open_blockchain(&node_id);
+        let utxo_set = utxo_set::UtxoSet::new(blockchain);
+        let psbt = transaction::new_unsigned_transaction(
+            &wallet::locking_script(from),
+            to.to_string(),
+            amount,
+            fee,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &utxo_set,
+        )
+        .and_then(|tx| Psbt::new(&tx, &utxo_set, &[redeem_script]));
+        let mut psbt = match psbt {
+            Ok(psbt) => psbt,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        };
+        Cli::sign_with_wallets(&mut psbt, &node_id);
+        Cli::write_psbt_file(out_file, &psbt);
+    }
+
+    // finalizes a multisig transaction with enough signatures and submits it
+    pub fn send_multisig(in_file: &str, node_id: String, encrypt: bool) {
+        let tx = match Cli::read_psbt_file(in_file).finalize() {
+            Ok(tx) => tx,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        };
+        Cli::submit(&node_id, &tx, encrypt);
+        println!("Success!");
+    }
+
+    // signs the inputs it can with every key of the wallet file
+    fn sign_with_wallets(psbt: &mut Psbt, node_id: &str) {
+        let wallets = new_wallets(node_id.to_string());
+        let mut signed = 0;
+        for address in wallets.get_addresses() {
+            let wallet = wallets.get_wallet(&address).unwrap();
+            signed += psbt.sign(&wallet.get_private_key());
+        }
+        println!("Added {} signatures", signed);
+    }
+
+    pub fn create_psbt(
+        from: &str,
+        to: &str,
+        amount: i64,
+        fee: i64,
+        out_file: &str,
+        node_id: String,
+    ) {
+        if !wallet::validate_address(from.to_string()) {
+            eprintln!("Error: sender Address is not valid");
+            std::process::exit(1);
+        }
+        if !wallet::validate_address(to.to_string()) {
+            eprintln!("Error: receiver Address is not valid");
+            std::process::exit(1);
+        }
+        let blockchain = Cli::open_blockchain(&node_id);
+        let utxo_set = utxo_set::UtxoSet::new(blockchain);
+        let redeem_scripts: Vec<Vec<u8>> = wallets::get_redeem_scripts(&node_id)
+            .into_values()
+            .collect();
+        let psbt = transaction::new_unsigned_transaction(
+            &wallet::locking_script(from),
+            to.to_string(),
+            amount,
+            fee,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &utxo_set,
+        )
+        .and_then(|tx| Psbt::new(&tx, &utxo_set, &redeem_scripts));
+        match psbt {
+            Ok(psbt) => Cli::write_psbt_file(out_file, &psbt),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // signs with the keys of the wallet file, the chain is not needed
+    pub fn sign_psbt(in_file: &str, out_file: &str, node_id: String) {
+        let mut psbt = Cli::read_psbt_file(in_file);
+        Cli::print_transaction(psbt.get_tx());
+        println!("Fee: {}", psbt.get_fee());
+        Cli::sign_with_wallets(&mut psbt, &node_id);
+        Cli::write_psbt_file(out_file, &psbt);
+    }
+
+    pub fn combine_psbt(in_files: &str, out_file: &str) {
+        let mut files = in_files.split(',');
+        let mut psbt = Cli::read_psbt_file(files.next().unwrap());
+        for file in files {
+            if let Err(e) = psbt.combine(&Cli::read_psbt_file(file)) {
+                eprintln!("Error: cannot combine {}: {}", file, e);
+                std::process::exit(1);
+            }
+        }
+        Cli::write_psbt_file(out_file, &psbt);
+    }
+
+    pub fn finalize_psbt(in_file: &str, out_file: &str) {
+        match Cli::read_psbt_file(in_file).finalize() {
+            Ok(tx) => {
+                Cli::write_tx_file(out_file, &tx);
+                println!("{} is signed, send it with broadcasttx", out_file);
+            }
+            Err(e) => {
+                eprintln!("Error: cannot finalize {}: {}", in_file, e);
+                std::process::exit(1);
             }
         }
+    }
 
+    pub fn broadcast_tx(in_file: &str, node_id: String, encrypt: bool) {
+        let tx = Cli::read_tx_file(in_file);
+        Cli::submit(&node_id, &tx, encrypt);
         println!("Success!");
     }
 
+    fn write_psbt_file(path: &str, psbt: &Psbt) {
+        Cli::write_file(path, psbt.to_hex());
+        match psbt.missing_signatures() {
+            0 => println!(
+                "{} has all its signatures, finalize it with finalizepsbt or sendmultisig",
+                path
+            ),
+            missing => println!("{} needs {} more signatures", path, missing),
+        }
+    }
+
+    fn read_psbt_file(path: &str) -> Psbt {
+        match Psbt::from_hex(&Cli::read_file(path)) {
+            Ok(psbt) => psbt,
+            Err(e) => {
+                eprintln!(
+                    "Error: {} is not a partially signed transaction: {}",
+                    path, e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // writes a transaction, hex encoded
+    fn write_tx_file(path: &str, tx: &Transaction) {
+        Cli::write_file(path, hex::encode(bincode::serialize(tx).unwrap()));
+    }
+
+    fn read_tx_file(path: &str) -> Transaction {
+        let tx = hex::decode(Cli::read_file(path).trim())
+            .map_err(|e| e.to_string())
+            .and_then(|data| bincode::deserialize(&data).map_err(|e| e.to_string()));
+        match tx {
+            Ok(tx) => tx,
+            Err(e) => {
+                eprintln!("Error: {} is not a transaction: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    fn write_file(path: &str, data: String) {
+        if let Err(e) = fs::write(path, data) {
+            eprintln!("Error: failed to write {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+
+    fn read_file(path: &str) -> String {
+        match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Error: failed to read {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // the values of -name value pairs, exits unless all required names and no unknown ones are given
+    fn parse_options(
+        args: &[String],
+        required: &[&str],
+        optional: &[&str],
+        usage: &str,
+    ) -> HashMap<String, String> {
+        let mut options = HashMap::new();
+        let mut args_left = args.iter();
+        while let Some(name) = args_left.next() {
+            match args_left.next() {
+                Some(value)
+                    if required.contains(&name.as_str()) || optional.contains(&name.as_str()) =>
+                {
+                    options.insert(name.clone(), value.clone());
+                }
+                _ => {
+                    println!("{}", usage);
+                    std::process::exit(1);
+                }
+            }
+        }
+        if required.iter().any(|name| !options.contains_key(*name)) {
+            println!("{}", usage);
+            std::process::exit(1);
+        }
+        options
+    }
+
+    // submits a transaction to the network and remembers it
+    fn submit(node_id: &str, tx: &Transaction, encrypt: bool) {
+        match server::broadcast_tx(node_id, tx, encrypt) {
+            Ok(addr) => println!("Submitted the transaction to {}", addr),
+            Err(e) => {
+                eprintln!("Error: failed to submit the transaction: {}", e);
+                std::process::exit(1);
+            }
+        }
+        if let Err(e) = wallets::save_sent_tx(node_id, tx) {
+            eprintln!("Warning: could not save the transaction: {}", e);
+        }
+    }
+
+    // the value given to a timelock option, exits unless it is a number up to max
+    fn parse_lock(value: Option<&String>, option: &str, max: u32) -> u32 {
+        match value.and_then(|lock| lock.parse::<u32>().ok()) {
+            Some(lock) if lock <= max => lock,
+            _ => {
+                eprintln!("Error: {} needs a number up to {}", option, max);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // the amount given to -fee, exits if it is not one
+    fn parse_fee(value: Option<&String>) -> i64 {
+        match value.and_then(|fee| fee.parse::<i64>().ok()) {
+            Some(fee) if fee >= 0 => fee,
+            _ => {
+                eprintln!("Error: -fee needs a non-negative amount");
+                std::process::exit(1);
+            }
+        }
+    }
+
     pub fn reindex_utxo(node_id: String) {
-        let bc = Blockchain::new_blockchain(node_id).unwrap();
+        let bc = Cli::open_blockchain(&node_id);
         let utxo_set = utxo_set::UtxoSet::new(bc);
         utxo_set.reindex();
         let count = utxo_set.count_transactions();
         println!("Done! There are {} transactions in the UTXO set.", count);
     }
 
-    pub fn start_node(node_id: String, miner_address: String) {
-        println!("Starting node {}", node_id);
+    pub fn start_node(config: NodeConfig) {
+        println!("Starting node {}", config.node_id);
+        let miner_address = config.mining_address.clone();
         if miner_address.len() > 0 {
             if wallet::validate_address(miner_address.clone()) {
                 println!(
@@ -240,6 +930,70 @@ impl Cli {
                 panic!("Wrong miner address!");
             }
         }
-        server::start_server(node_id, miner_address);
+        let node = match Node::new(config) {
+            Ok(node) => node,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        };
+        if let Some(node_key) = node.get_node_key() {
+            println!(
+                "Sessions are encrypted. Node key: {}",
+                hex::encode(node_key)
+            );
+        }
+        shutdown::install_handler();
+        if let Err(e) = node.start() {
+            eprintln!("Error: failed to start the node: {}", e);
+            std::process::exit(1);
+        }
+        // stops on Ctrl-C or kill, saving the mempool and the addresses before exiting
+        while node.is_running() && !shutdown::is_requested() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        println!("Shutting down");
+        node.stop();
+        node.wait();
+    }
+
+    // the chain of the node, exits if there is none or it cannot be read
+    fn open_blockchain(node_id: &str) -> Blockchain {
+        match Blockchain::new_blockchain(node_id.to_string()) {
+            Ok(bc) => bc,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // a positive whole number given to an option, exits if it is not
+    fn parse_count(value: &str) -> u64 {
+        match value.parse::<u64>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                eprintln!("Error: {} is not a positive number", value);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    fn exit_with_startnode_usage() -> ! {
+        println!(
+            "Usage: startnode -miner ADDRESS -mineinterval SECS -maxmempool MB -mempoolexpiry HOURS -bind ADDR -externalip ADDR -seed ADDR -encrypt -allowpeer KEY"
+        );
+        std::process::exit(1);
+    }
+
+    // parses a socket address such as 0.0.0.0:3000 or [::1]:3000, exiting if it is invalid
+    fn parse_socket_addr(addr: &str) -> SocketAddr {
+        match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                eprintln!("Error: {} is not a valid socket address", addr);
+                std::process::exit(1);
+            }
+        }
     }
 }
@@ -0,0 +1,244 @@
+use crate::Block;
+use crate::BlockHeader;
+use crate::Transaction;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+const SHORT_ID_LENGTH: usize = 6;
+
+// a transaction sent in full, the receiver cannot have it in its mempool
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PrefilledTx {
+    pub index: u32, // position in the block
+    pub tx: Transaction,
+}
+
+/*A block announced as its header and a short id per transaction. Peers
+have seen most of the transactions already, so they rebuild the block
+from their mempool and ask only for the ones they miss. Short ids are
+salted with the block hash and a nonce of the sender, so nobody can
+craft transactions whose ids collide in every block. */
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub nonce: u64,
+    pub short_ids: Vec<Vec<u8>>, // of the transactions that are not prefilled, in block order
+    pub prefilled: Vec<PrefilledTx>, // ascending by index
+}
+
+impl CompactBlock {
+    // prefills the coinbase, which is never in a mempool
+    pub fn new(block: &Block, nonce: u64) -> CompactBlock {
+        let header = block.get_header();
+        let mut short_ids = vec![];
+        let mut prefilled = vec![];
+        for (index, tx) in block.get_transactions().into_iter().enumerate() {
+            if tx.is_coinbase() {
+                prefilled.push(PrefilledTx {
+                    index: index as u32,
+                    tx,
+                });
+            } else {
+                short_ids.push(short_id(&header.get_hash(), nonce, &tx.get_id()));
+            }
+        }
+        CompactBlock {
+            header,
+            nonce,
+            short_ids,
+            prefilled,
+        }
+    }
+
+    pub fn tx_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    pub fn short_id(&self, tx_id: &[u8]) -> Vec<u8> {
+        short_id(&self.header.get_hash(), self.nonce, tx_id)
+    }
+}
+
+// a block being rebuilt from a compact block
+pub struct PartialBlock {
+    header: BlockHeader,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /*places the prefilled transactions and the mempool transactions
+    matching a short id. A short id matched by several transactions is
+    left missing, the sender resolves it. */
+    pub fn new<'a>(
+        compact: &CompactBlock,
+        mempool: impl Iterator<Item = &'a Transaction>,
+    ) -> Result<PartialBlock, String> {
+        let count = compact.tx_count();
+        let mut transactions: Vec<Option<Transaction>> = vec![None; count];
+        let mut prefilled_slots = vec![false; count];
+        for prefilled in &compact.prefilled {
+            let index = prefilled.index as usize;
+            if index >= count || prefilled_slots[index] {
+                return Err(format!("invalid prefilled index {}", index));
+            }
+            prefilled_slots[index] = true;
+            transactions[index] = Some(prefilled.tx.clone());
+        }
+
+        // short id -> position in the block
+        let mut positions: HashMap<&[u8], usize> = HashMap::new();
+        let mut slots = prefilled_slots
+            .iter()
+            .enumerate()
+            .filter(|(_, prefilled)| !**prefilled)
+            .map(|(index, _)| index);
+        for short_id in &compact.short_ids {
+            let index = slots.next().ok_or("too many short ids")?;
+            if positions.insert(short_id.as_slice(), index).is_some() {
+                return Err(format!("duplicate short id {}", hex::encode(short_id)));
+            }
+        }
+
+        let mut matches: HashMap<usize, usize> = HashMap::new(); // position -> number of matching transactions
+        for tx in mempool {
+            let short_id = compact.short_id(&tx.get_id());
+            if let Some(&index) = positions.get(short_id.as_slice()) {
+                *matches.entry(index).or_insert(0) += 1;
+                transactions[index] = Some(tx.clone());
+            }
+        }
+        for (index, count) in matches {
+            if count > 1 {
+                transactions[index] = None;
+            }
+        }
+
+        Ok(PartialBlock {
+            header: compact.header.clone(),
+            transactions,
+        })
+    }
+
+    pub fn get_hash(&self) -> Vec<u8> {
+        self.header.get_hash()
+    }
+
+    // the positions of the transactions still missing
+    pub fn missing(&self) -> Vec<u32> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    // fills the missing transactions, in the order of missing()
+    pub fn fill(&mut self, transactions: Vec<Transaction>) -> Result<(), String> {
+        let missing = self.missing();
+        if transactions.len() != missing.len() {
+            return Err(format!(
+                "{} transactions for {} missing",
+                transactions.len(),
+                missing.len()
+            ));
+        }
+        for (index, tx) in missing.into_iter().zip(transactions) {
+            self.transactions[index as usize] = Some(tx);
+        }
+        Ok(())
+    }
+
+    // the rebuilt block, once no transaction is missing
+    pub fn to_block(&self) -> Option<Block> {
+        let transactions: Option<Vec<Transaction>> = self.transactions.iter().cloned().collect();
+        transactions.map(|transactions| Block::from_header(&self.header, transactions))
+    }
+}
+
+fn short_id(block_hash: &[u8], nonce: u64, tx_id: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(block_hash);
+    hasher.update(nonce.to_le_bytes());
+    let salt = hasher.finalize();
+
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(tx_id);
+    hasher.finalize()[..SHORT_ID_LENGTH].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use crate::transaction;
+    use crate::UtxoSet;
+    use crate::Wallet;
+
+    // a block with a coinbase and two transactions on a new chain of node_id
+    fn block_of_two_txs(node_id: &str) -> Block {
+        let (wallet, mut bc) = test_util::new_chain(node_id);
+        let other_payer = Wallet::new_wallet();
+        test_util::mine(&mut bc, vec![], &other_payer);
+        let utxo_set = UtxoSet::new(bc.clone());
+        let txs: Vec<Transaction> = [&wallet, &other_payer]
+            .iter()
+            .map(|payer| {
+                let to = test_util::address(&Wallet::new_wallet());
+                let sequence = transaction::SEQUENCE_FINAL;
+                transaction::new_utxo_transaction(payer, to, 3, 1, sequence, 0, &utxo_set)
+            })
+            .collect();
+        let to = test_util::address(&wallet);
+        let coinbase = transaction::new_coinbase_tx(to, "Height 2".to_string(), 2);
+        Block::new_block([vec![coinbase], txs].concat(), bc.get_tip_hash(), 2)
+    }
+
+    #[test]
+    fn a_block_is_rebuilt_from_the_mempool_and_the_missing_transactions() {
+        let block = block_of_two_txs("compact_block_rebuilt");
+        let txs = block.get_transactions();
+        let compact = CompactBlock::new(&block, 7);
+        assert_eq!(compact.tx_count(), 3);
+        assert_eq!(compact.prefilled.len(), 1);
+        assert_eq!(compact.prefilled[0].index, 0);
+
+        let mut partial = PartialBlock::new(&compact, [&txs[2]].into_iter()).unwrap();
+        assert_eq!(partial.missing(), vec![1]);
+        assert!(partial.to_block().is_none());
+        assert!(partial.fill(vec![]).is_err());
+        partial.fill(vec![txs[1].clone()]).unwrap();
+        let rebuilt = partial.to_block().unwrap();
+        assert_eq!(rebuilt.get_hash(), block.get_hash());
+        let ids = |block: &Block| {
+            block
+                .get_transactions()
+                .iter()
+                .map(|tx| tx.get_id())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&rebuilt), ids(&block));
+
+        // short ids change with the nonce
+        assert_ne!(CompactBlock::new(&block, 8).short_ids, compact.short_ids);
+    }
+
+    #[test]
+    fn malformed_compact_blocks_are_refused() {
+        let block = block_of_two_txs("compact_block_malformed");
+        let compact = CompactBlock::new(&block, 7);
+        let mut duplicate = compact.clone();
+        duplicate.short_ids[1] = duplicate.short_ids[0].clone();
+        assert!(PartialBlock::new(&duplicate, [].into_iter()).is_err());
+
+        let mut out_of_range = compact.clone();
+        out_of_range.prefilled[0].index = 3;
+        assert!(PartialBlock::new(&out_of_range, [].into_iter()).is_err());
+        let mut twice = compact;
+        twice.prefilled.push(twice.prefilled[0].clone());
+        twice.short_ids.pop();
+        assert!(PartialBlock::new(&twice, [].into_iter()).is_err());
+    }
+}
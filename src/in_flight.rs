@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+struct Request {
+    peer: String,
+    sent: Instant,
+}
+
+/*Tracks the blocks or transactions requested with getdata, by id: which
+peer was asked and when, and which other peers announced the item, so it
+can be asked from one of them when the request fails or times out. */
+pub struct InFlight {
+    requests: HashMap<Vec<u8>, Request>,
+    announcers: HashMap<Vec<u8>, Vec<String>>, // peers not asked yet, in the order they announced the item
+    tracked: HashMap<String, usize>, // the number of items each peer is asked for or announced
+}
+
+impl InFlight {
+    pub fn new() -> InFlight {
+        InFlight {
+            requests: HashMap::new(),
+            announcers: HashMap::new(),
+            tracked: HashMap::new(),
+        }
+    }
+
+    // records that peer has the item
+    pub fn announce(&mut self, id: Vec<u8>, peer: &str) {
+        if self.requested_from(&id) == Some(peer) {
+            return;
+        }
+        let announcers = self.announcers.entry(id).or_default();
+        if !announcers.iter().any(|announcer| announcer == peer) {
+            announcers.push(peer.to_string());
+            self.track(peer);
+        }
+    }
+
+    pub fn is_requested(&self, id: &[u8]) -> bool {
+        self.requests.contains_key(id)
+    }
+
+    pub fn requested_from(&self, id: &[u8]) -> Option<&str> {
+        self.requests.get(id).map(|request| request.peer.as_str())
+    }
+
+    // how long ago the item was requested
+    pub fn request_age(&self, id: &[u8]) -> Option<Duration> {
+        self.requests.get(id).map(|request| request.sent.elapsed())
+    }
+
+    // the number of items requested from peer and not delivered yet
+    pub fn count_for(&self, peer: &str) -> usize {
+        self.requests
+            .values()
+            .filter(|request| request.peer == peer)
+            .count()
+    }
+
+    // the number of items peer is asked for or announced, so a peer cannot make us track without bound
+    pub fn count_tracked(&self, peer: &str) -> usize {
+        self.tracked.get(peer).copied().unwrap_or(0)
+    }
+
+    pub fn request(&mut self, id: Vec<u8>, peer: &str) {
+        if let Some(announcers) = self.announcers.get_mut(&id) {
+            let count = announcers.len();
+            announcers.retain(|announcer| announcer != peer);
+            if announcers.len() < count {
+                self.untrack(peer);
+            }
+        }
+        let previous = self.requests.insert(
+            id,
+            Request {
+                peer: peer.to_string(),
+                sent: Instant::now(),
+            },
+        );
+        if let Some(previous) = previous {
+            self.untrack(&previous.peer);
+        }
+        self.track(peer);
+    }
+
+    // forgets a delivered item, returns the peer it was requested from
+    pub fn received(&mut self, id: &[u8]) -> Option<String> {
+        for announcer in self.announcers.remove(id).unwrap_or_default() {
+            self.untrack(&announcer);
+        }
+        let peer = self.requests.remove(id)?.peer;
+        self.untrack(&peer);
+        Some(peer)
+    }
+
+    // drops the request of an item peer does not have, returns whether there was one
+    pub fn not_found(&mut self, id: &[u8], peer: &str) -> bool {
+        if self.requested_from(id) != Some(peer) {
+            return false;
+        }
+        self.requests.remove(id);
+        self.untrack(peer);
+        true
+    }
+
+    // drops and returns the ids of the requests older than timeout with the peers they were sent to
+    pub fn expire(&mut self, timeout: Duration) -> Vec<(Vec<u8>, String)> {
+        let expired: Vec<Vec<u8>> = self
+            .requests
+            .iter()
+            .filter(|(_, request)| request.sent.elapsed() > timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let expired: Vec<(Vec<u8>, String)> = expired
+            .into_iter()
+            .filter_map(|id| self.requests.remove(&id).map(|request| (id, request.peer)))
+            .collect();
+        for (_, peer) in &expired {
+            self.untrack(peer);
+        }
+        expired
+    }
+
+    // forgets a disconnected peer, returns the ids of the items requested from it
+    pub fn remove_peer(&mut self, peer: &str) -> Vec<Vec<u8>> {
+        for announcers in self.announcers.values_mut() {
+            announcers.retain(|announcer| announcer != peer);
+        }
+        let ids: Vec<Vec<u8>> = self
+            .requests
+            .iter()
+            .filter(|(_, request)| request.peer == peer)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &ids {
+            self.requests.remove(id);
+        }
+        self.tracked.remove(peer);
+        ids
+    }
+
+    /*returns the next peer to ask for an item that is not requested,
+    forgetting the item when no peer is left */
+    pub fn next_announcer(&mut self, id: &[u8]) -> Option<String> {
+        let announcers = self.announcers.get_mut(id)?;
+        let next = if announcers.is_empty() {
+            None
+        } else {
+            Some(announcers.remove(0))
+        };
+        match &next {
+            Some(peer) => self.untrack(peer),
+            None => {
+                self.announcers.remove(id);
+            }
+        }
+        next
+    }
+
+    fn track(&mut self, peer: &str) {
+        *self.tracked.entry(peer.to_string()).or_default() += 1;
+    }
+
+    fn untrack(&mut self, peer: &str) {
+        if let Some(count) = self.tracked.get_mut(peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.tracked.remove(peer);
+            }
+        }
+    }
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        InFlight::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_failed_request_is_asked_from_the_next_announcer() {
+        let mut in_flight = InFlight::new();
+        in_flight.request(vec![1], "a");
+        in_flight.announce(vec![1], "b");
+        in_flight.announce(vec![1], "c");
+        in_flight.announce(vec![1], "b");
+        assert_eq!(in_flight.requested_from(&[1]), Some("a"));
+
+        assert!(!in_flight.not_found(&[1], "b"));
+        assert!(in_flight.not_found(&[1], "a"));
+        assert_eq!(in_flight.next_announcer(&[1]).as_deref(), Some("b"));
+        in_flight.request(vec![1], "b");
+        assert_eq!(in_flight.remove_peer("b"), vec![vec![1]]);
+        assert_eq!(in_flight.next_announcer(&[1]).as_deref(), Some("c"));
+        assert_eq!(in_flight.next_announcer(&[1]), None);
+    }
+
+    #[test]
+    fn expired_requests_are_returned_with_their_peers() {
+        let mut in_flight = InFlight::new();
+        in_flight.request(vec![1], "a");
+        assert!(in_flight.expire(Duration::from_secs(60)).is_empty());
+        assert_eq!(
+            in_flight.expire(Duration::ZERO),
+            vec![(vec![1], "a".to_string())]
+        );
+        assert!(!in_flight.is_requested(&[1]));
+    }
+
+    #[test]
+    fn items_are_counted_for_each_peer_until_they_are_done_with() {
+        let mut in_flight = InFlight::new();
+        in_flight.request(vec![1], "a");
+        in_flight.request(vec![2], "a");
+        in_flight.announce(vec![1], "b");
+        in_flight.announce(vec![2], "b");
+        assert_eq!(in_flight.count_for("a"), 2);
+        assert_eq!(in_flight.count_tracked("a"), 2);
+        assert_eq!(in_flight.count_tracked("b"), 2);
+
+        // asking another peer moves the item over
+        in_flight.not_found(&[1], "a");
+        let next = in_flight.next_announcer(&[1]).unwrap();
+        in_flight.request(vec![1], &next);
+        assert_eq!(in_flight.count_tracked("a"), 1);
+        assert_eq!(in_flight.count_tracked("b"), 2);
+
+        assert_eq!(in_flight.received(&[2]).as_deref(), Some("a"));
+        assert_eq!(in_flight.count_tracked("a"), 0);
+        assert_eq!(in_flight.count_tracked("b"), 1);
+        in_flight.remove_peer("b");
+        assert_eq!(in_flight.count_tracked("b"), 0);
+    }
+}
@@ -1,5 +1,6 @@
 mod block;
 pub use block::Block;
+pub use block::BlockHeader;
 
 mod blockchain;
 pub use blockchain::Blockchain;
@@ -10,6 +11,9 @@ pub use proofofwork::ProofOfWork;
 
 mod cli;
 pub use cli::Cli;
+pub use cli::SendOptions;
+
+mod script;
 
 mod transaction;
 pub use transaction::TXOutput;
@@ -26,4 +30,41 @@ pub use utxo_set::UtxoSet;
 
 mod merkle_tree;
 
+mod addr_book;
+
+mod ban_list;
+
+mod compact_block;
+
+mod in_flight;
+
+mod mempool;
+pub use mempool::MempoolLimits;
+
+mod miner;
+
+mod message;
+
+mod orphan_blocks;
+
+mod orphan_txs;
+
+mod peer;
+
+mod psbt;
+pub use psbt::Psbt;
+
+mod recently_seen;
+pub use peer::PeerInfo;
+pub use peer::PeerVersion;
+
+mod shutdown;
+
 mod server;
+pub use server::Node;
+pub use server::NodeConfig;
+
+mod transport;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,949 @@
+use crate::Block;
+use crate::Transaction;
+use crate::UtxoSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::env::current_dir;
+use std::fmt;
+use std::fs;
+use std::time::Duration;
+
+const MEMPOOL_FILE: &str = "mempool_{}.dat";
+const INCREMENTAL_RELAY_FEE: i64 = 1; // per 1000 bytes, the minimum fee rate rises this much above an evicted package
+const MIN_FEE_HALF_LIFE: i64 = 12 * 60 * 60; // seconds for the raised minimum fee rate to fall by half
+const MAX_REPLACED: usize = 100; // transactions one replacement may evict, descendants included
+
+// what the mempool holds at most
+pub struct MempoolLimits {
+    pub max_size: usize, // bytes of transactions, the lowest fee rates are evicted beyond it
+    pub expiry: Duration, // transactions pending longer are dropped
+    pub max_ancestors: usize, // unconfirmed transactions a transaction depends on, itself included
+    pub max_descendants: usize, // unconfirmed transactions depending on a transaction, itself included
+}
+
+impl Default for MempoolLimits {
+    fn default() -> MempoolLimits {
+        MempoolLimits {
+            max_size: 300_000_000,
+            expiry: Duration::from_secs(14 * 24 * 60 * 60),
+            max_ancestors: 25,
+            max_descendants: 25,
+        }
+    }
+}
+
+// why the mempool did not take a transaction
+#[derive(Debug)]
+pub enum Rejection {
+    AlreadyKnown,
+    MissingInputs,       // spends outputs that are unknown or spent in the chain
+    Conflict(String), // spends an output the pool transaction with this id spends already, which is not replaceable
+    Replacement(String), // would replace pool transactions without paying enough for it
+    Invalid(String),  // no honest node relays it
+    Mutated(String), // invalid in parts its id does not cover, a copy with the same id may be valid
+    FeeTooLow(i64),  // the fee rate is below this minimum, per 1000 bytes
+    TooLongChain(String),
+    NonFinal(String), // its lock time or a relative lock keeps it out of the next block
+    PoolFull,         // it pays less than every transaction the pool would have to evict for it
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::AlreadyKnown => write!(f, "already in the mempool"),
+            Rejection::MissingInputs => write!(f, "inputs missing or spent"),
+            Rejection::Conflict(id) => write!(f, "conflicts with {}", id),
+            Rejection::Replacement(reason) => write!(f, "replacement rejected: {}", reason),
+            Rejection::Invalid(reason) => write!(f, "{}", reason),
+            Rejection::Mutated(reason) => write!(f, "{}", reason),
+            Rejection::FeeTooLow(min) => {
+                write!(f, "fee rate below the minimum of {} per 1000 bytes", min)
+            }
+            Rejection::TooLongChain(reason) => write!(f, "{}", reason),
+            Rejection::NonFinal(reason) => write!(f, "not final, {}", reason),
+            Rejection::PoolFull => write!(f, "mempool full"),
+        }
+    }
+}
+
+struct Entry {
+    tx: Transaction,
+    fee: i64,
+    size: usize,         // serialized size in bytes
+    sequence: u64,       // admission order, a transaction always comes after its parents
+    time: i64,           // when it entered the pool
+    descendant_fee: i64, // of the transaction and its descendants in the pool
+    descendant_size: usize,
+}
+
+impl Entry {
+    // what the transaction with its descendants pays per 1000 bytes
+    fn descendant_rate(&self) -> i64 {
+        fee_rate(self.descendant_fee, self.descendant_size)
+    }
+}
+
+/*The unconfirmed transactions a node relays and mines. Every
+transaction spends outputs of the UTXO set or of other pool transactions,
+and no two transactions spend the same output. The pool follows the chain
+tip: confirmed and conflicting transactions leave it, transactions of
+blocks disconnected by a reorg come back.
+
+When the pool outgrows its limit, the transactions whose package with
+their descendants pays the lowest fee rate are evicted, and the minimum
+fee rate for new transactions rises above theirs. It falls back by half
+every MIN_FEE_HALF_LIFE. */
+pub struct Mempool {
+    entries: HashMap<String, Entry>,             // by hex transaction id
+    spent: HashMap<(String, i64), String>, // outpoint (hex tx id, vout) -> id of the pool transaction spending it
+    by_descendant_rate: BTreeSet<(i64, String)>, // ids by the fee rate of their package with their descendants, for eviction
+    tip: Vec<u8>,                                // the block the pool is valid on
+    next_sequence: u64,
+    limits: MempoolLimits,
+    total_size: usize,
+    min_fee_rate: i64, // per 1000 bytes, as raised by the last eviction
+    min_fee_time: i64, // when it was raised
+}
+
+impl Mempool {
+    pub fn new(tip: Vec<u8>, limits: MempoolLimits) -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+            spent: HashMap::new(),
+            by_descendant_rate: BTreeSet::new(),
+            tip,
+            next_sequence: 0,
+            limits,
+            total_size: 0,
+            min_fee_rate: 0,
+            min_fee_time: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // bytes of all transactions
+    pub fn get_total_size(&self) -> usize {
+        self.total_size
+    }
+
+    // the fee rate a new transaction must pay, per 1000 bytes
+    pub fn get_min_fee_rate(&self) -> i64 {
+        let halvings = (chrono::Utc::now().timestamp() - self.min_fee_time) / MIN_FEE_HALF_LIFE;
+        let rate = self
+            .min_fee_rate
+            .checked_shr(halvings as u32)
+            .unwrap_or_default();
+        if rate < INCREMENTAL_RELAY_FEE {
+            0
+        } else {
+            rate
+        }
+    }
+
+    pub fn contains(&self, id: &[u8]) -> bool {
+        self.entries.contains_key(&hex::encode(id))
+    }
+
+    pub fn get(&self, id: &[u8]) -> Option<&Transaction> {
+        self.entries.get(&hex::encode(id)).map(|entry| &entry.tx)
+    }
+
+    pub fn get_fee(&self, id: &[u8]) -> Option<i64> {
+        self.entries.get(&hex::encode(id)).map(|entry| entry.fee)
+    }
+
+    pub fn get_size(&self, id: &[u8]) -> Option<usize> {
+        self.entries.get(&hex::encode(id)).map(|entry| entry.size)
+    }
+
+    // the ids of the unconfirmed transactions a transaction spends from, directly or not, parents first
+    pub fn get_ancestors(&self, id: &[u8]) -> Vec<Vec<u8>> {
+        let tx = match self.get(id) {
+            Some(tx) => tx,
+            None => return vec![],
+        };
+        let mut ancestors: Vec<&Entry> = self
+            .ancestors_of(tx)
+            .iter()
+            .filter_map(|id| self.entries.get(id))
+            .collect();
+        ancestors.sort_by_key(|entry| entry.sequence);
+        ancestors
+            .into_iter()
+            .map(|entry| entry.tx.get_id())
+            .collect()
+    }
+
+    pub fn get_tip(&self) -> Vec<u8> {
+        self.tip.clone()
+    }
+
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.entries.values().map(|entry| &entry.tx)
+    }
+
+    // all transactions, parents before their children
+    pub fn get_transactions(&self) -> Vec<Transaction> {
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        entries.into_iter().map(|entry| entry.tx.clone()).collect()
+    }
+
+    /*validates a transaction against the UTXO set and the pool and adds
+    it, evicting the lowest fee rates if the pool outgrows its limit.
+    Returns the transactions it replaced. */
+    pub fn add(
+        &mut self,
+        tx: Transaction,
+        utxo_set: &UtxoSet,
+    ) -> Result<Vec<Transaction>, Rejection> {
+        let id = hex::encode(tx.get_id());
+        let replaced = self.insert(tx, utxo_set, chrono::Utc::now().timestamp(), true)?;
+        self.trim();
+        if !self.entries.contains_key(&id) {
+            // the replaced transactions paid for their place, they get it back
+            for entry in replaced {
+                let _ = self.insert(entry.tx, utxo_set, entry.time, false);
+            }
+            return Err(Rejection::PoolFull);
+        }
+        Ok(replaced.into_iter().map(|entry| entry.tx).collect())
+    }
+
+    /*saves the transactions with the time they entered the pool, parents
+    first. The file is replaced at once, a crash leaves the previous one. */
+    pub fn save_to_file(&self, node_id: &str) -> Result<(), String> {
+        let mut entries: Vec<&Entry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        let saved: Vec<(&Transaction, i64)> = entries
+            .iter()
+            .map(|entry| (&entry.tx, entry.time))
+            .collect();
+        let data = bincode::serialize(&saved).map_err(|e| e.to_string())?;
+        let path = mempool_path(node_id);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data).map_err(|e| e.to_string())?;
+        fs::rename(tmp_path, path).map_err(|e| e.to_string())
+    }
+
+    /*adds the transactions saved by save_to_file that are still valid on
+    the chain of utxo_set and not expired. Returns how many were added. */
+    pub fn load_from_file(&mut self, node_id: &str, utxo_set: &UtxoSet) -> usize {
+        let saved: Vec<(Transaction, i64)> = fs::read(mempool_path(node_id))
+            .ok()
+            .and_then(|data| bincode::deserialize(&data).ok())
+            .unwrap_or_default();
+        let horizon = chrono::Utc::now().timestamp() - self.limits.expiry.as_secs() as i64;
+        for (tx, time) in saved {
+            if time >= horizon {
+                let _ = self.insert(tx, utxo_set, time, true);
+            }
+        }
+        self.trim();
+        self.entries.len()
+    }
+
+    // drops the transactions pending longer than the expiry, with their descendants
+    pub fn expire(&mut self) -> Vec<Transaction> {
+        let horizon = chrono::Utc::now().timestamp() - self.limits.expiry.as_secs() as i64;
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.time < horizon)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let mut removed = vec![];
+        for id in expired {
+            removed.extend(self.remove_with_descendants(&id));
+        }
+        removed
+    }
+
+    fn insert(
+        &mut self,
+        tx: Transaction,
+        utxo_set: &UtxoSet,
+        time: i64,
+        check_fee: bool,
+    ) -> Result<Vec<Entry>, Rejection> {
+        let id = hex::encode(tx.get_id());
+        if self.entries.contains_key(&id) {
+            return Err(Rejection::AlreadyKnown);
+        }
+        if tx.is_coinbase() {
+            return Err(Rejection::Invalid("coinbase outside a block".to_string()));
+        }
+        if !tx.has_valid_id() {
+            return Err(Rejection::Mutated("id is not its hash".to_string()));
+        }
+        if tx.get_vin().is_empty() || tx.get_vout().is_empty() {
+            return Err(Rejection::Invalid("no inputs or no outputs".to_string()));
+        }
+
+        let mut outpoints = HashSet::new();
+        let mut conflicts = HashSet::new();
+        let mut prev_outs = vec![];
+        let mut input_value: i64 = 0;
+        for vin in tx.get_vin() {
+            let outpoint = (hex::encode(vin.get_txid()), vin.get_vout());
+            if !outpoints.insert(outpoint.clone()) {
+                return Err(Rejection::Invalid("spends an output twice".to_string()));
+            }
+            if let Some(spender) = self.spent.get(&outpoint) {
+                if !self.entries[spender].tx.is_replaceable() {
+                    return Err(Rejection::Conflict(spender.clone()));
+                }
+                conflicts.insert(spender.clone());
+            }
+            let prev_out = match self.entries.get(&outpoint.0) {
+                Some(parent) => usize::try_from(vin.get_vout())
+                    .ok()
+                    .and_then(|index| parent.tx.get_vout().get(index).cloned())
+                    .ok_or_else(|| {
+                        Rejection::Invalid(format!(
+                            "spends missing output {} of {}",
+                            vin.get_vout(),
+                            outpoint.0
+                        ))
+                    })?,
+                None => utxo_set
+                    .find_output(&vin.get_txid(), vin.get_vout())
+                    .ok_or(Rejection::MissingInputs)?,
+            };
+            input_value = input_value
+                .checked_add(prev_out.get_value())
+                .ok_or_else(|| Rejection::Invalid("input value overflows".to_string()))?;
+            prev_outs.push(prev_out);
+        }
+
+        let mut output_value: i64 = 0;
+        for out in tx.get_vout() {
+            if out.get_value() <= 0 {
+                return Err(Rejection::Invalid("output without value".to_string()));
+            }
+            output_value = output_value
+                .checked_add(out.get_value())
+                .ok_or_else(|| Rejection::Invalid("output value overflows".to_string()))?;
+        }
+        if output_value > input_value {
+            return Err(Rejection::Invalid(
+                "spends more than its inputs".to_string(),
+            ));
+        }
+        // it must be able to go into the next block
+        let bc = utxo_set.get_blockchain();
+        bc.check_locks(&tx, bc.get_best_height() + 1, &bc.get_tip_hash())
+            .map_err(Rejection::NonFinal)?;
+
+        let fee = input_value - output_value;
+        let size = bincode::serialize(&tx).unwrap().len();
+        if check_fee {
+            let min_fee_rate = self.get_min_fee_rate();
+            if fee_rate(fee, size) < min_fee_rate {
+                return Err(Rejection::FeeTooLow(min_fee_rate));
+            }
+        }
+        let replaced = self.check_replacement(&tx, &conflicts, fee, size)?;
+        self.check_chain_limits(&tx)?;
+        tx.verify_scripts(&prev_outs).map_err(Rejection::Mutated)?;
+
+        let replaced = self.remove_entries(replaced);
+        for ancestor in self.ancestors_of(&tx) {
+            self.change_descendant_totals(&ancestor, fee, size as i64);
+        }
+        for outpoint in outpoints {
+            self.spent.insert(outpoint, id.clone());
+        }
+        self.by_descendant_rate
+            .insert((fee_rate(fee, size), id.clone()));
+        self.entries.insert(
+            id,
+            Entry {
+                tx,
+                fee,
+                size,
+                sequence: self.next_sequence,
+                time,
+                descendant_fee: fee,
+                descendant_size: size,
+            },
+        );
+        self.total_size += size;
+        self.next_sequence += 1;
+        Ok(replaced)
+    }
+
+    /*checks a transaction spending the same outputs as the replaceable pool
+    transactions conflicts, and returns the ids of the transactions it
+    replaces: the conflicts and their descendants. The replacement must pay
+    a higher fee than all of them together, so relaying it is paid for,
+    and a higher fee rate than each conflict, so miners prefer it. */
+    fn check_replacement(
+        &self,
+        tx: &Transaction,
+        conflicts: &HashSet<String>,
+        fee: i64,
+        size: usize,
+    ) -> Result<Vec<String>, Rejection> {
+        let mut replaced: Vec<String> = vec![];
+        if conflicts.is_empty() {
+            return Ok(replaced);
+        }
+        for conflict in conflicts {
+            for id in self.descendants_of(conflict) {
+                if !replaced.contains(&id) {
+                    replaced.push(id);
+                }
+            }
+        }
+        if replaced.len() > MAX_REPLACED {
+            return Err(Rejection::Replacement(format!(
+                "would replace {} transactions, more than {}",
+                replaced.len(),
+                MAX_REPLACED
+            )));
+        }
+        if tx
+            .get_vin()
+            .iter()
+            .any(|vin| replaced.contains(&hex::encode(vin.get_txid())))
+        {
+            return Err(Rejection::Replacement(
+                "spends an output of a transaction it replaces".to_string(),
+            ));
+        }
+        // it pays for its own relay on top of what the replaced ones paid
+        let replaced_fee: i64 = replaced.iter().map(|id| self.entries[id].fee).sum();
+        let min_fee = replacement_fee(replaced_fee, size);
+        if fee < min_fee {
+            return Err(Rejection::Replacement(format!(
+                "fee {} is below {}, the {} of the replaced transactions plus the incremental relay fee",
+                fee, min_fee, replaced_fee
+            )));
+        }
+        for conflict in conflicts {
+            let entry = &self.entries[conflict];
+            if fee as i128 * entry.size as i128 <= entry.fee as i128 * size as i128 {
+                return Err(Rejection::Replacement(format!(
+                    "fee rate is not above the one of {}",
+                    conflict
+                )));
+            }
+        }
+        Ok(replaced)
+    }
+
+    /*refuses a transaction with too many unconfirmed ancestors, or that
+    would give one of them too many descendants, so long chains cannot pin
+    the pool or make every block template expensive */
+    fn check_chain_limits(&self, tx: &Transaction) -> Result<(), Rejection> {
+        let ancestors = self.ancestors_of(tx);
+        if ancestors.len() + 1 > self.limits.max_ancestors {
+            return Err(Rejection::TooLongChain(format!(
+                "more than {} unconfirmed ancestors",
+                self.limits.max_ancestors - 1
+            )));
+        }
+        for ancestor in &ancestors {
+            if self.descendants_of(ancestor).len() + 1 > self.limits.max_descendants {
+                return Err(Rejection::TooLongChain(format!(
+                    "{} has {} unconfirmed descendants already",
+                    ancestor, self.limits.max_descendants
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /*evicts the transactions whose package with their descendants pays
+    the lowest fee rate until the pool fits its limit, and raises the
+    minimum fee rate above the evicted ones */
+    fn trim(&mut self) {
+        while self.total_size > self.limits.max_size {
+            let (rate, id) = match self.by_descendant_rate.first() {
+                Some(lowest) => lowest.clone(),
+                None => break,
+            };
+            let removed = self.remove_with_descendants(&id);
+            let min_fee_rate = self
+                .get_min_fee_rate()
+                .max(rate.saturating_add(INCREMENTAL_RELAY_FEE));
+            self.min_fee_rate = min_fee_rate;
+            self.min_fee_time = chrono::Utc::now().timestamp();
+            println!(
+                "Mempool full, evicted {} transactions paying {} per 1000 bytes",
+                removed.len(),
+                rate
+            );
+        }
+    }
+
+    // the ids of the pool transactions a transaction spends from, directly or not
+    fn ancestors_of(&self, tx: &Transaction) -> HashSet<String> {
+        let mut ancestors = HashSet::new();
+        let mut stack = vec![tx];
+        while let Some(tx) = stack.pop() {
+            for vin in tx.get_vin() {
+                let parent_id = hex::encode(vin.get_txid());
+                if let Some(parent) = self.entries.get(&parent_id) {
+                    if ancestors.insert(parent_id) {
+                        stack.push(&parent.tx);
+                    }
+                }
+            }
+        }
+        ancestors
+    }
+
+    // a pool transaction and the ids of the pool transactions spending from it, directly or not
+    fn descendants_of(&self, id: &str) -> Vec<String> {
+        let mut descendants = vec![];
+        let mut seen = HashSet::new();
+        let mut stack = vec![id.to_string()];
+        while let Some(id) = stack.pop() {
+            let entry = match self.entries.get(&id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+            for index in 0..entry.tx.get_vout().len() {
+                if let Some(child) = self.spent.get(&(id.clone(), index as i64)) {
+                    stack.push(child.clone());
+                }
+            }
+            descendants.push(id);
+        }
+        descendants
+    }
+
+    /*brings the pool to the tip of the chain of utxo_set, whose UTXO set
+    must be up to date. Returns the transactions removed for conflicting
+    with the new blocks. */
+    pub fn sync_with_chain(&mut self, utxo_set: &UtxoSet) -> Vec<Transaction> {
+        let bc = utxo_set.get_blockchain();
+        let mut conflicted = vec![];
+        if bc.get_tip_hash() == self.tip {
+            return conflicted;
+        }
+        let (disconnected, connected) = bc.find_fork(&self.tip);
+        for block in &connected {
+            conflicted.extend(self.remove_for_block(block));
+        }
+        if !disconnected.is_empty() {
+            self.readmit(&disconnected, utxo_set);
+        }
+        self.tip = bc.get_tip_hash();
+        conflicted
+    }
+
+    /*removes the transactions confirmed by a block, and those spending
+    the same outputs as the block with their descendants */
+    fn remove_for_block(&mut self, block: &Block) -> Vec<Transaction> {
+        let mut conflicted = vec![];
+        for tx in block.get_transactions() {
+            self.remove_entry(&hex::encode(tx.get_id()));
+            if tx.is_coinbase() {
+                continue;
+            }
+            for vin in tx.get_vin() {
+                let outpoint = (hex::encode(vin.get_txid()), vin.get_vout());
+                if let Some(spender) = self.spent.get(&outpoint).cloned() {
+                    conflicted.extend(self.remove_with_descendants(&spender));
+                }
+            }
+        }
+        conflicted
+    }
+
+    /*puts the transactions of blocks that left the main chain back, oldest
+    first, and checks the pool again, as the reorg may have spent its
+    inputs or removed them */
+    fn readmit(&mut self, disconnected: &[Block], utxo_set: &UtxoSet) {
+        let now = chrono::Utc::now().timestamp();
+        let mut candidates: Vec<(Transaction, i64)> = disconnected
+            .iter()
+            .rev()
+            .flat_map(|block| block.get_transactions())
+            .filter(|tx| !tx.is_coinbase())
+            .map(|tx| (tx, now))
+            .collect();
+        let mut entries: Vec<Entry> = self.entries.drain().map(|(_, entry)| entry).collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        candidates.extend(entries.into_iter().map(|entry| (entry.tx, entry.time)));
+        self.spent.clear();
+        self.by_descendant_rate.clear();
+        self.total_size = 0;
+        for (tx, time) in candidates {
+            let _ = self.insert(tx, utxo_set, time, false);
+        }
+        self.trim();
+    }
+
+    fn remove_with_descendants(&mut self, id: &str) -> Vec<Transaction> {
+        let descendants = self.descendants_of(id);
+        self.remove_entries(descendants)
+            .into_iter()
+            .map(|entry| entry.tx)
+            .collect()
+    }
+
+    /*removes transactions children first, so the ancestors of each are
+    still there to take it out of their descendant totals. Returns them
+    parents first. */
+    fn remove_entries(&mut self, mut ids: Vec<String>) -> Vec<Entry> {
+        ids.sort_by_key(|id| std::cmp::Reverse(self.entries.get(id).map(|e| e.sequence)));
+        let mut removed: Vec<Entry> = ids.iter().filter_map(|id| self.remove_entry(id)).collect();
+        removed.reverse();
+        removed
+    }
+
+    fn remove_entry(&mut self, id: &str) -> Option<Entry> {
+        let entry = self.entries.remove(id)?;
+        self.by_descendant_rate
+            .remove(&(entry.descendant_rate(), id.to_string()));
+        for ancestor in self.ancestors_of(&entry.tx) {
+            self.change_descendant_totals(&ancestor, -entry.fee, -(entry.size as i64));
+        }
+        self.total_size -= entry.size;
+        for vin in entry.tx.get_vin() {
+            self.spent
+                .remove(&(hex::encode(vin.get_txid()), vin.get_vout()));
+        }
+        Some(entry)
+    }
+
+    // adds to the totals of a transaction with its descendants, keeping the eviction index in order
+    fn change_descendant_totals(&mut self, id: &str, fee: i64, size: i64) {
+        let entry = match self.entries.get_mut(id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        self.by_descendant_rate
+            .remove(&(entry.descendant_rate(), id.to_string()));
+        entry.descendant_fee += fee;
+        entry.descendant_size = (entry.descendant_size as i64 + size) as usize;
+        self.by_descendant_rate
+            .insert((entry.descendant_rate(), id.to_string()));
+    }
+}
+
+fn mempool_path(node_id: &str) -> std::path::PathBuf {
+    current_dir()
+        .unwrap()
+        .join(MEMPOOL_FILE.replace("{}", node_id))
+}
+
+// the incremental relay fee of size bytes, rounded up
+pub fn incremental_fee(size: usize) -> i64 {
+    (INCREMENTAL_RELAY_FEE.saturating_mul(size as i64) + 999) / 1000
+}
+
+// the least a replacement of size bytes pays for the transactions it replaces
+pub fn replacement_fee(replaced_fee: i64, size: usize) -> i64 {
+    replaced_fee.saturating_add(incremental_fee(size))
+}
+
+// fee per 1000 bytes
+fn fee_rate(fee: i64, size: usize) -> i64 {
+    fee.saturating_mul(1000) / size.max(1) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use crate::transaction;
+    use crate::Blockchain;
+    use crate::Wallet;
+
+    // spends the genesis output of the wallet, paying fee
+    fn spend(wallet: &Wallet, fee: i64, sequence: u32, utxo_set: &UtxoSet) -> Transaction {
+        let to = test_util::address(&Wallet::new_wallet());
+        transaction::new_utxo_transaction(wallet, to, 3, fee, sequence, 0, utxo_set)
+    }
+
+    #[test]
+    fn a_block_spending_the_same_output_removes_the_pool_transaction() {
+        let (wallet, mut bc) = test_util::new_chain("mempool_conflict");
+        let utxo_set = UtxoSet::new(bc.clone());
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        let in_pool = spend(&wallet, 1, transaction::SEQUENCE_FINAL, &utxo_set);
+        mempool.add(in_pool.clone(), &utxo_set).unwrap();
+
+        let confirmed = spend(&wallet, 2, transaction::SEQUENCE_FINAL, &utxo_set);
+        test_util::mine(&mut bc, vec![confirmed], &wallet);
+        let utxo_set = UtxoSet::new(bc.clone());
+        let removed = mempool.sync_with_chain(&utxo_set);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].get_id(), in_pool.get_id());
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.get_tip(), bc.get_tip_hash());
+    }
+
+    #[test]
+    fn transactions_of_a_disconnected_block_return_to_the_pool() {
+        let (wallet, mut bc) = test_util::new_chain("mempool_readmit");
+        let genesis_hash = bc.get_tip_hash();
+        let tx = spend(
+            &wallet,
+            1,
+            transaction::SEQUENCE_FINAL,
+            &UtxoSet::new(bc.clone()),
+        );
+        test_util::mine(&mut bc, vec![tx.clone()], &wallet);
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+
+        // a longer chain without tx
+        let mut prev_hash = genesis_hash;
+        for height in 1..=2 {
+            test_util::wait_for_block_time();
+            let coinbase = transaction::new_coinbase_tx(
+                test_util::address(&wallet),
+                format!("Height {}", height),
+                0,
+            );
+            let block = Block::new_block(vec![coinbase], prev_hash, height);
+            prev_hash = block.get_hash();
+            bc.add_block(block);
+        }
+        let utxo_set = UtxoSet::new(bc.clone());
+        utxo_set.update_to_tip();
+        assert!(mempool.sync_with_chain(&utxo_set).is_empty());
+        assert!(mempool.contains(&tx.get_id()));
+        assert_eq!(mempool.get_fee(&tx.get_id()), Some(1));
+    }
+
+    // a parent paying 1 to a new wallet, and its child paying 4 of it
+    fn parent_and_child(
+        wallet: &Wallet,
+        bc: &Blockchain,
+        node_id: &str,
+    ) -> (Transaction, Transaction) {
+        let payer = Wallet::new_wallet();
+        let parent = transaction::new_utxo_transaction(
+            wallet,
+            test_util::address(&payer),
+            5,
+            1,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &UtxoSet::new(bc.clone()),
+        );
+        let child = test_util::spend_child(
+            bc,
+            node_id,
+            &parent,
+            &payer,
+            1,
+            4,
+            transaction::SEQUENCE_FINAL,
+        );
+        (parent, child)
+    }
+
+    #[test]
+    fn a_full_pool_evicts_the_package_paying_the_lowest_rate() {
+        let (wallet, mut bc) = test_util::new_chain("mempool_trim");
+        let (medium_payer, low_payer) = (Wallet::new_wallet(), Wallet::new_wallet());
+        test_util::mine(&mut bc, vec![], &medium_payer);
+        test_util::mine(&mut bc, vec![], &low_payer);
+        let utxo_set = UtxoSet::new(bc.clone());
+        let (parent, child) = parent_and_child(&wallet, &bc, "mempool_trim");
+        let medium = spend(&medium_payer, 2, transaction::SEQUENCE_FINAL, &utxo_set);
+        let low = spend(&low_payer, 1, transaction::SEQUENCE_FINAL, &utxo_set);
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        for tx in [&parent, &child, &medium, &low] {
+            mempool.add(tx.clone(), &utxo_set).unwrap();
+        }
+        let parent_entry = &mempool.entries[&hex::encode(parent.get_id())];
+        assert_eq!(parent_entry.descendant_fee, 5);
+
+        mempool.limits.max_size = mempool.get_total_size() - 1;
+        mempool.trim();
+        assert!(!mempool.contains(&low.get_id()));
+        let low_rate = fee_rate(1, bincode::serialize(&low).unwrap().len());
+        assert_eq!(mempool.get_min_fee_rate(), low_rate + INCREMENTAL_RELAY_FEE);
+
+        // the child pays for its parent
+        mempool.limits.max_size = mempool.get_total_size() - 1;
+        mempool.trim();
+        assert!(!mempool.contains(&medium.get_id()));
+        assert!(mempool.contains(&parent.get_id()));
+        assert_eq!(mempool.by_descendant_rate.len(), 2);
+
+        mempool.remove_with_descendants(&hex::encode(child.get_id()));
+        let parent_entry = &mempool.entries[&hex::encode(parent.get_id())];
+        assert_eq!(parent_entry.descendant_fee, 1);
+        assert_eq!(parent_entry.descendant_size, parent_entry.size);
+        assert_eq!(mempool.by_descendant_rate.len(), 1);
+    }
+
+    #[test]
+    fn expired_transactions_leave_with_their_descendants() {
+        let (wallet, bc) = test_util::new_chain("mempool_expiry");
+        let utxo_set = UtxoSet::new(bc.clone());
+        let (parent, child) = parent_and_child(&wallet, &bc, "mempool_expiry");
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        // the parent entered the pool long ago
+        mempool.insert(parent, &utxo_set, 0, true).unwrap();
+        mempool.add(child, &utxo_set).unwrap();
+
+        assert_eq!(mempool.expire().len(), 2);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.get_total_size(), 0);
+        assert!(mempool.by_descendant_rate.is_empty());
+    }
+
+    #[test]
+    fn chains_longer_than_the_limits_are_refused() {
+        let (wallet, bc) = test_util::new_chain("mempool_chain_limits");
+        let utxo_set = UtxoSet::new(bc.clone());
+        let (parent, child) = parent_and_child(&wallet, &bc, "mempool_chain_limits");
+        for limits in [
+            MempoolLimits {
+                max_ancestors: 1,
+                ..MempoolLimits::default()
+            },
+            MempoolLimits {
+                max_descendants: 1,
+                ..MempoolLimits::default()
+            },
+        ] {
+            let mut mempool = Mempool::new(bc.get_tip_hash(), limits);
+            mempool.add(parent.clone(), &utxo_set).unwrap();
+            assert!(matches!(
+                mempool.add(child.clone(), &utxo_set),
+                Err(Rejection::TooLongChain(_))
+            ));
+        }
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        mempool.add(parent, &utxo_set).unwrap();
+        assert!(mempool.add(child, &utxo_set).is_ok());
+    }
+
+    fn replaceable() -> u32 {
+        transaction::input_sequence(true, 0, None)
+    }
+
+    #[test]
+    fn replacement_pays_the_incremental_relay_fee() {
+        let (wallet, bc) = test_util::new_chain("mempool_replacement");
+        let utxo_set = UtxoSet::new(bc.clone());
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        let original = spend(&wallet, 1, replaceable(), &utxo_set);
+        assert!(mempool.add(original.clone(), &utxo_set).unwrap().is_empty());
+
+        let size = bincode::serialize(&original).unwrap().len();
+        assert_eq!(incremental_fee(size), 1);
+        let same_fee = spend(&wallet, 1, replaceable(), &utxo_set);
+        assert!(matches!(
+            mempool.add(same_fee, &utxo_set),
+            Err(Rejection::Replacement(_))
+        ));
+        assert!(mempool.contains(&original.get_id()));
+
+        let bumped = spend(&wallet, 2, replaceable(), &utxo_set);
+        let replaced = mempool.add(bumped.clone(), &utxo_set).unwrap();
+        assert_eq!(replaced.len(), 1);
+        assert_eq!(replaced[0].get_id(), original.get_id());
+        assert!(!mempool.contains(&original.get_id()));
+        assert!(mempool.contains(&bumped.get_id()));
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn transactions_not_signaling_replacement_stay() {
+        let (wallet, bc) = test_util::new_chain("mempool_no_replacement");
+        let utxo_set = UtxoSet::new(bc.clone());
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        let original = spend(&wallet, 1, transaction::SEQUENCE_FINAL, &utxo_set);
+        mempool.add(original.clone(), &utxo_set).unwrap();
+
+        let conflict = spend(&wallet, 5, replaceable(), &utxo_set);
+        assert!(matches!(
+            mempool.add(conflict, &utxo_set),
+            Err(Rejection::Conflict(_))
+        ));
+        assert!(mempool.contains(&original.get_id()));
+    }
+
+    #[test]
+    fn the_same_transaction_is_added_once() {
+        let (wallet, bc) = test_util::new_chain("mempool_replace_parent");
+        let utxo_set = UtxoSet::new(bc.clone());
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        let original = spend(&wallet, 1, replaceable(), &utxo_set);
+        mempool.add(original.clone(), &utxo_set).unwrap();
+        assert!(matches!(
+            mempool.add(original, &utxo_set),
+            Err(Rejection::AlreadyKnown)
+        ));
+    }
+
+    #[test]
+    fn an_evicted_replacement_gives_the_replaced_transaction_its_place_back() {
+        let (wallet, mut bc) = test_util::new_chain("mempool_replacement_evicted");
+        let rich_payer = Wallet::new_wallet();
+        test_util::mine(&mut bc, vec![], &rich_payer);
+        let utxo_set = UtxoSet::new(bc.clone());
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        let original = spend(&wallet, 1, replaceable(), &utxo_set);
+        let rich = spend(&rich_payer, 6, transaction::SEQUENCE_FINAL, &utxo_set);
+        mempool.add(original.clone(), &utxo_set).unwrap();
+        mempool.add(rich.clone(), &utxo_set).unwrap();
+
+        let bumped = spend(&wallet, 2, replaceable(), &utxo_set);
+        let size = bincode::serialize(&bumped).unwrap().len();
+        mempool.limits.max_size = bincode::serialize(&rich).unwrap().len() + size - 1;
+        assert!(matches!(
+            mempool.add(bumped.clone(), &utxo_set),
+            Err(Rejection::PoolFull)
+        ));
+        assert!(mempool.contains(&original.get_id()));
+        assert!(!mempool.contains(&bumped.get_id()));
+        assert_eq!(mempool.get_fee(&original.get_id()), Some(1));
+        assert_eq!(mempool.by_descendant_rate.len(), 2);
+    }
+
+    #[test]
+    fn saved_transactions_still_valid_are_loaded() {
+        let (wallet, mut bc) = test_util::new_chain("mempool_saved");
+        let (old_payer, confirmed_payer) = (Wallet::new_wallet(), Wallet::new_wallet());
+        test_util::mine(&mut bc, vec![], &old_payer);
+        test_util::mine(&mut bc, vec![], &confirmed_payer);
+        let utxo_set = UtxoSet::new(bc.clone());
+        let (parent, child) = parent_and_child(&wallet, &bc, "mempool_saved");
+        let old = spend(&old_payer, 1, transaction::SEQUENCE_FINAL, &utxo_set);
+        let confirmed = spend(&confirmed_payer, 1, transaction::SEQUENCE_FINAL, &utxo_set);
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        mempool.insert(old, &utxo_set, 0, true).unwrap();
+        for tx in [&parent, &child, &confirmed] {
+            mempool.add(tx.clone(), &utxo_set).unwrap();
+        }
+        mempool.save_to_file("mempool_saved").unwrap();
+
+        // a block confirms one of them while the node is down
+        let spender = spend(&confirmed_payer, 2, transaction::SEQUENCE_FINAL, &utxo_set);
+        test_util::mine(&mut bc, vec![spender], &wallet);
+        let utxo_set = UtxoSet::new(bc.clone());
+        let mut loaded = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        assert_eq!(loaded.load_from_file("mempool_saved", &utxo_set), 2);
+        assert!(loaded.contains(&parent.get_id()));
+        assert!(loaded.contains(&child.get_id()));
+        let time = |mempool: &Mempool| mempool.entries[&hex::encode(parent.get_id())].time;
+        assert_eq!(time(&loaded), time(&mempool));
+        assert_eq!(
+            Mempool::new(bc.get_tip_hash(), MempoolLimits::default())
+                .load_from_file("mempool_missing", &utxo_set),
+            0
+        );
+    }
+}
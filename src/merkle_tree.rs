@@ -48,7 +48,11 @@ pub fn new_merkle_tree(mut data: Vec<Vec<u8>>) -> MerkleTree {
         nodes.push(node);
     }
 
-    for _ in 0..data.len() / 2 {
+    // every level with an odd number of nodes duplicates its last node
+    while nodes.len() > 1 {
+        if !nodes.len().is_multiple_of(2) {
+            nodes.push(nodes[nodes.len() - 1].clone());
+        }
         let mut new_level: Vec<MerkleNode> = vec![];
         for j in (0..nodes.len()).step_by(2) {
             let node = new_merkle_node(
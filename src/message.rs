@@ -0,0 +1,140 @@
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind, Read, Write};
+
+/* Message header
+Magic     Command (zero padded)       Length    Checksum
+62637273  76657273696f6e0000000000    64000000  358d4932
+
+The length is the payload size in little endian, the checksum is
+the first 4 bytes of the double SHA-256 of the payload.
+*/
+
+const NETWORK_MAGIC: [u8; 4] = [0x62, 0x63, 0x72, 0x73]; // identifies our network
+const COMMAND_LENGTH: usize = 12;
+const CHECKSUM_LENGTH: usize = 4;
+const HEADER_LENGTH: usize = NETWORK_MAGIC.len() + COMMAND_LENGTH + 4 + CHECKSUM_LENGTH;
+const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+pub const MAX_FRAME_SIZE: usize = HEADER_LENGTH + MAX_MESSAGE_SIZE;
+
+// writes one framed message to the stream
+pub fn write_message(stream: &mut impl Write, command: &str, payload: &[u8]) -> Result<(), Error> {
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err(Error::new(ErrorKind::InvalidInput, "message is too large"));
+    }
+    let mut data = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    data.extend(NETWORK_MAGIC);
+    data.extend(command_to_bytes(command));
+    data.extend((payload.len() as u32).to_le_bytes());
+    data.extend(checksum(payload));
+    data.extend(payload);
+    stream.write_all(&data)?;
+    stream.flush()
+}
+
+/*reads one framed message from the stream and returns its command and payload,
+or None when the peer closed the connection between two messages */
+pub fn read_message(stream: &mut impl Read) -> Result<Option<(String, Vec<u8>)>, Error> {
+    let mut header = [0u8; HEADER_LENGTH];
+    if let Err(e) = stream.read_exact(&mut header[..1]) {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(e);
+    }
+    stream.read_exact(&mut header[1..])?;
+
+    let (magic, rest) = header.split_at(NETWORK_MAGIC.len());
+    let (command, rest) = rest.split_at(COMMAND_LENGTH);
+    let (length, expected_checksum) = rest.split_at(4);
+    if magic != NETWORK_MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "wrong network magic"));
+    }
+    let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "message is too large"));
+    }
+
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload)?;
+    if checksum(&payload) != expected_checksum {
+        return Err(Error::new(ErrorKind::InvalidData, "wrong payload checksum"));
+    }
+    Ok(Some((bytes_to_command(command), payload)))
+}
+
+fn checksum(payload: &[u8]) -> Vec<u8> {
+    let hash = Sha256::digest(Sha256::digest(payload));
+    hash[..CHECKSUM_LENGTH].to_vec()
+}
+
+fn command_to_bytes(command: &str) -> Vec<u8> {
+    let mut bytes = vec![0; COMMAND_LENGTH];
+    for (i, c) in command.chars().enumerate() {
+        bytes[i] = c as u8;
+    }
+    bytes
+}
+
+fn bytes_to_command(bytes: &[u8]) -> String {
+    let mut command = String::new();
+    for b in bytes {
+        if *b != 0 {
+            command.push(*b as char);
+        }
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(command: &str, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![];
+        write_message(&mut data, command, payload).unwrap();
+        data
+    }
+
+    #[test]
+    fn messages_round_trip_one_after_the_other() {
+        let data = [frame("version", b"payload"), frame("verack", b"")].concat();
+        assert_eq!(&data[..HEADER_LENGTH], {
+            let mut header = NETWORK_MAGIC.to_vec();
+            header.extend(b"version\0\0\0\0\0");
+            header.extend(7u32.to_le_bytes());
+            header.extend(checksum(b"payload"));
+            header
+        });
+        let mut stream = data.as_slice();
+        assert_eq!(
+            read_message(&mut stream).unwrap(),
+            Some(("version".to_string(), b"payload".to_vec()))
+        );
+        assert_eq!(
+            read_message(&mut stream).unwrap(),
+            Some(("verack".to_string(), vec![]))
+        );
+        assert_eq!(read_message(&mut stream).unwrap(), None);
+    }
+
+    #[test]
+    fn damaged_frames_are_rejected() {
+        let data = frame("tx", b"payload");
+        let mut wrong_magic = data.clone();
+        wrong_magic[0] ^= 1;
+        let mut wrong_checksum = data.clone();
+        *wrong_checksum.last_mut().unwrap() ^= 1;
+        let mut too_large = data.clone();
+        too_large[16..20].copy_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+        for damaged in [wrong_magic, wrong_checksum, too_large] {
+            let error = read_message(&mut damaged.as_slice()).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+        let cut = &data[..data.len() - 1];
+        let error = read_message(&mut &cut[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+
+        let payload = vec![0; MAX_MESSAGE_SIZE + 1];
+        assert!(write_message(&mut vec![], "block", &payload).is_err());
+    }
+}
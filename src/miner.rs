@@ -0,0 +1,145 @@
+use crate::mempool::Mempool;
+use crate::Transaction;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+// the mempool transactions chosen for the next block
+pub struct BlockTemplate {
+    pub transactions: Vec<Transaction>, // parents before children
+    pub fees: i64,
+    pub size: usize,
+}
+
+/*Fills a block with the transactions paying the most per byte.
+Transactions are chosen together with their unconfirmed ancestors, as
+a package rated by its total fee over its total size, so a child paying
+a high fee pulls in a parent paying little (child pays for parent).
+Packages that do not fit in max_size anymore are skipped. */
+pub fn new_block_template(mempool: &Mempool, max_size: usize) -> BlockTemplate {
+    let mut template = BlockTemplate {
+        transactions: vec![],
+        fees: 0,
+        size: 0,
+    };
+    let mut included: HashSet<Vec<u8>> = HashSet::new();
+    let mut skipped: HashSet<Vec<u8>> = HashSet::new();
+    loop {
+        let mut best: Option<(Vec<Vec<u8>>, i64, usize)> = None;
+        for tx in mempool.transactions() {
+            let id = tx.get_id();
+            if included.contains(&id) || skipped.contains(&id) {
+                continue;
+            }
+            let mut package: Vec<Vec<u8>> = mempool
+                .get_ancestors(&id)
+                .into_iter()
+                .filter(|ancestor| !included.contains(ancestor))
+                .collect();
+            package.push(id);
+            let fee: i64 = package
+                .iter()
+                .map(|id| mempool.get_fee(id).unwrap_or_default())
+                .sum();
+            let size: usize = package
+                .iter()
+                .map(|id| mempool.get_size(id).unwrap_or_default())
+                .sum();
+            let better = match &best {
+                Some((_, best_fee, best_size)) => {
+                    compare_fee_rates(fee, size, *best_fee, *best_size) == Ordering::Greater
+                }
+                None => true,
+            };
+            if better {
+                best = Some((package, fee, size));
+            }
+        }
+
+        let (package, fee, size) = match best {
+            Some(best) => best,
+            None => break,
+        };
+        if template.size + size > max_size {
+            skipped.insert(package.last().unwrap().clone());
+            continue;
+        }
+        for id in package {
+            if let Some(tx) = mempool.get(&id) {
+                template.transactions.push(tx.clone());
+            }
+            included.insert(id);
+        }
+        template.fees += fee;
+        template.size += size;
+    }
+    template
+}
+
+// compares fee / size of two packages without rounding
+fn compare_fee_rates(fee: i64, size: usize, other_fee: i64, other_size: usize) -> Ordering {
+    (fee as i128 * other_size as i128).cmp(&(other_fee as i128 * size as i128))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use crate::transaction;
+    use crate::MempoolLimits;
+    use crate::UtxoSet;
+    use crate::Wallet;
+
+    fn spend(payer: &Wallet, fee: i64, utxo_set: &UtxoSet) -> Transaction {
+        let to = test_util::address(&Wallet::new_wallet());
+        let sequence = transaction::SEQUENCE_FINAL;
+        transaction::new_utxo_transaction(payer, to, 3, fee, sequence, 0, utxo_set)
+    }
+
+    fn size(tx: &Transaction) -> usize {
+        bincode::serialize(tx).unwrap().len()
+    }
+
+    #[test]
+    fn packages_are_chosen_by_fee_rate() {
+        let (wallet, mut bc) = test_util::new_chain("miner_template");
+        let (medium_payer, payer) = (Wallet::new_wallet(), Wallet::new_wallet());
+        test_util::mine(&mut bc, vec![], &medium_payer);
+        let utxo_set = UtxoSet::new(bc.clone());
+        let parent = transaction::new_utxo_transaction(
+            &wallet,
+            test_util::address(&payer),
+            5,
+            1,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &utxo_set,
+        );
+        let child = test_util::spend_child(
+            &bc,
+            "miner_template",
+            &parent,
+            &payer,
+            1,
+            4,
+            transaction::SEQUENCE_FINAL,
+        );
+        let medium = spend(&medium_payer, 2, &utxo_set);
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        for tx in [&medium, &parent, &child] {
+            mempool.add(tx.clone(), &utxo_set).unwrap();
+        }
+
+        // the child pays for its parent, which comes first
+        let template = new_block_template(&mempool, usize::MAX);
+        let ids: Vec<Vec<u8>> = template.transactions.iter().map(|tx| tx.get_id()).collect();
+        assert_eq!(ids, vec![parent.get_id(), child.get_id(), medium.get_id()]);
+        assert_eq!(template.fees, 7);
+        assert_eq!(template.size, size(&parent) + size(&child) + size(&medium));
+
+        // a package that does not fit is skipped for one that does
+        let template = new_block_template(&mempool, size(&medium));
+        assert_eq!(template.transactions.len(), 1);
+        assert_eq!(template.transactions[0].get_id(), medium.get_id());
+        assert_eq!(template.fees, 2);
+    }
+}
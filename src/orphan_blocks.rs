@@ -0,0 +1,146 @@
+use crate::Block;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const MAX_ORPHAN_BLOCKS: usize = 100;
+const ORPHAN_BLOCK_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+struct OrphanBlock {
+    block: Block,
+    received: Instant, // when the block was added to the pool
+}
+
+/*Blocks often arrive before their parent. Instead of adding them to
+the chain, they wait here, keyed by the hash of the missing parent,
+until the parent is connected. */
+pub struct OrphanBlocks {
+    orphans: HashMap<Vec<u8>, Vec<OrphanBlock>>,
+}
+
+impl OrphanBlocks {
+    pub fn new() -> OrphanBlocks {
+        OrphanBlocks {
+            orphans: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.values().map(|children| children.len()).sum()
+    }
+
+    pub fn contains(&self, block_hash: &[u8]) -> bool {
+        self.orphans
+            .values()
+            .flatten()
+            .any(|orphan| orphan.block.get_hash() == block_hash)
+    }
+
+    // adds a block to the pool, making room by dropping expired and then the oldest orphans
+    pub fn add(&mut self, block: Block) {
+        if self.contains(&block.get_hash()) {
+            return;
+        }
+        self.expire();
+        while self.len() >= MAX_ORPHAN_BLOCKS {
+            self.evict_oldest();
+        }
+        self.orphans
+            .entry(block.get_prev_block_hash())
+            .or_default()
+            .push(OrphanBlock {
+                block,
+                received: Instant::now(),
+            });
+    }
+
+    // removes and returns the orphans whose parent is block_hash
+    pub fn take_children(&mut self, block_hash: &[u8]) -> Vec<Block> {
+        self.orphans
+            .remove(block_hash)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|orphan| orphan.block)
+            .collect()
+    }
+
+    fn expire(&mut self) {
+        for children in self.orphans.values_mut() {
+            children.retain(|orphan| orphan.received.elapsed() < ORPHAN_BLOCK_EXPIRY);
+        }
+        self.orphans.retain(|_, children| !children.is_empty());
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .orphans
+            .iter()
+            .flat_map(|(parent, children)| {
+                children
+                    .iter()
+                    .map(move |orphan| (parent.clone(), orphan.block.get_hash(), orphan.received))
+            })
+            .min_by_key(|(_, _, received)| *received);
+        if let Some((parent, block_hash, _)) = oldest {
+            if let Some(children) = self.orphans.get_mut(&parent) {
+                children.retain(|orphan| orphan.block.get_hash() != block_hash);
+                if children.is_empty() {
+                    self.orphans.remove(&parent);
+                }
+            }
+        }
+    }
+}
+
+impl Default for OrphanBlocks {
+    fn default() -> Self {
+        OrphanBlocks::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use crate::transaction;
+    use crate::Wallet;
+
+    fn block(prev_hash: Vec<u8>, height: usize) -> Block {
+        let to = test_util::address(&Wallet::new_wallet());
+        let coinbase = transaction::new_coinbase_tx(to, format!("Height {}", height), 0);
+        Block::new_block(vec![coinbase], prev_hash, height)
+    }
+
+    #[test]
+    fn orphans_wait_for_their_parent() {
+        let mut orphan_blocks = OrphanBlocks::new();
+        let parent = block(vec![1; 32], 1);
+        let children = [block(parent.get_hash(), 2), block(parent.get_hash(), 2)];
+        for child in &children {
+            orphan_blocks.add(child.clone());
+        }
+        orphan_blocks.add(children[0].clone());
+        assert_eq!(orphan_blocks.len(), 2);
+        assert!(orphan_blocks.contains(&children[1].get_hash()));
+        assert!(!orphan_blocks.contains(&parent.get_hash()));
+
+        assert!(orphan_blocks
+            .take_children(&children[0].get_hash())
+            .is_empty());
+        let taken = orphan_blocks.take_children(&parent.get_hash());
+        assert_eq!(taken.len(), 2);
+        assert_eq!(orphan_blocks.len(), 0);
+    }
+
+    #[test]
+    fn a_full_pool_drops_the_oldest_orphan() {
+        let mut orphan_blocks = OrphanBlocks::new();
+        let first = block(vec![0; 32], 1);
+        orphan_blocks.add(first.clone());
+        orphan_blocks.orphans.get_mut(&vec![0; 32]).unwrap()[0].received -= Duration::from_secs(1);
+        for i in 1..=MAX_ORPHAN_BLOCKS {
+            orphan_blocks.add(block(vec![i as u8; 32], 1));
+        }
+        assert_eq!(orphan_blocks.len(), MAX_ORPHAN_BLOCKS);
+        assert!(!orphan_blocks.contains(&first.get_hash()));
+    }
+}
@@ -0,0 +1,208 @@
+use crate::Transaction;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+const MAX_ORPHAN_TXS: usize = 100;
+const MAX_ORPHAN_TX_SIZE: usize = 100_000; // larger orphans are dropped, in bytes
+const ORPHAN_TX_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+struct OrphanTx {
+    tx: Transaction,
+    from: String,      // the peer that sent it, often a wallet gone already
+    received: Instant, // when the transaction was added to the pool
+}
+
+/*Transactions spending outputs of transactions the node has not seen
+yet, usually because a child was relayed before its parent. They wait
+here, indexed by the transactions they spend from, until a parent enters
+the mempool or a block. */
+pub struct OrphanTxs {
+    orphans: HashMap<String, OrphanTx>, // by hex transaction id
+    by_parent: HashMap<String, HashSet<String>>, // hex id of a spent transaction -> ids of the orphans spending it
+}
+
+impl OrphanTxs {
+    pub fn new() -> OrphanTxs {
+        OrphanTxs {
+            orphans: HashMap::new(),
+            by_parent: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn contains(&self, id: &[u8]) -> bool {
+        self.orphans.contains_key(&hex::encode(id))
+    }
+
+    /*adds a transaction to the pool, making room by dropping expired and
+    then the oldest orphans. Returns false if it is too large to keep. */
+    pub fn add(&mut self, tx: Transaction, from: &str) -> bool {
+        let id = hex::encode(tx.get_id());
+        if self.orphans.contains_key(&id) {
+            return true;
+        }
+        if bincode::serialize(&tx).unwrap().len() > MAX_ORPHAN_TX_SIZE {
+            return false;
+        }
+        self.expire();
+        while self.orphans.len() >= MAX_ORPHAN_TXS {
+            self.evict_oldest();
+        }
+        for vin in tx.get_vin() {
+            self.by_parent
+                .entry(hex::encode(vin.get_txid()))
+                .or_default()
+                .insert(id.clone());
+        }
+        self.orphans.insert(
+            id,
+            OrphanTx {
+                tx,
+                from: from.to_string(),
+                received: Instant::now(),
+            },
+        );
+        true
+    }
+
+    // removes and returns the orphans spending outputs of parent_id, with the peers that sent them
+    pub fn take_children(&mut self, parent_id: &[u8]) -> Vec<(Transaction, String)> {
+        let children = self
+            .by_parent
+            .get(&hex::encode(parent_id))
+            .cloned()
+            .unwrap_or_default();
+        children
+            .iter()
+            .filter_map(|id| self.remove(id))
+            .map(|orphan| (orphan.tx, orphan.from))
+            .collect()
+    }
+
+    // removes and returns all orphans, oldest first
+    pub fn take_all(&mut self) -> Vec<(Transaction, String)> {
+        let mut orphans: Vec<OrphanTx> = self.orphans.drain().map(|(_, orphan)| orphan).collect();
+        self.by_parent.clear();
+        orphans.sort_by_key(|orphan| orphan.received);
+        orphans
+            .into_iter()
+            .map(|orphan| (orphan.tx, orphan.from))
+            .collect()
+    }
+
+    fn remove(&mut self, id: &str) -> Option<OrphanTx> {
+        let orphan = self.orphans.remove(id)?;
+        for vin in orphan.tx.get_vin() {
+            let parent_id = hex::encode(vin.get_txid());
+            if let Some(children) = self.by_parent.get_mut(&parent_id) {
+                children.remove(id);
+                if children.is_empty() {
+                    self.by_parent.remove(&parent_id);
+                }
+            }
+        }
+        Some(orphan)
+    }
+
+    fn expire(&mut self) {
+        let expired: Vec<String> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.received.elapsed() >= ORPHAN_TX_EXPIRY)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            self.remove(&id);
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .orphans
+            .iter()
+            .min_by_key(|(_, orphan)| orphan.received)
+            .map(|(id, _)| id.clone());
+        if let Some(id) = oldest {
+            self.remove(&id);
+        }
+    }
+}
+
+impl Default for OrphanTxs {
+    fn default() -> Self {
+        OrphanTxs::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use crate::transaction;
+    use crate::UtxoSet;
+    use crate::Wallet;
+
+    fn coinbase(data: String) -> Transaction {
+        transaction::new_coinbase_tx(test_util::address(&Wallet::new_wallet()), data, 0)
+    }
+
+    #[test]
+    fn an_orphan_waits_for_its_parent() {
+        let (wallet, bc) = test_util::new_chain("orphan_txs_parent");
+        let payer = Wallet::new_wallet();
+        let parent = transaction::new_utxo_transaction(
+            &wallet,
+            test_util::address(&payer),
+            5,
+            1,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &UtxoSet::new(bc.clone()),
+        );
+        let child = test_util::spend_child(
+            &bc,
+            "orphan_txs_parent",
+            &parent,
+            &payer,
+            1,
+            1,
+            transaction::SEQUENCE_FINAL,
+        );
+        let mut orphan_txs = OrphanTxs::new();
+        assert!(orphan_txs.add(child.clone(), "peer"));
+        assert!(orphan_txs.contains(&child.get_id()));
+        assert!(orphan_txs.take_children(&child.get_id()).is_empty());
+
+        let children = orphan_txs.take_children(&parent.get_id());
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].0.get_id(), child.get_id());
+        assert_eq!(children[0].1, "peer");
+        assert_eq!(orphan_txs.len(), 0);
+        assert!(orphan_txs.by_parent.is_empty());
+    }
+
+    #[test]
+    fn a_full_pool_drops_the_oldest_orphan() {
+        let mut orphan_txs = OrphanTxs::new();
+        let first = coinbase("orphan 0".to_string());
+        orphan_txs.add(first.clone(), "peer");
+        orphan_txs
+            .orphans
+            .get_mut(&hex::encode(first.get_id()))
+            .unwrap()
+            .received -= Duration::from_secs(1);
+        for i in 1..=MAX_ORPHAN_TXS {
+            orphan_txs.add(coinbase(format!("orphan {}", i)), "peer");
+        }
+        assert_eq!(orphan_txs.len(), MAX_ORPHAN_TXS);
+        assert!(!orphan_txs.contains(&first.get_id()));
+
+        let large = coinbase("x".repeat(MAX_ORPHAN_TX_SIZE));
+        assert!(!orphan_txs.add(large.clone(), "peer"));
+        assert!(!orphan_txs.contains(&large.get_id()));
+        assert_eq!(orphan_txs.take_all().len(), MAX_ORPHAN_TXS);
+    }
+}
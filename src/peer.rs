@@ -0,0 +1,406 @@
+use crate::transport::{self, Sealer, Transport};
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const HANDSHAKE_COMMANDS: [&str; 2] = ["version", "verack"];
+const MAX_SEND_QUEUE: usize = 32 * 1024 * 1024; // bytes queued for a peer that does not read, beyond which it is dropped
+
+// what the reader threads of all sessions report to the dispatcher
+pub enum PeerEvent {
+    Message {
+        peer: String,
+        command: String,
+        payload: Vec<u8>,
+    },
+    Misbehaved {
+        peer: String,
+        reason: String, // why the stream could not be read
+    },
+    Disconnected(String),
+}
+
+/*A long-lived session with another node. Each session has a reader thread,
+which forwards every received message to the dispatcher, and a writer
+thread, so a slow peer never blocks the rest of the node. On an encrypted
+session the reader runs the key exchange first and hands the sending key
+to the writer. */
+pub struct Peer {
+    addr: String,                        // remote address of the socket
+    listen_addr: Option<String>, // address the peer accepts connections on, from its version
+    outbound: bool,              // whether we opened the connection
+    outgoing: Sender<(String, Vec<u8>)>, // messages queued for the writer thread
+    queued: Arc<AtomicUsize>,    // bytes of the queued messages not written yet
+    stream: TcpStream,
+    version: Option<PeerVersion>, // what the peer told about itself in its version message
+    verack_received: bool,
+    pending: Vec<(String, Vec<u8>)>, // messages held back until the handshake completes
+    connected: Instant,
+    ping_nonce: Option<u64>,    // nonce of the ping waiting for its pong
+    ping_sent: Option<Instant>, // when the last ping went out
+    latency: Option<Duration>,  // round trip time of the last answered ping
+    misbehavior: u32, // grows with every invalid message, the peer is banned at the threshold
+    best_known_height: usize, // the height up to which the peer can serve blocks, as far as we know
+}
+
+// the capabilities a peer announced during the handshake
+#[derive(Clone)]
+pub struct PeerVersion {
+    pub version: u32,
+    pub services: u64,
+    pub user_agent: String,
+    pub start_height: usize,
+}
+
+// a snapshot of a session for the operator
+pub struct PeerInfo {
+    pub addr: String,
+    pub listen_addr: Option<String>,
+    pub outbound: bool,
+    pub version: Option<PeerVersion>,
+    pub latency: Option<Duration>,
+}
+
+impl Peer {
+    /*starts the reader and writer threads of a connected stream, encrypted if
+    transport is set. Fails if the stream is already closed or cannot be shared. */
+    pub fn spawn(
+        stream: TcpStream,
+        outbound: bool,
+        transport: Option<Arc<Transport>>,
+        events: Sender<PeerEvent>,
+    ) -> io::Result<Peer> {
+        let addr = stream.peer_addr()?.to_string();
+        let mut reader = stream.try_clone()?;
+        let mut writer = stream.try_clone()?;
+        let (outgoing, queue) = mpsc::channel::<(String, Vec<u8>)>();
+        let (sealers, sealer) = mpsc::channel::<Sealer>();
+        let encrypted = transport.is_some();
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let reader_addr = addr.clone();
+        thread::spawn(move || {
+            let result = read_messages(
+                &mut reader,
+                &reader_addr,
+                outbound,
+                transport,
+                sealers,
+                &events,
+            );
+            if let Err(e) = result {
+                eprintln!("Dropping connection to {}: {}", reader_addr, e);
+                // a broken frame is the peer's fault, a broken socket is not
+                if e.kind() == ErrorKind::InvalidData {
+                    let _ = events.send(PeerEvent::Misbehaved {
+                        peer: reader_addr.clone(),
+                        reason: e.to_string(),
+                    });
+                }
+            }
+            let _ = reader.shutdown(Shutdown::Both);
+            let _ = events.send(PeerEvent::Disconnected(reader_addr));
+        });
+
+        let writer_addr = addr.clone();
+        let written = queued.clone();
+        thread::spawn(move || {
+            // queued messages wait until the key exchange is done
+            let mut sealer = if encrypted {
+                match sealer.recv() {
+                    Ok(sealer) => Some(sealer),
+                    Err(_) => return,
+                }
+            } else {
+                None
+            };
+            for (command, payload) in queue {
+                let result =
+                    transport::write_message(&mut writer, sealer.as_mut(), &command, &payload);
+                written.fetch_sub(payload.len(), Ordering::SeqCst);
+                if let Err(e) = result {
+                    eprintln!("Failed to send {} to {}: {}", command, writer_addr, e);
+                    let _ = writer.shutdown(Shutdown::Both);
+                    break;
+                }
+            }
+        });
+
+        Ok(Peer {
+            addr,
+            listen_addr: None,
+            outbound,
+            outgoing,
+            queued,
+            stream,
+            version: None,
+            verack_received: false,
+            pending: Vec::new(),
+            connected: Instant::now(),
+            ping_nonce: None,
+            ping_sent: None,
+            latency: None,
+            misbehavior: 0,
+            best_known_height: 0,
+        })
+    }
+
+    /*queues a message for the writer thread. Until both sides have
+    exchanged version and verack, only handshake messages go out. */
+    pub fn send(&mut self, command: &str, payload: Vec<u8>) {
+        if self.is_handshake_complete() || HANDSHAKE_COMMANDS.contains(&command) {
+            self.enqueue(command.to_string(), payload);
+        } else {
+            self.pending.push((command.to_string(), payload));
+        }
+    }
+
+    // the number of bytes queued for the peer and not written yet
+    pub fn get_send_queue_size(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    // closes the socket, which stops both threads
+    pub fn disconnect(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    // whether addr names this peer, either by its socket or by its listening address
+    pub fn is_reachable_at(&self, addr: &str) -> bool {
+        self.addr == addr || self.listen_addr.as_deref() == Some(addr)
+    }
+
+    pub fn get_addr(&self) -> String {
+        self.addr.clone()
+    }
+
+    pub fn get_ip(&self) -> Option<IpAddr> {
+        self.addr.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+    }
+
+    pub fn get_listen_addr(&self) -> Option<String> {
+        self.listen_addr.clone()
+    }
+
+    pub fn set_listen_addr(&mut self, addr: String) {
+        self.listen_addr = Some(addr);
+    }
+
+    pub fn is_outbound(&self) -> bool {
+        self.outbound
+    }
+
+    pub fn get_version(&self) -> Option<PeerVersion> {
+        self.version.clone()
+    }
+
+    pub fn set_version(&mut self, version: PeerVersion) {
+        self.best_known_height = self.best_known_height.max(version.start_height);
+        self.version = Some(version);
+        self.flush_pending();
+    }
+
+    pub fn get_best_known_height(&self) -> usize {
+        self.best_known_height
+    }
+
+    // the peer showed it has the blocks up to height
+    pub fn update_best_known_height(&mut self, height: usize) {
+        self.best_known_height = self.best_known_height.max(height);
+    }
+
+    // the peer showed it does not have the block at height
+    pub fn limit_best_known_height(&mut self, height: usize) {
+        self.best_known_height = self.best_known_height.min(height.saturating_sub(1));
+    }
+
+    pub fn set_verack_received(&mut self) {
+        self.verack_received = true;
+        self.flush_pending();
+    }
+
+    pub fn is_handshake_complete(&self) -> bool {
+        self.version.is_some() && self.verack_received
+    }
+
+    pub fn get_info(&self) -> PeerInfo {
+        PeerInfo {
+            addr: self.addr.clone(),
+            listen_addr: self.listen_addr.clone(),
+            outbound: self.outbound,
+            version: self.version.clone(),
+            latency: self.latency,
+        }
+    }
+
+    pub fn get_connected_time(&self) -> Duration {
+        self.connected.elapsed()
+    }
+
+    // whether the last ping was answered and is at least interval old
+    pub fn is_ping_due(&self, interval: Duration) -> bool {
+        self.ping_nonce.is_none() && self.ping_sent.is_none_or(|sent| sent.elapsed() >= interval)
+    }
+
+    // whether a ping has waited longer than timeout for its pong
+    pub fn is_ping_timed_out(&self, timeout: Duration) -> bool {
+        self.ping_nonce.is_some() && self.ping_sent.is_some_and(|sent| sent.elapsed() > timeout)
+    }
+
+    pub fn send_ping(&mut self, nonce: u64) {
+        self.send("ping", nonce.to_le_bytes().to_vec());
+        self.ping_nonce = Some(nonce);
+        self.ping_sent = Some(Instant::now());
+    }
+
+    // records the latency if nonce answers the outstanding ping
+    pub fn pong_received(&mut self, nonce: u64) -> Option<Duration> {
+        if self.ping_nonce != Some(nonce) {
+            return None;
+        }
+        self.ping_nonce = None;
+        self.latency = self.ping_sent.map(|sent| sent.elapsed());
+        self.latency
+    }
+
+    // adds score to the misbehaviour of the peer and returns the new total
+    pub fn add_misbehavior(&mut self, score: u32) -> u32 {
+        self.misbehavior = self.misbehavior.saturating_add(score);
+        self.misbehavior
+    }
+
+    fn flush_pending(&mut self) {
+        if self.is_handshake_complete() {
+            for (command, payload) in std::mem::take(&mut self.pending) {
+                self.enqueue(command, payload);
+            }
+        }
+    }
+
+    // a peer that lets its queue grow past MAX_SEND_QUEUE is not reading, so it is dropped
+    fn enqueue(&mut self, command: String, payload: Vec<u8>) {
+        let size = payload.len();
+        if self.queued.fetch_add(size, Ordering::SeqCst) + size > MAX_SEND_QUEUE {
+            self.queued.fetch_sub(size, Ordering::SeqCst);
+            eprintln!(
+                "Dropping connection to {}: more than {} bytes queued",
+                self.addr, MAX_SEND_QUEUE
+            );
+            self.disconnect();
+            return;
+        }
+        if self.outgoing.send((command, payload)).is_err() {
+            self.queued.fetch_sub(size, Ordering::SeqCst);
+        }
+    }
+}
+
+// forwards the messages of a session to the dispatcher until the peer closes it
+fn read_messages(
+    reader: &mut TcpStream,
+    addr: &str,
+    outbound: bool,
+    transport: Option<Arc<Transport>>,
+    sealers: Sender<Sealer>,
+    events: &Sender<PeerEvent>,
+) -> Result<(), Error> {
+    let mut opener = match transport {
+        Some(transport) => {
+            let session = transport.handshake(reader, outbound)?;
+            println!(
+                "Encrypted session with {}, node key {}",
+                addr,
+                hex::encode(&session.remote_key)
+            );
+            let _ = sealers.send(session.sealer);
+            Some(session.opener)
+        }
+        None => None,
+    };
+    while let Some((command, payload)) = transport::read_message(reader, opener.as_mut())? {
+        let event = PeerEvent::Message {
+            peer: addr.to_string(),
+            command,
+            payload,
+        };
+        if events.send(event).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn a_peer_that_does_not_read_is_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut remote, _) = listener.accept().unwrap();
+        let (events, _received) = mpsc::channel();
+        let mut peer = Peer::spawn(stream, true, None, events).unwrap();
+
+        // the socket buffers fill up first, then the queue
+        let chunk = 1024 * 1024;
+        for _ in 0..MAX_SEND_QUEUE / chunk {
+            peer.send("version", vec![0; chunk]);
+        }
+        assert!(peer.get_send_queue_size() > 0);
+        assert!(peer.get_send_queue_size() <= MAX_SEND_QUEUE);
+        for _ in 0..MAX_SEND_QUEUE / chunk {
+            peer.send("version", vec![0; chunk]);
+        }
+
+        // what was written before the peer was dropped ends with the closed socket
+        remote
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut received = vec![];
+        remote.read_to_end(&mut received).unwrap();
+        assert!(received.len() < 2 * MAX_SEND_QUEUE);
+    }
+
+    #[test]
+    fn only_the_pong_of_the_last_ping_counts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut remote, _) = listener.accept().unwrap();
+        let (events, _received) = mpsc::channel();
+        let mut peer = Peer::spawn(stream, true, None, events).unwrap();
+        // messages other than the handshake wait for it
+        peer.set_version(PeerVersion {
+            version: 0,
+            services: 0,
+            user_agent: String::new(),
+            start_height: 0,
+        });
+        peer.set_verack_received();
+        assert!(peer.is_ping_due(Duration::from_secs(30)));
+
+        peer.send_ping(7);
+        remote
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let (command, payload) = transport::read_message(&mut remote, None).unwrap().unwrap();
+        assert_eq!(command, "ping");
+        assert_eq!(payload, 7u64.to_le_bytes());
+        assert!(!peer.is_ping_due(Duration::ZERO));
+        assert!(peer.is_ping_timed_out(Duration::ZERO));
+        assert!(!peer.is_ping_timed_out(Duration::from_secs(60)));
+
+        assert_eq!(peer.pong_received(8), None);
+        assert!(peer.pong_received(7).is_some());
+        assert!(peer.get_info().latency.is_some());
+        assert!(!peer.is_ping_timed_out(Duration::ZERO));
+        assert!(!peer.is_ping_due(Duration::from_secs(30)));
+        assert!(peer.is_ping_due(Duration::ZERO));
+    }
+}
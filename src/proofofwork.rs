@@ -1,4 +1,5 @@
 use crate::Block;
+use crate::BlockHeader;
 use num_bigint::BigUint;
 use sha2::{Digest, Sha256};
 use std::ops::ShlAssign;
@@ -10,25 +11,30 @@ const TARGET_BITS: i32 = 4;
 const MAX_NONCE: i64 = i64::max_value(); //avoid a possible overflow of nonce
 
 pub struct ProofOfWork {
-    block: Block,
+    header: BlockHeader,
     target: BigUint,
 }
 
 impl ProofOfWork {
     pub fn new_proof_of_work(block: Block) -> ProofOfWork {
+        ProofOfWork::from_header(block.get_header())
+    }
+
+    // proof of work only commits to the header, so it can be checked without the block body
+    pub fn from_header(header: BlockHeader) -> ProofOfWork {
         /*  a target as the upper boundary of a range:
         if a number (a hash) is lower than the boundary, it’s valid, and vice versa. */
         let mut target = BigUint::from(1u32);
         target.shl_assign(256 - TARGET_BITS);
-        ProofOfWork { block, target }
+        ProofOfWork { header, target }
     }
 
     // nonce here is the counter from the Hashcash description
     fn prepare_data(&self, nonce: i64) -> Vec<u8> {
         let mut data = vec![];
-        data.extend(self.block.get_prev_block_hash());
-        data.extend(self.block.hash_transactions());
-        data.extend(self.block.get_timestamp().to_be_bytes());
+        data.extend(self.header.get_prev_block_hash());
+        data.extend(self.header.get_merkle_root());
+        data.extend(self.header.get_timestamp().to_be_bytes());
         data.extend(TARGET_BITS.to_be_bytes());
         data.extend(nonce.to_be_bytes());
         data
@@ -62,13 +68,14 @@ impl ProofOfWork {
         (nonce, hash)
     }
 
+    // the stored hash must match the header data and be below the target
     pub fn validate(&self) -> bool {
-        let data = self.prepare_data(self.block.get_nounce());
+        let data = self.prepare_data(self.header.get_nonce());
         let mut hasher = Sha256::new();
         hasher.update(data);
         let hash = hasher.finalize().to_vec();
         let hash_int = BigUint::from_bytes_be(&hash);
 
-        hash_int.lt(&self.target)
+        hash == self.header.get_hash() && hash_int.lt(&self.target)
     }
 }
@@ -0,0 +1,380 @@
+use crate::script;
+use crate::transaction;
+use crate::wallet;
+use crate::TXOutput;
+use crate::Transaction;
+use crate::UtxoSet;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/*A partially signed transaction: an unsigned transaction with what is
+needed to sign it without the chain, the outputs its inputs spend and
+the redeem scripts of multisig outputs, and the signatures collected so
+far. It goes from the machine that created it to the ones holding the
+keys, maybe offline, then back to be finalized and broadcast.
+
+Every signer signs the inputs it has keys for, copies signed by
+different wallets are combined, and once every input has the
+signatures its locking script needs, finalize builds the unlocking
+scripts. */
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Psbt {
+    tx: Transaction, // without unlocking scripts
+    inputs: Vec<PsbtInput>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct PsbtInput {
+    prev_out: TXOutput,
+    redeem_script: Option<Vec<u8>>, // when prev_out pays to a script hash
+    signatures: BTreeMap<Vec<u8>, Vec<u8>>, // public key -> signature
+}
+
+impl Psbt {
+    /*wraps an unsigned transaction spending unspent outputs, redeem_scripts
+    are the ones known for outputs paying to a script hash */
+    pub fn new(
+        tx: &Transaction,
+        utxo_set: &UtxoSet,
+        redeem_scripts: &[Vec<u8>],
+    ) -> Result<Psbt, String> {
+        let mut inputs = vec![];
+        for (in_id, vin) in tx.get_vin().iter().enumerate() {
+            let prev_out = utxo_set
+                .find_output(&vin.get_txid(), vin.get_vout())
+                .ok_or(format!("input {} spends an unknown or spent output", in_id))?;
+            let script_pubkey = prev_out.get_script_pubkey();
+            let redeem_script = match script::parse_p2sh(&script_pubkey) {
+                Some(script_hash) => {
+                    let redeem_script = redeem_scripts
+                        .iter()
+                        .find(|redeem_script| {
+                            wallet::hash_pub_key(&redeem_script.to_vec()) == script_hash
+                        })
+                        .ok_or(format!("the redeem script of input {} is unknown", in_id))?;
+                    if script::parse_multisig(redeem_script).is_none() {
+                        return Err(format!("input {} is not a multisig output", in_id));
+                    }
+                    Some(redeem_script.clone())
+                }
+                None if script::parse_p2pkh(&script_pubkey).is_some() => None,
+                None => {
+                    return Err(format!(
+                        "input {} is locked with a script that cannot be signed",
+                        in_id
+                    ))
+                }
+            };
+            inputs.push(PsbtInput {
+                prev_out,
+                redeem_script,
+                signatures: BTreeMap::new(),
+            });
+        }
+        Ok(Psbt {
+            tx: tx.trimmed_copy(),
+            inputs,
+        })
+    }
+
+    pub fn get_tx(&self) -> &Transaction {
+        &self.tx
+    }
+
+    // what the inputs spend beyond the outputs
+    pub fn get_fee(&self) -> i64 {
+        let input_value: i64 = self
+            .inputs
+            .iter()
+            .map(|input| input.prev_out.get_value())
+            .sum();
+        input_value
+            - self
+                .tx
+                .get_vout()
+                .iter()
+                .map(|out| out.get_value())
+                .sum::<i64>()
+    }
+
+    // signs the inputs that private_key can unlock, returns how many it signed
+    pub fn sign(&mut self, private_key: &[u8]) -> usize {
+        let pub_key = transaction::public_key(private_key);
+        let mut signed = 0;
+        for (in_id, input) in self.inputs.iter_mut().enumerate() {
+            if input.signatures.contains_key(&pub_key) || !input.can_sign(&pub_key) {
+                continue;
+            }
+            let tx_bytes = self
+                .tx
+                .signature_message(in_id, &input.prev_out.get_script_pubkey());
+            let signature = transaction::ecdsa_sign(&private_key.to_vec(), &tx_bytes);
+            if !input.verify_signature(&self.tx, in_id, &pub_key, &signature) {
+                continue;
+            }
+            input.signatures.insert(pub_key.clone(), signature);
+            signed += 1;
+        }
+        signed
+    }
+
+    /*adds the signatures of other, a copy of the same transaction signed by
+    other keys. Every signature must be valid for its input and key, a copy
+    spending other outputs or carrying a bad signature is rejected whole. */
+    pub fn combine(&mut self, other: &Psbt) -> Result<(), String> {
+        if self.tx.get_id() != other.tx.get_id() {
+            return Err("the transactions differ".to_string());
+        }
+        for (in_id, (input, other_input)) in self.inputs.iter().zip(&other.inputs).enumerate() {
+            if input.prev_out.get_value() != other_input.prev_out.get_value()
+                || input.prev_out.get_script_pubkey() != other_input.prev_out.get_script_pubkey()
+                || input.redeem_script != other_input.redeem_script
+            {
+                return Err(format!("input {} spends a different output", in_id));
+            }
+            for (pub_key, signature) in &other_input.signatures {
+                if !input.can_sign(pub_key) {
+                    return Err(format!(
+                        "input {} is signed by a key that cannot unlock it",
+                        in_id
+                    ));
+                }
+                if !input.verify_signature(&self.tx, in_id, pub_key, signature) {
+                    return Err(format!("input {} has an invalid signature", in_id));
+                }
+            }
+        }
+        for (input, other_input) in self.inputs.iter_mut().zip(&other.inputs) {
+            for (pub_key, signature) in &other_input.signatures {
+                input
+                    .signatures
+                    .entry(pub_key.clone())
+                    .or_insert(signature.clone());
+            }
+        }
+        Ok(())
+    }
+
+    // the signatures the inputs still need, in total
+    pub fn missing_signatures(&self) -> usize {
+        self.inputs
+            .iter()
+            .map(|input| input.required_signatures() - input.unlocking_signatures().len())
+            .sum()
+    }
+
+    /*the signed transaction, once every input has the signatures it needs.
+    The unlocking scripts are checked before it is returned. */
+    pub fn finalize(&self) -> Result<Transaction, String> {
+        let missing = self.missing_signatures();
+        if missing > 0 {
+            return Err(format!("{} more signatures are needed", missing));
+        }
+        let mut tx = self.tx.clone();
+        for (in_id, input) in self.inputs.iter().enumerate() {
+            let signatures = input.unlocking_signatures();
+            let script_sig = match &input.redeem_script {
+                Some(redeem_script) => script::p2sh_unlock(&signatures, redeem_script),
+                None => {
+                    let (pub_key, signature) = input.signatures.iter().next().unwrap();
+                    script::p2pkh_unlock(signature, pub_key)
+                }
+            };
+            tx.set_script_sig(in_id, script_sig);
+        }
+        let prev_outs: Vec<TXOutput> = self
+            .inputs
+            .iter()
+            .map(|input| input.prev_out.clone())
+            .collect();
+        tx.verify_scripts(&prev_outs)?;
+        Ok(tx)
+    }
+
+    // hex encoded, to be passed around as a file
+    pub fn to_hex(&self) -> String {
+        hex::encode(bincode::serialize(self).unwrap())
+    }
+
+    pub fn from_hex(data: &str) -> Result<Psbt, String> {
+        let bytes = hex::decode(data.trim()).map_err(|e| e.to_string())?;
+        let psbt: Psbt = bincode::deserialize(&bytes).map_err(|e| e.to_string())?;
+        if psbt.inputs.len() != psbt.tx.get_vin().len() {
+            return Err("an output to spend per input is needed".to_string());
+        }
+        Ok(psbt)
+    }
+}
+
+impl PsbtInput {
+    // whether pub_key is one of the keys that may unlock the input
+    fn can_sign(&self, pub_key: &[u8]) -> bool {
+        match &self.redeem_script {
+            Some(redeem_script) => script::parse_multisig(redeem_script)
+                .is_some_and(|(_, pub_keys)| pub_keys.iter().any(|key| key == pub_key)),
+            None => script::parse_p2pkh(&self.prev_out.get_script_pubkey()).is_some_and(
+                |pub_key_hash| wallet::hash_pub_key(&pub_key.to_vec()) == pub_key_hash,
+            ),
+        }
+    }
+
+    // whether signature signs input in_id of tx with pub_key
+    fn verify_signature(
+        &self,
+        tx: &Transaction,
+        in_id: usize,
+        pub_key: &[u8],
+        signature: &[u8],
+    ) -> bool {
+        let tx_bytes = tx.signature_message(in_id, &self.prev_out.get_script_pubkey());
+        transaction::ecdsa_sign_verify(&pub_key.to_vec(), &tx_bytes, &signature.to_vec())
+    }
+
+    fn required_signatures(&self) -> usize {
+        match &self.redeem_script {
+            Some(redeem_script) => {
+                script::parse_multisig(redeem_script).map_or(0, |(required, _)| required)
+            }
+            None => 1,
+        }
+    }
+
+    // the signatures of the unlocking script, for a multisig output the first required ones in key order
+    fn unlocking_signatures(&self) -> Vec<Vec<u8>> {
+        match self
+            .redeem_script
+            .as_deref()
+            .and_then(script::parse_multisig)
+        {
+            Some((required, pub_keys)) => pub_keys
+                .iter()
+                .filter_map(|pub_key| self.signatures.get(pub_key).cloned())
+                .take(required)
+                .collect(),
+            None => self.signatures.values().take(1).cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mempool::Mempool;
+    use crate::test_util;
+    use crate::MempoolLimits;
+    use crate::Wallet;
+
+    // a chain of node_id with an output paying 5 to a 2-of-3 multisig of the returned wallets
+    fn fund_multisig(node_id: &str) -> (Vec<Wallet>, Vec<u8>, UtxoSet) {
+        let (wallet, mut bc) = test_util::new_chain(node_id);
+        let signers: Vec<Wallet> = (0..3).map(|_| Wallet::new_wallet()).collect();
+        let pub_keys: Vec<Vec<u8>> = signers.iter().map(|w| w.public_key.clone()).collect();
+        let redeem_script = script::multisig(2, &pub_keys).unwrap();
+        let address = wallet::calc_script_address(&wallet::hash_pub_key(&redeem_script));
+        let funding = transaction::new_utxo_transaction(
+            &wallet,
+            address,
+            5,
+            1,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &UtxoSet::new(bc.clone()),
+        );
+        test_util::mine(&mut bc, vec![funding], &wallet);
+        (signers, redeem_script, UtxoSet::new(bc))
+    }
+
+    fn spend_multisig(redeem_script: &[u8], utxo_set: &UtxoSet) -> Psbt {
+        let address = wallet::calc_script_address(&wallet::hash_pub_key(&redeem_script.to_vec()));
+        let tx = transaction::new_unsigned_transaction(
+            &wallet::locking_script(&address),
+            test_util::address(&Wallet::new_wallet()),
+            3,
+            1,
+            transaction::SEQUENCE_FINAL,
+            0,
+            utxo_set,
+        )
+        .unwrap();
+        Psbt::new(&tx, utxo_set, &[redeem_script.to_vec()]).unwrap()
+    }
+
+    #[test]
+    fn a_p2pkh_input_is_signed_by_its_key_only() {
+        let (wallet, bc) = test_util::new_chain("psbt_p2pkh");
+        let utxo_set = UtxoSet::new(bc);
+        let script_pubkey = script::p2pkh(&wallet::hash_pub_key(&wallet.public_key));
+        let to = test_util::address(&Wallet::new_wallet());
+        let tx = transaction::new_unsigned_transaction(
+            &script_pubkey,
+            to,
+            3,
+            1,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &utxo_set,
+        )
+        .unwrap();
+        let mut psbt = Psbt::new(&tx, &utxo_set, &[]).unwrap();
+        assert_eq!(psbt.get_fee(), 1);
+        assert!(psbt.finalize().is_err());
+        assert_eq!(psbt.sign(&Wallet::new_wallet().get_private_key()), 0);
+        assert_eq!(psbt.sign(&wallet.get_private_key()), 1);
+        assert_eq!(psbt.sign(&wallet.get_private_key()), 0);
+
+        let psbt = Psbt::from_hex(&psbt.to_hex()).unwrap();
+        let signed = psbt.finalize().unwrap();
+        assert_eq!(signed.get_id(), tx.get_id());
+        let mut mempool = Mempool::new(
+            utxo_set.get_blockchain().get_tip_hash(),
+            MempoolLimits::default(),
+        );
+        assert!(mempool.add(signed, &utxo_set).is_ok());
+    }
+
+    #[test]
+    fn two_copies_signed_by_two_of_three_keys_finalize_the_input() {
+        let (signers, redeem_script, utxo_set) = fund_multisig("psbt_multisig");
+        let psbt = spend_multisig(&redeem_script, &utxo_set);
+        let (mut first, mut second) = (psbt.clone(), psbt);
+        assert_eq!(first.sign(&signers[2].get_private_key()), 1);
+        assert_eq!(second.sign(&signers[0].get_private_key()), 1);
+        assert_eq!(first.missing_signatures(), 1);
+        assert!(first.finalize().is_err());
+
+        first.combine(&second).unwrap();
+        assert_eq!(first.missing_signatures(), 0);
+        let signed = first.finalize().unwrap();
+        let mut mempool = Mempool::new(
+            utxo_set.get_blockchain().get_tip_hash(),
+            MempoolLimits::default(),
+        );
+        assert!(mempool.add(signed, &utxo_set).is_ok());
+
+        // a third signature is not needed, the first two in key order are used
+        second.sign(&signers[1].get_private_key());
+        first.combine(&second).unwrap();
+        assert!(first.finalize().is_ok());
+    }
+
+    #[test]
+    fn a_copy_with_a_bad_signature_is_not_combined() {
+        let (signers, redeem_script, utxo_set) = fund_multisig("psbt_bad_signature");
+        let psbt = spend_multisig(&redeem_script, &utxo_set);
+        let (mut first, mut second) = (psbt.clone(), psbt);
+        first.sign(&signers[0].get_private_key());
+        second.sign(&signers[1].get_private_key());
+        let signature = second.inputs[0].signatures.values_mut().next().unwrap();
+        let last = signature.len() - 1;
+        signature[last] ^= 1;
+
+        assert!(first.combine(&second).is_err());
+        assert_eq!(first.inputs[0].signatures.len(), 1);
+        assert_eq!(first.missing_signatures(), 1);
+
+        // nor a copy of another transaction
+        let (_, other_script, other_utxo_set) = fund_multisig("psbt_other");
+        let other = spend_multisig(&other_script, &other_utxo_set);
+        assert!(first.combine(&other).is_err());
+    }
+}
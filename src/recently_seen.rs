@@ -0,0 +1,39 @@
+use std::collections::{HashSet, VecDeque};
+
+/*Remembers the ids of the last few thousand items a node received,
+valid or not, so an item relayed by several peers is requested and
+checked only once. The oldest ids are forgotten first. */
+pub struct RecentlySeen {
+    ids: HashSet<Vec<u8>>,
+    order: VecDeque<Vec<u8>>, // oldest first
+    capacity: usize,
+}
+
+impl RecentlySeen {
+    pub fn new(capacity: usize) -> RecentlySeen {
+        RecentlySeen {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn contains(&self, id: &[u8]) -> bool {
+        self.ids.contains(id)
+    }
+
+    // adds an id, returns whether it was not seen before
+    pub fn insert(&mut self, id: Vec<u8>) -> bool {
+        if self.ids.contains(&id) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id.clone());
+        self.order.push_back(id);
+        true
+    }
+}
@@ -0,0 +1,519 @@
+use crate::wallet;
+use sha2::{Digest, Sha256};
+
+// opcodes, a byte from 1 to 75 pushes that many bytes of data
+pub const OP_0: u8 = 0x00; // pushes an empty element, which is false
+pub const OP_PUSHDATA1: u8 = 0x4c; // the next byte is the length of the data pushed
+pub const OP_PUSHDATA2: u8 = 0x4d; // the next two bytes, little endian
+pub const OP_1: u8 = 0x51; // OP_1 to OP_16 push their number
+pub const OP_16: u8 = 0x60;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a; // marks an output as unspendable
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_SHA256: u8 = 0xa8;
+pub const OP_HASH160: u8 = 0xa9; // RIPEMD160 of SHA256, as for addresses
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+
+// execution limits, a script breaking one fails
+const MAX_SCRIPT_SIZE: usize = 10_000; // bytes
+const MAX_ELEMENT_SIZE: usize = 520; // bytes of one stack element
+const MAX_OPS: usize = 201; // operations other than pushes, per script
+const MAX_STACK_SIZE: usize = 1000; // elements
+pub const MAX_MULTISIG_KEYS: usize = 16; // so the key count fits in OP_1 to OP_16
+
+// one step of a script
+pub enum Op {
+    Push(Vec<u8>),
+    Code(u8),
+}
+
+/*Outputs are locked by a script, inputs unlock them with a script that
+only pushes data, like signatures and public keys. To spend an output,
+the unlocking script runs first, then the locking script runs on the
+stack it left, and must end with a true element on top.
+
+The default template pays to the hash of a public key:
+    locking:   OP_DUP OP_HASH160 <pub key hash> OP_EQUALVERIFY OP_CHECKSIG
+    unlocking: <signature> <pub key>
+
+An output can also pay to the hash of a script, the redeem script,
+which the spender reveals as the last push of the unlocking script. It
+runs after the locking script, on the stack the other pushes left:
+    locking:   OP_HASH160 <script hash> OP_EQUAL
+    unlocking: <data> ... <redeem script>
+A redeem script requiring M signatures of N keys is
+    OP_M <pub key 1> ... <pub key N> OP_N OP_CHECKMULTISIG
+unlocked by the signatures in the order of their keys:
+    <signature> ... <signature> <redeem script>
+
+check_sig tells whether a signature of the spending transaction is
+valid for a public key. */
+pub fn verify_script(
+    script_sig: &[u8],
+    script_pubkey: &[u8],
+    check_sig: &dyn Fn(&[u8], &[u8]) -> bool,
+) -> Result<(), String> {
+    if !is_push_only(script_sig) {
+        return Err("the unlocking script does more than push data".to_string());
+    }
+    let mut stack = vec![];
+    execute(script_sig, &mut stack, check_sig)?;
+    let mut redeem_stack = stack.clone();
+    execute(script_pubkey, &mut stack, check_sig)?;
+    check_true(&stack)?;
+
+    if parse_p2sh(script_pubkey).is_some() {
+        let redeem_script = pop(&mut redeem_stack)?;
+        execute(&redeem_script, &mut redeem_stack, check_sig)
+            .map_err(|e| format!("redeem script: {}", e))?;
+        check_true(&redeem_stack)?;
+    }
+    Ok(())
+}
+
+fn check_true(stack: &[Vec<u8>]) -> Result<(), String> {
+    match stack.last() {
+        Some(top) if is_true(top) => Ok(()),
+        _ => Err("the script evaluated to false".to_string()),
+    }
+}
+
+fn execute(
+    script: &[u8],
+    stack: &mut Vec<Vec<u8>>,
+    check_sig: &dyn Fn(&[u8], &[u8]) -> bool,
+) -> Result<(), String> {
+    if script.len() > MAX_SCRIPT_SIZE {
+        return Err(format!("script larger than {} bytes", MAX_SCRIPT_SIZE));
+    }
+    let ops = parse(script)?;
+    let mut op_count = ops.iter().filter(|op| matches!(op, Op::Code(_))).count();
+    if op_count > MAX_OPS {
+        return Err(format!("more than {} operations", MAX_OPS));
+    }
+
+    for op in ops {
+        match op {
+            Op::Push(data) => {
+                if data.len() > MAX_ELEMENT_SIZE {
+                    return Err(format!("element larger than {} bytes", MAX_ELEMENT_SIZE));
+                }
+                stack.push(data);
+            }
+            Op::Code(OP_VERIFY) => {
+                if !is_true(&pop(stack)?) {
+                    return Err("OP_VERIFY failed".to_string());
+                }
+            }
+            Op::Code(OP_RETURN) => return Err("OP_RETURN".to_string()),
+            Op::Code(OP_DROP) => {
+                pop(stack)?;
+            }
+            Op::Code(OP_DUP) => {
+                let top = stack.last().ok_or("stack underflow")?.clone();
+                stack.push(top);
+            }
+            Op::Code(code @ (OP_EQUAL | OP_EQUALVERIFY)) => {
+                let a = pop(stack)?;
+                let b = pop(stack)?;
+                if code == OP_EQUALVERIFY {
+                    if a != b {
+                        return Err("OP_EQUALVERIFY failed".to_string());
+                    }
+                } else {
+                    stack.push(from_bool(a == b));
+                }
+            }
+            Op::Code(OP_SHA256) => {
+                let data = pop(stack)?;
+                stack.push(Sha256::digest(data).to_vec());
+            }
+            Op::Code(OP_HASH160) => {
+                let data = pop(stack)?;
+                stack.push(wallet::hash_pub_key(&data));
+            }
+            Op::Code(code @ (OP_CHECKSIG | OP_CHECKSIGVERIFY)) => {
+                let pub_key = pop(stack)?;
+                let signature = pop(stack)?;
+                let valid = check_sig(&signature, &pub_key);
+                if code == OP_CHECKSIGVERIFY {
+                    if !valid {
+                        return Err("OP_CHECKSIGVERIFY failed".to_string());
+                    }
+                } else {
+                    stack.push(from_bool(valid));
+                }
+            }
+            Op::Code(code @ (OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY)) => {
+                let key_count = pop_count(stack, MAX_MULTISIG_KEYS)?;
+                // every key counts as an operation, it may cost a signature check
+                op_count += key_count;
+                if op_count > MAX_OPS {
+                    return Err(format!("more than {} operations", MAX_OPS));
+                }
+                let mut pub_keys = vec![];
+                for _ in 0..key_count {
+                    pub_keys.push(pop(stack)?);
+                }
+                pub_keys.reverse();
+                let sig_count = pop_count(stack, key_count)?;
+                let mut signatures = vec![];
+                for _ in 0..sig_count {
+                    signatures.push(pop(stack)?);
+                }
+                signatures.reverse();
+
+                // the signatures are in the order of their keys, each key signs at most once
+                let mut keys = pub_keys.iter();
+                let valid = signatures
+                    .iter()
+                    .all(|signature| keys.by_ref().any(|pub_key| check_sig(signature, pub_key)));
+                if code == OP_CHECKMULTISIGVERIFY {
+                    if !valid {
+                        return Err("OP_CHECKMULTISIGVERIFY failed".to_string());
+                    }
+                } else {
+                    stack.push(from_bool(valid));
+                }
+            }
+            Op::Code(code) => return Err(format!("unknown opcode 0x{:02x}", code)),
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(format!("more than {} stack elements", MAX_STACK_SIZE));
+        }
+    }
+    Ok(())
+}
+
+// splits a script into pushes and operations
+pub fn parse(script: &[u8]) -> Result<Vec<Op>, String> {
+    let mut ops = vec![];
+    let mut i = 0;
+    while i < script.len() {
+        let code = script[i];
+        i += 1;
+        let length = match code {
+            OP_0 => 0,
+            1..=75 => code as usize,
+            OP_PUSHDATA1 => {
+                let length = *script.get(i).ok_or("truncated OP_PUSHDATA1")? as usize;
+                i += 1;
+                length
+            }
+            OP_PUSHDATA2 => {
+                let bytes = script.get(i..i + 2).ok_or("truncated OP_PUSHDATA2")?;
+                i += 2;
+                u16::from_le_bytes([bytes[0], bytes[1]]) as usize
+            }
+            OP_1..=OP_16 => {
+                ops.push(Op::Push(vec![code - OP_1 + 1]));
+                continue;
+            }
+            _ => {
+                ops.push(Op::Code(code));
+                continue;
+            }
+        };
+        let data = script
+            .get(i..i + length)
+            .ok_or("push past the end of the script")?;
+        ops.push(Op::Push(data.to_vec()));
+        i += length;
+    }
+    Ok(ops)
+}
+
+// appends the shortest push of data
+pub fn push_data(script: &mut Vec<u8>, data: &[u8]) {
+    match data.len() {
+        0 => script.push(OP_0),
+        length @ 1..=75 => script.push(length as u8),
+        length @ 76..=255 => script.extend([OP_PUSHDATA1, length as u8]),
+        length => {
+            script.push(OP_PUSHDATA2);
+            script.extend((length as u16).to_le_bytes());
+        }
+    }
+    script.extend(data);
+}
+
+pub fn is_push_only(script: &[u8]) -> bool {
+    match parse(script) {
+        Ok(ops) => ops.iter().all(|op| matches!(op, Op::Push(_))),
+        Err(_) => false,
+    }
+}
+
+// the locking script paying to the hash of a public key
+pub fn p2pkh(pub_key_hash: &[u8]) -> Vec<u8> {
+    let mut script = vec![OP_DUP, OP_HASH160];
+    push_data(&mut script, pub_key_hash);
+    script.extend([OP_EQUALVERIFY, OP_CHECKSIG]);
+    script
+}
+
+// the unlocking script of a pay-to-pubkey-hash output
+pub fn p2pkh_unlock(signature: &[u8], pub_key: &[u8]) -> Vec<u8> {
+    let mut script = vec![];
+    push_data(&mut script, signature);
+    push_data(&mut script, pub_key);
+    script
+}
+
+// the locking script paying to the hash of a redeem script
+pub fn p2sh(script_hash: &[u8]) -> Vec<u8> {
+    let mut script = vec![OP_HASH160];
+    push_data(&mut script, script_hash);
+    script.push(OP_EQUAL);
+    script
+}
+
+// the redeem script hash a script pays to, if it follows the pay-to-script-hash template
+pub fn parse_p2sh(script: &[u8]) -> Option<Vec<u8>> {
+    match parse(script).ok()?.as_slice() {
+        [Op::Code(OP_HASH160), Op::Push(script_hash), Op::Code(OP_EQUAL)]
+            if script_hash.len() == 20 =>
+        {
+            Some(script_hash.clone())
+        }
+        _ => None,
+    }
+}
+
+// the redeem script requiring required signatures of pub_keys
+pub fn multisig(required: usize, pub_keys: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+    if pub_keys.is_empty() || pub_keys.len() > MAX_MULTISIG_KEYS {
+        return Err(format!(
+            "between 1 and {} keys are needed",
+            MAX_MULTISIG_KEYS
+        ));
+    }
+    if required == 0 || required > pub_keys.len() {
+        return Err(format!(
+            "between 1 and {} signatures can be required",
+            pub_keys.len()
+        ));
+    }
+    let mut script = vec![OP_1 + required as u8 - 1];
+    for pub_key in pub_keys {
+        push_data(&mut script, pub_key);
+    }
+    script.extend([OP_1 + pub_keys.len() as u8 - 1, OP_CHECKMULTISIG]);
+    // the unlocking script pushes it as one element
+    if script.len() > MAX_ELEMENT_SIZE {
+        return Err(format!(
+            "the redeem script is larger than {} bytes, use fewer keys",
+            MAX_ELEMENT_SIZE
+        ));
+    }
+    Ok(script)
+}
+
+// the unlocking script pushing elements, empty ones as OP_0, then the redeem script
+pub fn p2sh_unlock(elements: &[Vec<u8>], redeem_script: &[u8]) -> Vec<u8> {
+    let mut script = vec![];
+    for element in elements {
+        push_data(&mut script, element);
+    }
+    push_data(&mut script, redeem_script);
+    script
+}
+
+// the elements pushed by a push only script
+pub fn parse_pushes(script: &[u8]) -> Option<Vec<Vec<u8>>> {
+    parse(script)
+        .ok()?
+        .into_iter()
+        .map(|op| match op {
+            Op::Push(data) => Some(data),
+            Op::Code(_) => None,
+        })
+        .collect()
+}
+
+// the number of signatures required and the keys of a multisig redeem script
+pub fn parse_multisig(script: &[u8]) -> Option<(usize, Vec<Vec<u8>>)> {
+    let (last, pushes) = script.split_last()?;
+    if *last != OP_CHECKMULTISIG {
+        return None;
+    }
+    let mut pushes = parse_pushes(pushes)?;
+    if pushes.len() < 3 {
+        return None;
+    }
+    let key_count = *pushes.pop()?.first()? as usize;
+    let required = *pushes.remove(0).first()? as usize;
+    if pushes.len() != key_count || required == 0 || required > key_count {
+        return None;
+    }
+    Some((required, pushes))
+}
+
+// the public key hash a script pays to, if it follows the pay-to-pubkey-hash template
+pub fn parse_p2pkh(script: &[u8]) -> Option<Vec<u8>> {
+    match parse(script).ok()?.as_slice() {
+        [Op::Code(OP_DUP), Op::Code(OP_HASH160), Op::Push(pub_key_hash), Op::Code(OP_EQUALVERIFY), Op::Code(OP_CHECKSIG)] => {
+            Some(pub_key_hash.clone())
+        }
+        _ => None,
+    }
+}
+
+// the public key of a pay-to-pubkey-hash unlocking script
+pub fn parse_p2pkh_unlock(script: &[u8]) -> Option<Vec<u8>> {
+    match parse(script).ok()?.as_slice() {
+        [Op::Push(_), Op::Push(pub_key)] => Some(pub_key.clone()),
+        _ => None,
+    }
+}
+
+// a readable form of a script, as in OP_DUP OP_HASH160 <hex> ...
+pub fn disassemble(script: &[u8]) -> String {
+    let ops = match parse(script) {
+        Ok(ops) => ops,
+        Err(e) => return format!("[invalid script: {}]", e),
+    };
+    let words: Vec<String> = ops
+        .iter()
+        .map(|op| match op {
+            Op::Push(data) => hex::encode(data),
+            Op::Code(code) => opcode_name(*code),
+        })
+        .collect();
+    words.join(" ")
+}
+
+fn opcode_name(code: u8) -> String {
+    let name = match code {
+        OP_VERIFY => "OP_VERIFY",
+        OP_RETURN => "OP_RETURN",
+        OP_DROP => "OP_DROP",
+        OP_DUP => "OP_DUP",
+        OP_EQUAL => "OP_EQUAL",
+        OP_EQUALVERIFY => "OP_EQUALVERIFY",
+        OP_SHA256 => "OP_SHA256",
+        OP_HASH160 => "OP_HASH160",
+        OP_CHECKSIG => "OP_CHECKSIG",
+        OP_CHECKSIGVERIFY => "OP_CHECKSIGVERIFY",
+        OP_CHECKMULTISIG => "OP_CHECKMULTISIG",
+        OP_CHECKMULTISIGVERIFY => "OP_CHECKMULTISIGVERIFY",
+        _ => return format!("OP_UNKNOWN[0x{:02x}]", code),
+    };
+    name.to_string()
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
+    stack.pop().ok_or_else(|| "stack underflow".to_string())
+}
+
+// pops a count, an element of at most one byte up to max
+fn pop_count(stack: &mut Vec<Vec<u8>>, max: usize) -> Result<usize, String> {
+    let element = pop(stack)?;
+    let count = match element.as_slice() {
+        [] => 0,
+        [count] => *count as usize,
+        _ => return Err("invalid count".to_string()),
+    };
+    if count > max {
+        return Err(format!("count {} above {}", count, max));
+    }
+    Ok(count)
+}
+
+// any element with a non zero byte is true
+fn is_true(element: &[u8]) -> bool {
+    element.iter().any(|byte| *byte != 0)
+}
+
+fn from_bool(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // signatures are stood in for by the key they are made with, so no keys are needed
+    fn sign(pub_key: &[u8]) -> Vec<u8> {
+        [b"signed by ".as_slice(), pub_key].concat()
+    }
+
+    fn check_sig(signature: &[u8], pub_key: &[u8]) -> bool {
+        signature == sign(pub_key)
+    }
+
+    fn keys() -> Vec<Vec<u8>> {
+        (1..=3).map(|n| vec![n; 33]).collect()
+    }
+
+    #[test]
+    fn p2pkh_needs_a_signature_of_the_key() {
+        let pub_key = vec![7; 33];
+        let script_pubkey = p2pkh(&wallet::hash_pub_key(&pub_key));
+        let unlock = p2pkh_unlock(&sign(&pub_key), &pub_key);
+        assert!(verify_script(&unlock, &script_pubkey, &check_sig).is_ok());
+
+        let other_key = vec![8; 33];
+        let wrong_key = p2pkh_unlock(&sign(&other_key), &other_key);
+        assert!(verify_script(&wrong_key, &script_pubkey, &check_sig).is_err());
+        let wrong_signature = p2pkh_unlock(&sign(&other_key), &pub_key);
+        assert!(verify_script(&wrong_signature, &script_pubkey, &check_sig).is_err());
+    }
+
+    #[test]
+    fn unlocking_scripts_only_push_data() {
+        let pub_key = vec![7; 33];
+        let script_pubkey = p2pkh(&wallet::hash_pub_key(&pub_key));
+        let mut unlock = p2pkh_unlock(&sign(&pub_key), &pub_key);
+        unlock.push(OP_DUP);
+        unlock.push(OP_DROP);
+        assert!(verify_script(&unlock, &script_pubkey, &check_sig).is_err());
+    }
+
+    #[test]
+    fn multisig_needs_enough_signatures_in_key_order() {
+        let keys = keys();
+        let redeem_script = multisig(2, &keys).unwrap();
+        assert_eq!(parse_multisig(&redeem_script), Some((2, keys.clone())));
+        let script_pubkey = p2sh(&wallet::hash_pub_key(&redeem_script));
+
+        let unlock = p2sh_unlock(&[sign(&keys[0]), sign(&keys[2])], &redeem_script);
+        assert!(verify_script(&unlock, &script_pubkey, &check_sig).is_ok());
+
+        let too_few = p2sh_unlock(&[sign(&keys[1])], &redeem_script);
+        assert!(verify_script(&too_few, &script_pubkey, &check_sig).is_err());
+        let out_of_order = p2sh_unlock(&[sign(&keys[2]), sign(&keys[0])], &redeem_script);
+        assert!(verify_script(&out_of_order, &script_pubkey, &check_sig).is_err());
+        let same_key_twice = p2sh_unlock(&[sign(&keys[1]), sign(&keys[1])], &redeem_script);
+        assert!(verify_script(&same_key_twice, &script_pubkey, &check_sig).is_err());
+    }
+
+    #[test]
+    fn p2sh_needs_the_redeem_script_of_the_hash() {
+        let keys = keys();
+        let redeem_script = multisig(1, &keys).unwrap();
+        let script_pubkey = p2sh(&wallet::hash_pub_key(&redeem_script));
+        let other_script = multisig(1, &keys[..2]).unwrap();
+        let unlock = p2sh_unlock(&[sign(&keys[0])], &other_script);
+        assert!(verify_script(&unlock, &script_pubkey, &check_sig).is_err());
+    }
+
+    #[test]
+    fn multisig_limits_keys_and_signatures() {
+        let keys = keys();
+        assert!(multisig(0, &keys).is_err());
+        assert!(multisig(4, &keys).is_err());
+        assert!(multisig(1, &[]).is_err());
+        assert!(multisig(1, &vec![vec![1; 33]; MAX_MULTISIG_KEYS + 1]).is_err());
+    }
+}
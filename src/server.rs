@@ -1,303 +1,2024 @@
+use crate::addr_book::{self, AddrBook, NetAddress};
+use crate::ban_list;
+use crate::block::MAX_BLOCK_SIZE;
+use crate::compact_block::{CompactBlock, PartialBlock};
+use crate::in_flight::InFlight;
+use crate::mempool::{Mempool, MempoolLimits, Rejection};
+use crate::miner;
+use crate::orphan_blocks::OrphanBlocks;
+use crate::orphan_txs::OrphanTxs;
+use crate::peer::{Peer, PeerEvent, PeerInfo, PeerVersion};
+use crate::recently_seen::RecentlySeen;
 use crate::transaction;
+use crate::transport::{self, Transport};
 use crate::Block;
+use crate::BlockHeader;
 use crate::Blockchain;
+use crate::ProofOfWork;
 use crate::Transaction;
 use crate::UtxoSet;
-use once_cell::sync::Lazy;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
-use std::collections::HashMap;
-use std::io::Read;
-use std::io::Write;
-use std::net::{TcpListener, TcpStream};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-const NODE_VERSION: usize = 1;
-const COMMAND_LENGTH: usize = 12;
+const PROTOCOL_VERSION: u32 = 8;
+const MIN_PEER_PROTO_VERSION: u32 = 8; // peers below this version are disconnected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5); // how long an unreachable node may hold up a connection attempt
+const PING_INTERVAL: Duration = Duration::from_secs(30); // time between two pings to a peer
+const PING_TIMEOUT: Duration = Duration::from_secs(60); // peers not answering a ping in time are dropped
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+const DUMP_INTERVAL: Duration = Duration::from_secs(60); // how often the address book and the mempool are saved
+const MAX_OUTBOUND_PEERS: usize = 8;
+const ADDR_RETRY_INTERVAL: i64 = 60; // seconds before a known node is tried again
+const MAX_ADDR_TO_SEND: usize = 1000; // maximum number of addresses in one addr message
+const ADDR_RELAY_MAX: usize = 10; // addr messages up to this size announce new nodes and are relayed
+const ADDR_RELAY_FANOUT: usize = 2; // number of peers a new address is relayed to
+const RECENT_TXS: usize = 50_000; // number of received transaction ids remembered
+const MAX_INV_SIZE: usize = 50_000; // maximum number of items in one inv or notfound
+const MAX_GETDATA_SIZE: usize = 1000; // maximum number of items in one getdata
+const MAX_PEER_TX_ANNOUNCEMENTS: usize = 5000; // transactions tracked per peer, further announcements are ignored
+const SEND_QUEUE_BUDGET: usize = 4 * 1024 * 1024; // getdata is not served to a peer with more bytes queued
+const TX_REQUEST_TIMEOUT: Duration = Duration::from_secs(30); // a transaction not delivered in time is asked from another peer
+const BLOCK_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const BLOCK_DOWNLOAD_WINDOW: usize = 1024; // blocks are requested at most this far ahead of the lowest missing one
+const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+const BLOCK_STALL_TIMEOUT: Duration = Duration::from_secs(5); // how long the lowest missing block may hold up the window
+const DEFAULT_MINING_INTERVAL: Duration = Duration::from_secs(10);
+const COINBASE_RESERVED_SIZE: usize = 1000; // block space kept free for the coinbase
+const MEMPOOL_EXPIRY_INTERVAL: Duration = Duration::from_secs(60); // how often expired transactions are dropped
+const MAX_PARTIAL_BLOCKS: usize = 16; // compact blocks waiting for transactions, further ones are downloaded in full
 
-static mut NODE_ADDRESS: String = String::new();
-static mut MINING_ADDRESS: String = String::new();
-pub static mut KNOWN_NODES: Lazy<Vec<String>> = Lazy::new(|| vec![String::from("127.0.0.1:3000")]);
-static mut BLOCKS_IN_TRANSIT: Vec<Vec<u8>> = Vec::new();
-static mut MEMPOOL: Lazy<HashMap<String, Transaction>> = Lazy::new(|| HashMap::new());
+// service bits announced in version messages
+const NODE_NETWORK: u64 = 1; // full node, serves the whole block chain
+const NODE_LIGHT: u64 = 1 << 1; // keeps no chain, only submits and relays transactions
+const NODE_TX_INDEX: u64 = 1 << 2; // serves any confirmed transaction by id
+const MAX_HEADERS_RESULTS: usize = 2000; // maximum number of headers in one headers message
+const DISPATCHER_POLL_INTERVAL: Duration = Duration::from_millis(200); // how often the dispatcher checks for stop
+
+// misbehaviour scores, a peer reaching BAN_THRESHOLD is banned for BAN_DURATION seconds
+const BAN_THRESHOLD: u32 = 100;
+const BAN_DURATION: i64 = 24 * 60 * 60;
+const PROTOCOL_VIOLATION_SCORE: u32 = 1; // e.g. a message before the handshake
+const INVALID_TX_SCORE: u32 = 10;
+const MALFORMED_MESSAGE_SCORE: u32 = 20;
+const INVALID_HEADER_SCORE: u32 = 100;
+const INVALID_BLOCK_SCORE: u32 = 100;
+
+const SEED_NODE: &str = "127.0.0.1:3000"; // the node every other node connects to first, unless configured otherwise
+const DEFAULT_PORT: u16 = 3000;
 
 /*When a new node is run, it gets several nodes from a DNS seed,
-and sends them version message */
+and sends them version message. The connecting side speaks first, the
+other side answers with its own version, and each side acknowledges
+the version it received with verack. */
 #[derive(Serialize, Deserialize)]
 struct Version {
-    version: usize,
-    best_height: usize, // the length of the node's blockchain
-    addr_from: String,  // the address of the sender
+    version: u32,
+    services: u64,
+    user_agent: String,
+    start_height: usize, // the length of the node's blockchain
+    nonce: u64,          // random per node, reveals connections to ourselves
+    addr_from: String,   // the address of the sender, empty if it does not accept connections
 }
 
-pub fn start_server(node_id: String, miner_address: String) {
-    unsafe {
-        NODE_ADDRESS = format!("127.0.0.1:{}", node_id);
-        MINING_ADDRESS = miner_address;
-        let ln = TcpListener::bind(&NODE_ADDRESS).unwrap();
+/*How a node is run. The node ID only names the data files, the
+addresses the node listens on and the one it advertises are set
+independently, so a node can listen on all interfaces or behind NAT. */
+pub struct NodeConfig {
+    pub node_id: String,
+    pub mining_address: String,    // empty unless the node mines
+    pub mining_interval: Duration, // how often a block is mined while there are transactions
+    pub listen_addrs: Vec<SocketAddr>,
+    pub external_addr: Option<SocketAddr>, // defaults to the first specific listen address
+    pub encrypt: bool,                     // encrypts all sessions, peers must encrypt as well
+    pub allowed_peers: Vec<Vec<u8>>, // node keys of the peers accepted on encrypted sessions, empty to accept any
+    pub mempool_limits: MempoolLimits,
+    pub seed_nodes: Vec<String>, // connected to first while no other node is known
+}
 
-        let mut bc = Blockchain::new_blockchain(node_id.clone()).unwrap();
-        if NODE_ADDRESS != KNOWN_NODES[0] {
-            send_version(KNOWN_NODES[0].clone(), &bc);
+impl NodeConfig {
+    /*listens on loopback, on the port given by a numeric node ID as
+    before, or on DEFAULT_PORT */
+    pub fn new(node_id: String, mining_address: String) -> NodeConfig {
+        let port = node_id.parse::<u16>().unwrap_or(DEFAULT_PORT);
+        NodeConfig {
+            node_id,
+            mining_address,
+            mining_interval: DEFAULT_MINING_INTERVAL,
+            listen_addrs: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)],
+            external_addr: None,
+            encrypt: false,
+            allowed_peers: vec![],
+            mempool_limits: MempoolLimits::default(),
+            seed_nodes: vec![SEED_NODE.to_string()],
         }
+    }
 
-        for stream in ln.incoming() {
-            let stream = stream.unwrap();
-            handle_connection(stream, &mut bc);
-        }
+    // the address announced to other nodes, empty if there is none to announce
+    fn advertised_address(&self) -> String {
+        self.external_addr
+            .or_else(|| {
+                self.listen_addrs
+                    .iter()
+                    .find(|addr| !addr.ip().is_unspecified())
+                    .copied()
+            })
+            .map(|addr| addr.to_string())
+            .unwrap_or_default()
     }
 }
 
-fn send_version(addr: String, bc: &Blockchain) {
-    let best_height = bc.get_best_height();
-    let payload = bincode::serialize(&Version {
-        version: NODE_VERSION,
-        best_height,
-        addr_from: unsafe { NODE_ADDRESS.clone() },
-    })
-    .unwrap();
+/*A node owns its configuration, chain, mempool and peer sessions.
+All of them sit behind locks, so several nodes can run in one process
+and be queried while they are running. */
+#[derive(Clone)]
+pub struct Node {
+    inner: Arc<NodeState>,
+}
 
-    let mut request = command_to_bytes("version");
-    request.extend(payload);
-    send_data(addr, request);
+struct NodeState {
+    node_id: String,
+    node_address: String, // the address we advertise, empty if we do not accept connections
+    listeners: Mutex<Vec<TcpListener>>, // bound when the node is created, accepting once it is started
+    bound_addrs: Mutex<Vec<SocketAddr>>, // where the listeners accept connections while the node runs
+    mining_address: String,
+    mining_interval: Duration,
+    local_nonce: u64,
+    transport: Option<Arc<Transport>>, // set when sessions are encrypted
+    best_height: AtomicUsize,          // announced in our version without locking the chain
+    chain: Mutex<Blockchain>,
+    addr_book: Mutex<AddrBook>,
+    headers_in_transit: Mutex<Vec<BlockHeader>>, // validated headers whose blocks are not downloaded yet, ascending by height
+    block_requests: Mutex<InFlight>,
+    blocks_downloaded: Mutex<HashMap<Vec<u8>, (Block, String)>>, // blocks of headers_in_transit waiting for their parents, with the peers that sent them
+    tx_requests: Mutex<InFlight>,
+    orphan_blocks: Mutex<OrphanBlocks>,
+    partial_blocks: Mutex<HashMap<Vec<u8>, (String, PartialBlock)>>, // compact blocks waiting for their missing transactions, with the peer asked for them
+    mempool: Mutex<Mempool>,
+    orphan_txs: Mutex<OrphanTxs>,
+    recent_txs: Mutex<RecentlySeen>, // transactions accepted lately or found invalid, whatever copy of them is sent
+    peers: Mutex<HashMap<String, Peer>>, // open sessions by socket address
+    peer_events: Mutex<Option<Sender<PeerEvent>>>, // feeds the dispatcher while the node runs
+    running: AtomicBool,
+    threads: Mutex<Vec<JoinHandle<()>>>,
 }
 
-fn send_data(addr: String, data: Vec<u8>) {
-    let stream = TcpStream::connect(addr.clone());
-    match stream {
-        Ok(mut stream) => {
-            stream.write(&data).unwrap();
+impl Node {
+    /*binds the listen addresses, so a node given port 0 advertises the
+    port the system picked */
+    pub fn new(mut config: NodeConfig) -> Result<Node, String> {
+        let node_id = config.node_id.clone();
+        let chain = Blockchain::new_blockchain(node_id.clone())?;
+        let mut listeners = vec![];
+        let mut bound_addrs = vec![];
+        for addr in &config.listen_addrs {
+            let ln = TcpListener::bind(addr).map_err(|e| format!("{}: {}", addr, e))?;
+            bound_addrs.push(ln.local_addr().map_err(|e| format!("{}: {}", addr, e))?);
+            listeners.push(ln);
         }
-        Err(e) => {
-            eprintln!("Failed to connect: {}", e);
-            println!("{} is not available", addr);
-            unsafe {
-                KNOWN_NODES.retain(|node| *node != addr);
+        config.listen_addrs = bound_addrs.clone();
+        let node_address = config.advertised_address();
+        // the seed nodes are only needed while we know no other node
+        let mut addr_book = addr_book::new_addr_book(&node_id);
+        if addr_book.is_empty() {
+            for seed_node in &config.seed_nodes {
+                if *seed_node != node_address {
+                    addr_book.add(seed_node.clone(), chrono::Utc::now().timestamp());
+                }
             }
         }
+        let utxo_set = UtxoSet::new(chain.clone());
+        utxo_set.update_to_tip();
+        let mut mempool = Mempool::new(chain.get_tip_hash(), config.mempool_limits);
+        let loaded = mempool.load_from_file(&node_id, &utxo_set);
+        if loaded > 0 {
+            println!("Loaded {} transactions into the mempool", loaded);
+        }
+        let transport = if config.encrypt {
+            let node_key = transport::load_node_key(&node_id)?;
+            Some(Arc::new(Transport::new(node_key, config.allowed_peers)))
+        } else {
+            None
+        };
+        Ok(Node {
+            inner: Arc::new(NodeState {
+                node_address,
+                node_id,
+                listeners: Mutex::new(listeners),
+                bound_addrs: Mutex::new(bound_addrs),
+                mining_address: config.mining_address,
+                mining_interval: config.mining_interval,
+                local_nonce: random_nonce(),
+                transport,
+                best_height: AtomicUsize::new(chain.get_best_height()),
+                chain: Mutex::new(chain),
+                addr_book: Mutex::new(addr_book),
+                headers_in_transit: Mutex::new(Vec::new()),
+                block_requests: Mutex::new(InFlight::new()),
+                blocks_downloaded: Mutex::new(HashMap::new()),
+                tx_requests: Mutex::new(InFlight::new()),
+                orphan_blocks: Mutex::new(OrphanBlocks::new()),
+                partial_blocks: Mutex::new(HashMap::new()),
+                mempool: Mutex::new(mempool),
+                orphan_txs: Mutex::new(OrphanTxs::new()),
+                recent_txs: Mutex::new(RecentlySeen::new(RECENT_TXS)),
+                peers: Mutex::new(HashMap::new()),
+                peer_events: Mutex::new(None),
+                running: AtomicBool::new(false),
+                threads: Mutex::new(Vec::new()),
+            }),
+        })
     }
-}
 
-fn handle_connection(mut stream: TcpStream, bc: &mut Blockchain) {
-    let mut request = Vec::new();
-    stream.read_to_end(&mut request).unwrap();
+    // starts the listener, dispatcher and maintenance threads
+    pub fn start(&self) -> Result<(), String> {
+        let listeners: Vec<TcpListener> = self.inner.listeners.lock().unwrap().drain(..).collect();
+        let (events, received) = mpsc::channel();
+        *self.inner.peer_events.lock().unwrap() = Some(events.clone());
+        self.inner.running.store(true, Ordering::SeqCst);
 
-    let command = bytes_to_command(&request[..COMMAND_LENGTH]);
-    println!("Received command: {}", command);
+        let mut threads = self.inner.threads.lock().unwrap();
+        for ln in listeners {
+            if let Ok(addr) = ln.local_addr() {
+                println!("Listening on {}", addr);
+            }
+            let node = self.clone();
+            let events = events.clone();
+            threads.push(thread::spawn(move || node.accept_connections(ln, events)));
+        }
+        let node = self.clone();
+        threads.push(thread::spawn(move || node.dispatch(received)));
+        let node = self.clone();
+        threads.push(thread::spawn(move || node.maintain_peers()));
+        drop(threads);
 
-    match command.as_str() {
-        "addr" => handle_addr(&request),
-        "block" => handle_block(&request, bc),
-        "inv" => handle_inv(&request),
-        "getblocks" => handle_get_blocks(&request, bc),
-        "getdata" => handle_get_data(&request, bc),
-        "tx" => handle_tx(&request, bc),
-        "version" => handle_version(&request, bc),
-        _ => println!("Unknown command!"),
+        self.open_outbound_connections();
+        Ok(())
     }
 
-    stream.flush().unwrap();
-}
+    // disconnects all peers and stops the node threads
+    pub fn stop(&self) {
+        if !self.inner.running.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        for peer in self.inner.peers.lock().unwrap().values() {
+            peer.disconnect();
+        }
+        // wakes the listeners blocked in accept, so they see the node has stopped
+        for addr in self.inner.bound_addrs.lock().unwrap().drain(..) {
+            let _ = TcpStream::connect(reachable_addr(addr));
+        }
+        *self.inner.peer_events.lock().unwrap() = None;
+        self.wait();
+        self.save_addr_book();
+        self.save_mempool();
+    }
 
-fn handle_version(request: &Vec<u8>, bc: &Blockchain) {
-    let payload: Version = bincode::deserialize(&request[COMMAND_LENGTH..]).unwrap();
-    let my_best_height = bc.get_best_height();
-    let foreigner_best_height = payload.best_height;
+    // blocks until the node is stopped
+    pub fn wait(&self) {
+        let threads: Vec<JoinHandle<()>> = self.inner.threads.lock().unwrap().drain(..).collect();
+        for handle in threads {
+            handle.join().unwrap();
+        }
+    }
 
-    if my_best_height < foreigner_best_height {
-        send_get_blocks(payload.addr_from.clone());
-    } else if my_best_height > foreigner_best_height {
-        send_version(payload.addr_from.clone(), bc);
+    pub fn is_running(&self) -> bool {
+        self.inner.running.load(Ordering::SeqCst)
     }
 
-    if !node_is_known(&payload.addr_from) {
-        unsafe {
-            KNOWN_NODES.push(payload.addr_from);
-        }
+    pub fn get_node_address(&self) -> String {
+        self.inner.node_address.clone()
     }
-}
 
-#[derive(Serialize, Deserialize)]
-struct GetBlocks {
-    addr_from: String,
-}
+    // the public key other nodes know this node by, if sessions are encrypted
+    pub fn get_node_key(&self) -> Option<Vec<u8>> {
+        self.inner
+            .transport
+            .as_ref()
+            .map(|transport| transport.get_public_key())
+    }
 
-fn send_get_blocks(addr: String) {
-    let payload = bincode::serialize(&GetBlocks {
-        addr_from: unsafe { NODE_ADDRESS.clone() },
-    })
-    .unwrap();
+    pub fn get_best_height(&self) -> usize {
+        self.inner.chain.lock().unwrap().get_best_height()
+    }
 
-    let mut request = command_to_bytes("getblocks");
-    request.extend(payload);
-    send_data(addr, request);
-}
+    pub fn get_known_nodes(&self) -> Vec<String> {
+        self.inner.addr_book.lock().unwrap().get_addresses()
+    }
 
-fn handle_get_blocks(request: &Vec<u8>, bc: &Blockchain) {
-    let payload: GetBlocks = bincode::deserialize(&request[COMMAND_LENGTH..]).unwrap();
-    let blocks = bc.get_block_hashes();
-    send_inv(payload.addr_from, "block", blocks);
-}
+    // returns the socket addresses of the open peer sessions
+    pub fn get_peers(&self) -> Vec<String> {
+        self.inner.peers.lock().unwrap().keys().cloned().collect()
+    }
 
-/*Bitcoin uses inv to show other nodes what blocks or
-transactions current node has.  */
-#[derive(Serialize, Deserialize)]
-struct Inv {
-    addr_from: String,
-    inv_type: String, //whether these are blocks or transactions.
-    items: Vec<Vec<u8>>,
-}
+    pub fn get_peer_info(&self) -> Vec<PeerInfo> {
+        let peers = self.inner.peers.lock().unwrap();
+        peers.values().map(|peer| peer.get_info()).collect()
+    }
 
-fn send_inv(address: String, kind: &str, items: Vec<Vec<u8>>) {
-    let payload = bincode::serialize(&Inv {
-        addr_from: unsafe { NODE_ADDRESS.clone() },
-        inv_type: kind.to_string(),
-        items,
-    })
-    .unwrap();
+    /*mines a block of the mempool transactions paying the highest fee
+    rates and announces it. Returns None if the node does not mine or
+    has no transactions to mine. */
+    pub fn mine_block(&self) -> Option<Block> {
+        let mining_address = &self.inner.mining_address;
+        if mining_address.is_empty() {
+            return None;
+        }
+        let mut bc = self.inner.chain.lock().unwrap();
+        self.sync_mempool(&bc);
+        let template = {
+            let mempool = self.inner.mempool.lock().unwrap();
+            if mempool.is_empty() {
+                return None;
+            }
+            miner::new_block_template(&mempool, MAX_BLOCK_SIZE - COINBASE_RESERVED_SIZE)
+        };
+        if template.transactions.is_empty() {
+            return None;
+        }
 
-    let mut request = command_to_bytes("inv");
-    request.extend(payload);
-    send_data(address, request);
-}
+        let height = bc.get_best_height() + 1;
+        let cb_tx = transaction::new_coinbase_tx(
+            mining_address.clone(),
+            format!("Height {}", height),
+            template.fees,
+        );
+        let tx_count = template.transactions.len();
+        let mut txs = vec![cb_tx];
+        txs.extend(template.transactions);
+        let new_block = match bc.mine_block(txs) {
+            Ok(block) => block,
+            Err(e) => {
+                println!("Failed to mine a block: {}", e);
+                return None;
+            }
+        };
+        println!(
+            "New block is mined! {} transactions, {} bytes, {} in fees",
+            tx_count, template.size, template.fees
+        );
+        self.sync_mempool(&bc);
+        self.inner
+            .best_height
+            .store(bc.get_best_height(), Ordering::SeqCst);
+        self.relay_block(None, &new_block);
+        Some(new_block)
+    }
 
-fn handle_inv(request: &Vec<u8>) {
-    let payload: Inv = bincode::deserialize(&request[COMMAND_LENGTH..]).unwrap();
-    if payload.inv_type == "block" {
-        unsafe {
-            BLOCKS_IN_TRANSIT = payload.items.clone();
+    pub fn get_mempool(&self) -> Vec<Transaction> {
+        self.inner.mempool.lock().unwrap().get_transactions()
+    }
+
+    fn accept_connections(&self, ln: TcpListener, events: Sender<PeerEvent>) {
+        for stream in ln.incoming() {
+            if !self.is_running() {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    if let Ok(addr) = stream.peer_addr() {
+                        if self.is_banned(addr.ip()) {
+                            println!("Refusing connection from banned {}", addr);
+                            continue;
+                        }
+                    }
+                    // registered before its first message can reach the dispatcher
+                    let mut peers = self.inner.peers.lock().unwrap();
+                    match Peer::spawn(stream, false, self.inner.transport.clone(), events.clone()) {
+                        Ok(peer) => {
+                            println!("Accepted connection from {}", peer.get_addr());
+                            peers.insert(peer.get_addr(), peer);
+                        }
+                        Err(e) => eprintln!("Failed to set up an accepted connection: {}", e),
+                    }
+                }
+                Err(e) => eprintln!("Failed to accept connection: {}", e),
+            }
         }
-        let block_hash = payload.items[0].clone();
-        send_get_data(payload.addr_from, "block", &block_hash);
+    }
 
-        for (i, b) in payload.items.into_iter().enumerate() {
-            if b.eq(&block_hash) {
-                unsafe {
-                    BLOCKS_IN_TRANSIT.remove(i);
+    // the dispatcher: every message of every session is handled on this thread
+    fn dispatch(&self, received: Receiver<PeerEvent>) {
+        while self.is_running() {
+            let event = match received.recv_timeout(DISPATCHER_POLL_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            match event {
+                PeerEvent::Message {
+                    peer,
+                    command,
+                    payload,
+                } => {
+                    let mut bc = self.inner.chain.lock().unwrap();
+                    let result = self.handle_message(&peer, &command, &payload, &mut bc);
+                    self.inner
+                        .best_height
+                        .store(bc.get_best_height(), Ordering::SeqCst);
+                    drop(bc);
+                    if let Err(misbehavior) = result {
+                        self.misbehaving(&peer, misbehavior);
+                    }
+                }
+                PeerEvent::Misbehaved { peer, reason } => {
+                    self.misbehaving(&peer, Misbehavior::new(MALFORMED_MESSAGE_SCORE, reason));
+                }
+                PeerEvent::Disconnected(peer) => {
+                    if let Some(session) = self.inner.peers.lock().unwrap().remove(&peer) {
+                        let direction = if session.is_outbound() {
+                            "outbound"
+                        } else {
+                            "inbound"
+                        };
+                        println!("Peer {} ({}) disconnected", peer, direction);
+                    }
+                    // what the peer was asked for is asked from others
+                    let txs = self.inner.tx_requests.lock().unwrap().remove_peer(&peer);
+                    self.retry_tx_requests(txs);
+                    self.inner.block_requests.lock().unwrap().remove_peer(&peer);
+                    self.inner
+                        .partial_blocks
+                        .lock()
+                        .unwrap()
+                        .retain(|_, (from, _)| *from != peer);
+                    self.request_blocks();
                 }
             }
         }
-    } else if payload.inv_type == "tx" {
-        let tx_id = payload.items[0].clone();
-        unsafe {
-            println!("get MEMPOOP key: {}", hex::encode(tx_id.clone()));
-            if !MEMPOOL.contains_key(&hex::encode(tx_id.clone())) {
-                send_get_data(payload.addr_from, "tx", &tx_id);
+    }
+
+    /*pings every peer periodically and disconnects the ones that did not
+    complete the handshake or answer the previous ping in time. Outbound
+    sessions are topped up and the address book and mempool are saved now
+    and then. */
+    fn maintain_peers(&self) {
+        let mut last_dump = Instant::now();
+        let mut last_mining = Instant::now();
+        let mut last_expiry = Instant::now();
+        while self.is_running() {
+            thread::sleep(MAINTENANCE_INTERVAL);
+            let mut peers = self.inner.peers.lock().unwrap();
+            for peer in peers.values_mut() {
+                let handshake_timed_out =
+                    !peer.is_handshake_complete() && peer.get_connected_time() > HANDSHAKE_TIMEOUT;
+                if handshake_timed_out || peer.is_ping_timed_out(PING_TIMEOUT) {
+                    println!("Peer {} stopped responding, disconnecting", peer.get_addr());
+                    peer.disconnect();
+                } else if peer.is_handshake_complete() && peer.is_ping_due(PING_INTERVAL) {
+                    peer.send_ping(random_nonce());
+                }
+            }
+            drop(peers);
+
+            self.expire_requests();
+            self.open_outbound_connections();
+            if last_dump.elapsed() >= DUMP_INTERVAL {
+                self.save_addr_book();
+                self.save_mempool();
+                last_dump = Instant::now();
+            }
+            if !self.inner.mining_address.is_empty()
+                && last_mining.elapsed() >= self.inner.mining_interval
+            {
+                self.mine_block();
+                last_mining = Instant::now();
+            }
+            if last_expiry.elapsed() >= MEMPOOL_EXPIRY_INTERVAL {
+                let expired = self.inner.mempool.lock().unwrap().expire();
+                if !expired.is_empty() {
+                    println!("Dropped {} expired transactions", expired.len());
+                }
+                last_expiry = Instant::now();
             }
         }
     }
-}
 
-#[derive(Serialize, Deserialize)]
-struct GetData {
-    addr_from: String,
-    kind: String,
-    id: Vec<u8>,
-}
+    // asks other peers for the items that were not delivered in time
+    fn expire_requests(&self) {
+        let expired = self
+            .inner
+            .tx_requests
+            .lock()
+            .unwrap()
+            .expire(TX_REQUEST_TIMEOUT);
+        for (tx_id, peer) in &expired {
+            println!(
+                "Peer {} did not deliver transaction {} in time",
+                peer,
+                hex::encode(tx_id)
+            );
+        }
+        self.retry_tx_requests(expired.into_iter().map(|(tx_id, _)| tx_id).collect());
 
-fn send_get_data(addr: String, kind: &str, id: &[u8]) {
-    let payload = bincode::serialize(&GetData {
-        addr_from: unsafe { NODE_ADDRESS.clone() },
-        kind: kind.to_string(),
-        id: id.to_vec(),
-    })
-    .unwrap();
+        let expired = self
+            .inner
+            .block_requests
+            .lock()
+            .unwrap()
+            .expire(BLOCK_REQUEST_TIMEOUT);
+        for (block_hash, peer) in &expired {
+            println!(
+                "Peer {} did not deliver block {} in time",
+                peer,
+                hex::encode(block_hash)
+            );
+        }
+        if !expired.is_empty() {
+            self.request_blocks();
+        }
+        self.detect_block_stall();
+    }
 
-    let mut request = command_to_bytes("getdata");
-    request.extend(payload);
-    send_data(addr, request);
-}
+    /*The window cannot move while its lowest block is missing. A peer
+    holding it back for too long is dropped when another peer can serve
+    the block, and its blocks are requested from the others. */
+    fn detect_block_stall(&self) {
+        let (block_hash, height) = match self.inner.headers_in_transit.lock().unwrap().first() {
+            Some(header) => (header.get_hash(), header.get_height()),
+            None => return,
+        };
+        let staller = {
+            let block_requests = self.inner.block_requests.lock().unwrap();
+            match block_requests.request_age(&block_hash) {
+                Some(age) if age > BLOCK_STALL_TIMEOUT => block_requests
+                    .requested_from(&block_hash)
+                    .unwrap()
+                    .to_string(),
+                _ => return,
+            }
+        };
+        {
+            let peers = self.inner.peers.lock().unwrap();
+            let replaceable = peers
+                .iter()
+                .any(|(addr, peer)| *addr != staller && can_serve_block(peer, height));
+            if !replaceable {
+                return;
+            }
+            println!("Peer {} stalls the block download, disconnecting", staller);
+            if let Some(peer) = peers.get(&staller) {
+                peer.disconnect();
+            }
+        }
+        self.inner
+            .block_requests
+            .lock()
+            .unwrap()
+            .remove_peer(&staller);
+        self.request_blocks();
+    }
+
+    // connects to randomly selected known nodes until there are enough outbound sessions
+    fn open_outbound_connections(&self) {
+        let now = chrono::Utc::now().timestamp();
+        loop {
+            let (outbound, mut connected) = {
+                let peers = self.inner.peers.lock().unwrap();
+                let outbound = peers.values().filter(|peer| peer.is_outbound()).count();
+                let connected: Vec<String> = peers
+                    .values()
+                    .flat_map(|peer| peer.get_listen_addr())
+                    .collect();
+                (outbound, connected)
+            };
+            if outbound >= MAX_OUTBOUND_PEERS {
+                return;
+            }
+            connected.push(self.inner.node_address.clone());
+
+            let addr = {
+                let mut addr_book = self.inner.addr_book.lock().unwrap();
+                match addr_book.select(&connected, now, ADDR_RETRY_INTERVAL) {
+                    Some(addr) => {
+                        addr_book.mark_attempt(&addr, now);
+                        addr
+                    }
+                    None => return,
+                }
+            };
+            self.connect(addr);
+        }
+    }
 
-fn handle_get_data(request: &Vec<u8>, bc: &Blockchain) {
-    let payload: GetData = bincode::deserialize(&request[COMMAND_LENGTH..]).unwrap();
-    if payload.kind == "block" {
-        let block = bc.get_block(&payload.id);
-        if block.is_none() {
+    fn save_addr_book(&self) {
+        let addr_book = self.inner.addr_book.lock().unwrap();
+        if let Err(e) = addr_book.save_to_file(&self.inner.node_id) {
+            eprintln!("Failed to save the address book: {}", e);
+        }
+    }
+
+    fn save_mempool(&self) {
+        let mempool = self.inner.mempool.lock().unwrap();
+        if let Err(e) = mempool.save_to_file(&self.inner.node_id) {
+            eprintln!("Failed to save the mempool: {}", e);
+        }
+    }
+
+    // adds to the misbehaviour of a peer, banning its host once the threshold is reached, unless it is local
+    fn misbehaving(&self, peer: &str, misbehavior: Misbehavior) {
+        let mut peers = self.inner.peers.lock().unwrap();
+        let session = match peers.get_mut(peer) {
+            Some(session) => session,
+            None => return,
+        };
+        let score = session.add_misbehavior(misbehavior.score);
+        println!(
+            "Peer {} misbehaved: {}, score {}",
+            peer, misbehavior.reason, score
+        );
+        if score < BAN_THRESHOLD {
             return;
         }
-        send_block(payload.addr_from, &block.unwrap());
-    } else if payload.kind == "tx" {
-        let tx_id = hex::encode(payload.id);
-        unsafe {
-            let tx = MEMPOOL[&tx_id].clone();
-            send_tx(payload.addr_from, &tx);
+        session.disconnect();
+        /*every node of this machine shares the loopback address, banning it
+        would cut off the well behaved ones too, so local peers are only dropped */
+        if let Some(ip) = session.get_ip().filter(|ip| !ip.is_loopback()) {
+            let mut ban_list = ban_list::new_ban_list(&self.inner.node_id);
+            ban_list.ban(ip, BAN_DURATION, misbehavior.reason);
+            match ban_list.save_to_file(&self.inner.node_id) {
+                Ok(()) => println!("Banned {} for {} hours", ip, BAN_DURATION / 3600),
+                Err(e) => eprintln!("Failed to save the ban list: {}", e),
+            }
         }
     }
-}
 
-#[derive(Serialize, Deserialize)]
-struct Tx {
-    addr_from: String,
-    transaction: Vec<u8>,
-}
+    // the ban list is read on every check, so bans cleared from the CLI apply at once
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        ban_list::new_ban_list(&self.inner.node_id).is_banned(&ip)
+    }
 
-pub fn send_tx(addr: String, tx: &Transaction) {
-    let payload = bincode::serialize(&Tx {
-        addr_from: unsafe { NODE_ADDRESS.clone() },
-        transaction: bincode::serialize(tx).unwrap(),
-    })
-    .unwrap();
+    fn version_payload(&self) -> Vec<u8> {
+        bincode::serialize(&Version {
+            version: PROTOCOL_VERSION,
+            services: NODE_NETWORK,
+            user_agent: user_agent(),
+            start_height: self.inner.best_height.load(Ordering::SeqCst),
+            nonce: self.inner.local_nonce,
+            addr_from: self.inner.node_address.clone(),
+        })
+        .unwrap()
+    }
 
-    let mut request = command_to_bytes("tx");
-    request.extend(payload);
-    send_data(addr, request);
-}
+    // opens a session with addr unless there already is one
+    fn connect(&self, addr: String) {
+        self.find_or_connect(addr);
+    }
 
-fn handle_tx(request: &Vec<u8>, bc: &mut Blockchain) {
-    let payload: Tx = bincode::deserialize(&request[COMMAND_LENGTH..]).unwrap();
-    let tx_data = payload.transaction;
-    let tx: Transaction = bincode::deserialize(&tx_data).unwrap();
-    unsafe {
-        MEMPOOL.insert(hex::encode(tx.get_id()), tx.clone());
-        println!("insert into MEMPOOP key: {}", hex::encode(tx.get_id()));
+    // sends a message over the session with addr, connecting first if there is none
+    fn send_data(&self, addr: String, command: &str, payload: &[u8]) {
+        if let Some(key) = self.find_or_connect(addr) {
+            if let Some(peer) = self.inner.peers.lock().unwrap().get_mut(&key) {
+                peer.send(command, payload.to_vec());
+            }
+        }
+    }
+
+    /*returns the key of the session with addr, opening it and sending our
+    version if there is none. The peers are not locked while connecting,
+    so an unreachable node holds up no other session. */
+    fn find_or_connect(&self, addr: String) -> Option<String> {
+        if let Some(key) = find_session(&self.inner.peers.lock().unwrap(), &addr) {
+            return Some(key);
+        }
+        let events = self.inner.peer_events.lock().unwrap().clone()?;
+        if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
+            if self.is_banned(socket_addr.ip()) {
+                println!("Not connecting to banned {}", addr);
+                return None;
+            }
+        }
+
+        let connected = connect_to(&addr)
+            .and_then(|stream| Peer::spawn(stream, true, self.inner.transport.clone(), events));
+        let mut peer = match connected {
+            Ok(peer) => peer,
+            Err(e) => {
+                eprintln!("Failed to connect: {}", e);
+                println!("{} is not available", addr);
+                self.inner.addr_book.lock().unwrap().mark_failed(&addr);
+                return None;
+            }
+        };
+        let mut peers = self.inner.peers.lock().unwrap();
+        // another thread may have connected to addr in the meantime
+        if let Some(key) = find_session(&peers, &addr) {
+            peer.disconnect();
+            return Some(key);
+        }
+        peer.set_listen_addr(addr);
+        peer.send("version", self.version_payload());
+        let key = peer.get_addr();
+        peers.insert(key.clone(), peer);
+        Some(key)
+    }
+
+    // peer is the session the message arrived on, replies are sent back over it
+    fn handle_message(
+        &self,
+        peer: &str,
+        command: &str,
+        request: &[u8],
+        bc: &mut Blockchain,
+    ) -> Result<(), Misbehavior> {
+        println!("Received command: {} from {}", command, peer);
+
+        let handshake_complete = self
+            .inner
+            .peers
+            .lock()
+            .unwrap()
+            .get(peer)
+            .is_some_and(|session| session.is_handshake_complete());
+        if !handshake_complete && command != "version" && command != "verack" {
+            return Err(Misbehavior::new(
+                PROTOCOL_VIOLATION_SCORE,
+                format!("{} before the handshake", command),
+            ));
+        }
+
+        match command {
+            "addr" => self.handle_addr(peer, request),
+            "getaddr" => self.handle_get_addr(peer),
+            "block" => self.handle_block(peer, request, bc),
+            "cmpctblock" => self.handle_cmpct_block(peer, request, bc),
+            "getblocktxn" => self.handle_get_block_txn(peer, request, bc),
+            "blocktxn" => self.handle_block_txn(peer, request, bc),
+            "inv" => self.handle_inv(peer, request, bc),
+            "getheaders" => self.handle_get_headers(peer, request, bc),
+            "headers" => self.handle_headers(peer, request, bc),
+            "getdata" => self.handle_get_data(peer, request, bc),
+            "notfound" => self.handle_not_found(peer, request),
+            "tx" => self.handle_tx(peer, request, bc),
+            "version" => self.handle_version(peer, request, bc),
+            "verack" => self.handle_verack(peer),
+            "ping" => self.handle_ping(peer, request),
+            "pong" => self.handle_pong(peer, request),
+            _ => {
+                println!("Unknown command!");
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_version(
+        &self,
+        peer: &str,
+        request: &[u8],
+        bc: &Blockchain,
+    ) -> Result<(), Misbehavior> {
+        let payload: Version = decode("version", request)?;
+        let dialed_addr;
+        {
+            let mut peers = self.inner.peers.lock().unwrap();
+            let session = match peers.get_mut(peer) {
+                Some(session) => session,
+                None => return Ok(()),
+            };
+            if session.get_version().is_some() {
+                return Err(Misbehavior::new(
+                    PROTOCOL_VIOLATION_SCORE,
+                    "a second version".to_string(),
+                ));
+            }
+            if payload.nonce == self.inner.local_nonce {
+                println!("Connected to ourselves through {}, disconnecting", peer);
+                session.disconnect();
+                return Ok(());
+            }
+            if payload.version < MIN_PEER_PROTO_VERSION {
+                println!(
+                    "Peer {} uses obsolete protocol version {}, disconnecting",
+                    peer, payload.version
+                );
+                session.disconnect();
+                return Ok(());
+            }
 
-        if NODE_ADDRESS == KNOWN_NODES[0] {
-            for node in KNOWN_NODES.clone() {
-                if node != NODE_ADDRESS && node != payload.addr_from {
-                    send_inv(node, "tx", vec![tx.get_id().clone()]);
+            if !session.is_outbound() {
+                session.send("version", self.version_payload());
+            }
+            session.send("verack", vec![]);
+            /*the node we connected to learns our address from our version and
+            relays it, we learn about the nodes it knows */
+            if session.is_outbound() {
+                if !self.inner.node_address.is_empty() {
+                    session.send("addr", self.addr_payload(vec![self.local_net_address()]));
                 }
+                session.send("getaddr", vec![]);
+                dialed_addr = session.get_listen_addr();
+            } else {
+                dialed_addr = None;
             }
-        } else {
-            while MEMPOOL.len() >= 2 && MINING_ADDRESS.len() > 0 {
-                let mut txs: Vec<Transaction> = Vec::new();
+            // IPv6 addresses have several spellings, keep the canonical one
+            if let Ok(addr_from) = payload.addr_from.parse::<SocketAddr>() {
+                session.set_listen_addr(addr_from.to_string());
+            }
+            session.set_version(PeerVersion {
+                version: payload.version,
+                services: payload.services,
+                user_agent: payload.user_agent.clone(),
+                start_height: payload.start_height,
+            });
+        }
+
+        // only full nodes can serve us blocks
+        if payload.services & NODE_NETWORK != 0 && bc.get_best_height() < payload.start_height {
+            self.send_get_headers(peer.to_string(), bc.get_block_locator(), vec![]);
+        }
+
+        // a node we could connect to is a good address to keep
+        if let Some(addr) = dialed_addr {
+            let now = chrono::Utc::now().timestamp();
+            let mut addr_book = self.inner.addr_book.lock().unwrap();
+            addr_book.add(addr.clone(), now);
+            addr_book.mark_good(&addr, now);
+        }
+        Ok(())
+    }
+
+    fn handle_verack(&self, peer: &str) -> Result<(), Misbehavior> {
+        let mut peers = self.inner.peers.lock().unwrap();
+        if let Some(session) = peers.get_mut(peer) {
+            session.set_verack_received();
+            if let Some(version) = session.get_version() {
+                println!(
+                    "Handshake with {} complete: {} protocol {} services [{}] height {}",
+                    peer,
+                    version.user_agent,
+                    version.version,
+                    service_names(version.services),
+                    version.start_height
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // pings carry an 8 byte nonce which the pong echoes
+    fn handle_ping(&self, peer: &str, request: &[u8]) -> Result<(), Misbehavior> {
+        self.send_data(peer.to_string(), "pong", request);
+        Ok(())
+    }
+
+    fn handle_pong(&self, peer: &str, request: &[u8]) -> Result<(), Misbehavior> {
+        let nonce = match request.try_into() {
+            Ok(nonce) => u64::from_le_bytes(nonce),
+            Err(_) => {
+                return Err(Misbehavior::new(
+                    MALFORMED_MESSAGE_SCORE,
+                    "malformed pong".to_string(),
+                ))
+            }
+        };
+        let mut peers = self.inner.peers.lock().unwrap();
+        if let Some(latency) = peers
+            .get_mut(peer)
+            .and_then(|session| session.pong_received(nonce))
+        {
+            println!("Peer {} latency: {} ms", peer, latency.as_millis());
+        }
+        Ok(())
+    }
+
+    fn send_get_headers(&self, addr: String, locator: Vec<Vec<u8>>, stop_hash: Vec<u8>) {
+        let payload = bincode::serialize(&GetHeaders {
+            addr_from: self.inner.node_address.clone(),
+            locator,
+            stop_hash,
+        })
+        .unwrap();
+
+        self.send_data(addr, "getheaders", &payload);
+    }
+
+    fn handle_get_headers(
+        &self,
+        peer: &str,
+        request: &[u8],
+        bc: &Blockchain,
+    ) -> Result<(), Misbehavior> {
+        let payload: GetHeaders = decode("getheaders", request)?;
+        let headers = bc.find_headers(&payload.locator, &payload.stop_hash, MAX_HEADERS_RESULTS);
+        self.send_headers(peer.to_string(), headers);
+        Ok(())
+    }
+
+    fn send_headers(&self, addr: String, headers: Vec<BlockHeader>) {
+        let payload = bincode::serialize(&Headers {
+            addr_from: self.inner.node_address.clone(),
+            headers,
+        })
+        .unwrap();
+
+        self.send_data(addr, "headers", &payload);
+    }
+
+    /*Headers are checked to form a chain with valid proof of work on top of
+    a known block before any body is requested. Bodies are then downloaded
+    one by one in ascending height order, so parents always precede children. */
+    fn handle_headers(
+        &self,
+        peer: &str,
+        request: &[u8],
+        bc: &Blockchain,
+    ) -> Result<(), Misbehavior> {
+        let payload: Headers = decode("headers", request)?;
+        if payload.headers.is_empty() {
+            return Ok(());
+        }
+        if payload.headers.len() > MAX_HEADERS_RESULTS {
+            return Err(Misbehavior::new(
+                MALFORMED_MESSAGE_SCORE,
+                format!("{} headers in one message", payload.headers.len()),
+            ));
+        }
+
+        let mut headers_in_transit = self.inner.headers_in_transit.lock().unwrap();
+        let mut prev_hash = payload.headers[0].get_prev_block_hash();
+        let mut prev_height = match headers_in_transit
+            .iter()
+            .find(|header| header.get_hash() == prev_hash)
+        {
+            Some(header) => header.get_height(),
+            None => match bc.get_block(&prev_hash) {
+                Some(block) => block.get_height(),
+                None => {
+                    // not proof of bad faith, the peer may be on a chain we do not know yet
+                    println!("Received headers that do not connect to our chain");
+                    return Ok(());
+                }
+            },
+        };
+        for header in &payload.headers {
+            if header.get_prev_block_hash() != prev_hash
+                || header.get_height() != prev_height + 1
+                || !ProofOfWork::from_header(header.clone()).validate()
+            {
+                return Err(Misbehavior::new(
+                    INVALID_HEADER_SCORE,
+                    format!("invalid header {}", hex::encode(header.get_hash())),
+                ));
+            }
+            prev_hash = header.get_hash();
+            prev_height = header.get_height();
+        }
+
+        {
+            let orphan_blocks = self.inner.orphan_blocks.lock().unwrap();
+            for header in payload.headers.iter() {
+                let hash = header.get_hash();
+                if !bc.has_block(&hash)
+                    && !orphan_blocks.contains(&hash)
+                    && !headers_in_transit.iter().any(|h| h.get_hash() == hash)
+                {
+                    headers_in_transit.push(header.clone());
+                }
+            }
+            println!(
+                "Received {} headers, {} blocks left to download",
+                payload.headers.len(),
+                headers_in_transit.len()
+            );
+        }
+        drop(headers_in_transit);
+        // a peer sending headers can serve their blocks
+        if let Some(session) = self.inner.peers.lock().unwrap().get_mut(peer) {
+            session.update_best_known_height(prev_height);
+        }
+        self.request_blocks();
 
-                for (_, tx) in MEMPOOL.clone() {
-                    if bc.verify_transaction(&tx) {
-                        txs.push(tx.clone());
+        // a full batch means the peer has more headers for us
+        if payload.headers.len() == MAX_HEADERS_RESULTS {
+            let mut locator = vec![prev_hash];
+            locator.extend(bc.get_block_locator());
+            self.send_get_headers(peer.to_string(), locator, vec![]);
+        }
+        Ok(())
+    }
+
+    // announces items to every full node peer except the one they came from
+    fn relay_inv(&self, from: Option<&str>, kind: &str, items: Vec<Vec<u8>>) {
+        let payload = bincode::serialize(&Inv {
+            addr_from: self.inner.node_address.clone(),
+            inv_type: kind.to_string(),
+            items,
+        })
+        .unwrap();
+
+        let mut peers = self.inner.peers.lock().unwrap();
+        for (addr, peer) in peers.iter_mut() {
+            let full_node = peer
+                .get_version()
+                .is_some_and(|version| version.services & NODE_NETWORK != 0);
+            if Some(addr.as_str()) != from && peer.is_handshake_complete() && full_node {
+                peer.send("inv", payload.clone());
+            }
+        }
+    }
+
+    fn handle_inv(&self, peer: &str, request: &[u8], bc: &Blockchain) -> Result<(), Misbehavior> {
+        let payload: Inv = decode("inv", request)?;
+        if payload.items.len() > MAX_INV_SIZE {
+            return Err(Misbehavior::new(
+                MALFORMED_MESSAGE_SCORE,
+                format!("{} items in one inv", payload.items.len()),
+            ));
+        }
+        if payload.inv_type == "block" {
+            // announced blocks are fetched through their headers
+            if let Some(block_hash) = payload.items.last() {
+                if !bc.has_block(block_hash) {
+                    self.send_get_headers(
+                        peer.to_string(),
+                        bc.get_block_locator(),
+                        block_hash.clone(),
+                    );
+                }
+            }
+        } else if payload.inv_type == "tx" {
+            self.request_txs(peer, payload.items);
+        }
+        Ok(())
+    }
+
+    // asks a peer for the transactions not seen yet, those already asked from another peer are asked from this one if that fails
+    fn request_txs(&self, peer: &str, tx_ids: Vec<Vec<u8>>) {
+        let mut wanted = vec![];
+        {
+            let mempool = self.inner.mempool.lock().unwrap();
+            let orphan_txs = self.inner.orphan_txs.lock().unwrap();
+            let recent_txs = self.inner.recent_txs.lock().unwrap();
+            let mut tx_requests = self.inner.tx_requests.lock().unwrap();
+            for tx_id in tx_ids {
+                if tx_requests.count_tracked(peer) >= MAX_PEER_TX_ANNOUNCEMENTS {
+                    break;
+                }
+                if recent_txs.contains(&tx_id)
+                    || mempool.contains(&tx_id)
+                    || orphan_txs.contains(&tx_id)
+                {
+                    continue;
+                }
+                if tx_requests.is_requested(&tx_id) {
+                    tx_requests.announce(tx_id, peer);
+                } else {
+                    tx_requests.request(tx_id.clone(), peer);
+                    wanted.push(tx_id);
+                }
+            }
+        }
+        if !wanted.is_empty() {
+            self.send_get_data(peer.to_string(), "tx", wanted);
+        }
+    }
+
+    fn send_get_data(&self, addr: String, kind: &str, items: Vec<Vec<u8>>) {
+        for chunk in items.chunks(MAX_GETDATA_SIZE) {
+            let payload = bincode::serialize(&GetData {
+                addr_from: self.inner.node_address.clone(),
+                kind: kind.to_string(),
+                items: chunk.to_vec(),
+            })
+            .unwrap();
+
+            self.send_data(addr.clone(), "getdata", &payload);
+        }
+    }
+
+    // asks the next peers that announced them for transactions a request failed for
+    fn retry_tx_requests(&self, tx_ids: Vec<Vec<u8>>) {
+        let mut batches: HashMap<String, Vec<Vec<u8>>> = HashMap::new();
+        {
+            let mut tx_requests = self.inner.tx_requests.lock().unwrap();
+            for tx_id in tx_ids {
+                if let Some(peer) = tx_requests.next_announcer(&tx_id) {
+                    tx_requests.request(tx_id.clone(), &peer);
+                    batches.entry(peer).or_default().push(tx_id);
+                }
+            }
+        }
+        for (peer, tx_ids) in batches {
+            self.send_get_data(peer, "tx", tx_ids);
+        }
+    }
+
+    /*Spreads the blocks of the download window over the peers that can
+    serve them, the least busy peer first, up to a limit per peer. */
+    fn request_blocks(&self) {
+        let window: Vec<(Vec<u8>, usize)> = self
+            .inner
+            .headers_in_transit
+            .lock()
+            .unwrap()
+            .iter()
+            .take(BLOCK_DOWNLOAD_WINDOW)
+            .map(|header| (header.get_hash(), header.get_height()))
+            .collect();
+        if window.is_empty() {
+            return;
+        }
+
+        let mut batches: HashMap<String, Vec<Vec<u8>>> = HashMap::new();
+        {
+            let blocks_downloaded = self.inner.blocks_downloaded.lock().unwrap();
+            let peers = self.inner.peers.lock().unwrap();
+            let mut block_requests = self.inner.block_requests.lock().unwrap();
+            let mut candidates: Vec<(&String, usize, usize)> = peers
+                .iter()
+                .filter(|(_, peer)| can_serve_block(peer, 0))
+                .map(|(addr, peer)| {
+                    (
+                        addr,
+                        peer.get_best_known_height(),
+                        block_requests.count_for(addr),
+                    )
+                })
+                .collect();
+            for (block_hash, height) in window {
+                if blocks_downloaded.contains_key(&block_hash)
+                    || block_requests.is_requested(&block_hash)
+                {
+                    continue;
+                }
+                let candidate = candidates
+                    .iter_mut()
+                    .filter(|(_, best_height, in_flight)| {
+                        *best_height >= height && *in_flight < MAX_BLOCKS_IN_FLIGHT_PER_PEER
+                    })
+                    .min_by_key(|(_, _, in_flight)| *in_flight);
+                if let Some((addr, _, in_flight)) = candidate {
+                    *in_flight += 1;
+                    block_requests.request(block_hash.clone(), addr);
+                    batches
+                        .entry(addr.to_string())
+                        .or_default()
+                        .push(block_hash);
+                }
+            }
+        }
+        for (peer, block_hashes) in batches {
+            self.send_get_data(peer, "block", block_hashes);
+        }
+    }
+
+    fn handle_get_data(
+        &self,
+        peer: &str,
+        request: &[u8],
+        bc: &Blockchain,
+    ) -> Result<(), Misbehavior> {
+        let payload: GetData = decode("getdata", request)?;
+        if payload.items.len() > MAX_GETDATA_SIZE {
+            return Err(Misbehavior::new(
+                MALFORMED_MESSAGE_SCORE,
+                format!("{} items in one getdata", payload.items.len()),
+            ));
+        }
+        let mut not_found = vec![];
+        for id in payload.items {
+            // a peer that does not keep up is told to ask someone else for the rest
+            if self.send_queue_size(peer) > SEND_QUEUE_BUDGET {
+                not_found.push(id);
+                continue;
+            }
+            if payload.kind == "block" {
+                match bc.get_block(&id) {
+                    Some(block) => self.send_block(peer.to_string(), &block),
+                    None => not_found.push(id),
+                }
+            } else if payload.kind == "tx" {
+                let tx = self.inner.mempool.lock().unwrap().get(&id).cloned();
+                match tx {
+                    Some(tx) => self.send_tx(peer.to_string(), &tx),
+                    None => not_found.push(id),
+                }
+            }
+        }
+        // tells the peer to ask someone else instead of waiting for the timeout
+        if !not_found.is_empty() {
+            let payload = bincode::serialize(&NotFound {
+                addr_from: self.inner.node_address.clone(),
+                kind: payload.kind,
+                items: not_found,
+            })
+            .unwrap();
+            self.send_data(peer.to_string(), "notfound", &payload);
+        }
+        Ok(())
+    }
+
+    fn send_queue_size(&self, peer: &str) -> usize {
+        self.inner
+            .peers
+            .lock()
+            .unwrap()
+            .get(peer)
+            .map_or(0, |session| session.get_send_queue_size())
+    }
+
+    fn handle_not_found(&self, peer: &str, request: &[u8]) -> Result<(), Misbehavior> {
+        let payload: NotFound = decode("notfound", request)?;
+        if payload.items.len() > MAX_INV_SIZE {
+            return Err(Misbehavior::new(
+                MALFORMED_MESSAGE_SCORE,
+                format!("{} items in one notfound", payload.items.len()),
+            ));
+        }
+        if payload.kind == "tx" {
+            let mut tx_requests = self.inner.tx_requests.lock().unwrap();
+            let failed: Vec<Vec<u8>> = payload
+                .items
+                .into_iter()
+                .filter(|tx_id| tx_requests.not_found(tx_id, peer))
+                .collect();
+            drop(tx_requests);
+            self.retry_tx_requests(failed);
+        } else if payload.kind == "block" {
+            let mut failed = vec![];
+            {
+                let mut block_requests = self.inner.block_requests.lock().unwrap();
+                for block_hash in &payload.items {
+                    if block_requests.not_found(block_hash, peer) {
+                        failed.push(block_hash.clone());
                     }
                 }
+            }
+            // so the scheduler does not ask the peer again
+            let heights: Vec<usize> = self
+                .inner
+                .headers_in_transit
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|header| failed.contains(&header.get_hash()))
+                .map(|header| header.get_height())
+                .collect();
+            if let Some(session) = self.inner.peers.lock().unwrap().get_mut(peer) {
+                for height in heights {
+                    session.limit_best_known_height(height);
+                }
+            }
+            self.request_blocks();
+        }
+        Ok(())
+    }
+
+    fn send_tx(&self, addr: String, tx: &Transaction) {
+        let payload = bincode::serialize(&Tx {
+            addr_from: self.inner.node_address.clone(),
+            transaction: bincode::serialize(tx).unwrap(),
+        })
+        .unwrap();
+
+        self.send_data(addr, "tx", &payload);
+    }
+
+    /*Every node checks a new transaction and announces it to all its
+    peers but the sender. The ids of received transactions are remembered,
+    so a transaction coming back over another peer is dropped. */
+    fn handle_tx(
+        &self,
+        peer: &str,
+        request: &[u8],
+        bc: &mut Blockchain,
+    ) -> Result<(), Misbehavior> {
+        let payload: Tx = decode("tx", request)?;
+        let tx: Transaction = decode("tx", &payload.transaction)?;
+        self.inner
+            .tx_requests
+            .lock()
+            .unwrap()
+            .received(&tx.get_id());
+        if self.inner.recent_txs.lock().unwrap().contains(&tx.get_id()) {
+            return Ok(());
+        }
+        self.sync_mempool(bc);
+        let tx_id = tx.get_id();
+        let utxo_set = UtxoSet::new(bc.clone());
+        if self.accept_tx(peer, tx, &utxo_set)? {
+            self.accept_orphans(vec![tx_id], &utxo_set);
+        }
+        Ok(())
+    }
+
+    /*adds a transaction to the mempool and relays it, returns whether it
+    was added. A transaction spending outputs the node does not know waits
+    in the orphan pool, and its parents are asked from the peer. Only added
+    and invalid transactions are remembered as seen, the others may be
+    accepted later, once the pool or the chain changed. */
+    fn accept_tx(
+        &self,
+        peer: &str,
+        tx: Transaction,
+        utxo_set: &UtxoSet,
+    ) -> Result<bool, Misbehavior> {
+        let tx_id = tx.get_id();
+        let mut mempool = self.inner.mempool.lock().unwrap();
+        match mempool.add(tx.clone(), utxo_set) {
+            Ok(replaced) => {
+                for replaced_tx in replaced {
+                    println!(
+                        "Transaction {} replaced {}",
+                        hex::encode(&tx_id),
+                        hex::encode(replaced_tx.get_id())
+                    );
+                }
+            }
+            Err(Rejection::Invalid(reason)) => {
+                drop(mempool);
+                self.inner.recent_txs.lock().unwrap().insert(tx_id.clone());
+                return Err(Misbehavior::new(
+                    INVALID_TX_SCORE,
+                    format!("invalid transaction {}: {}", hex::encode(&tx_id), reason),
+                ));
+            }
+            // ids do not cover unlocking scripts, the valid copy must still get in
+            Err(Rejection::Mutated(reason)) => {
+                return Err(Misbehavior::new(
+                    INVALID_TX_SCORE,
+                    format!("invalid transaction {}: {}", hex::encode(&tx_id), reason),
+                ))
+            }
+            Err(Rejection::MissingInputs) => {
+                drop(mempool);
+                // an output of a confirmed transaction that is not in the UTXO set was spent
+                if let Some(vin) = tx.get_vin().into_iter().find(|vin| {
+                    utxo_set
+                        .find_output(&vin.get_txid(), vin.get_vout())
+                        .is_none()
+                        && utxo_set.is_confirmed(&vin.get_txid())
+                }) {
+                    println!(
+                        "Rejected transaction {}: double spends output {} of confirmed {}",
+                        hex::encode(&tx_id),
+                        vin.get_vout(),
+                        hex::encode(vin.get_txid())
+                    );
+                    return Ok(false);
+                }
+                let parents: Vec<Vec<u8>> = tx.get_vin().iter().map(|vin| vin.get_txid()).collect();
+                let mut orphan_txs = self.inner.orphan_txs.lock().unwrap();
+                if orphan_txs.add(tx, peer) {
+                    println!(
+                        "Transaction {} is an orphan, waiting for its parents ({} orphans)",
+                        hex::encode(&tx_id),
+                        orphan_txs.len()
+                    );
+                    drop(orphan_txs);
+                    self.request_txs(peer, parents);
+                } else {
+                    drop(orphan_txs);
+                    println!(
+                        "Rejected transaction {}: orphan too large",
+                        hex::encode(&tx_id)
+                    );
+                }
+                return Ok(false);
+            }
+            Err(rejection) => {
+                println!(
+                    "Rejected transaction {}: {}",
+                    hex::encode(&tx_id),
+                    rejection
+                );
+                return Ok(false);
+            }
+        }
+        println!(
+            "insert into MEMPOOP key: {} fee: {}, {} transactions pending ({} bytes)",
+            hex::encode(&tx_id),
+            mempool.get_fee(&tx_id).unwrap_or_default(),
+            mempool.len(),
+            mempool.get_total_size()
+        );
+        drop(mempool);
+        self.inner.recent_txs.lock().unwrap().insert(tx_id.clone());
+        self.relay_inv(Some(peer), "tx", vec![tx_id]);
+        Ok(true)
+    }
 
-                if txs.len() == 0 {
-                    println!("All transactions are invalid! Waiting for new ones...");
-                    return;
+    // tries the orphans spending outputs of transactions that just entered the mempool
+    fn accept_orphans(&self, parent_ids: Vec<Vec<u8>>, utxo_set: &UtxoSet) {
+        let mut parent_ids = parent_ids;
+        while let Some(parent_id) = parent_ids.pop() {
+            let children = self
+                .inner
+                .orphan_txs
+                .lock()
+                .unwrap()
+                .take_children(&parent_id);
+            for (tx, from) in children {
+                let tx_id = tx.get_id();
+                match self.accept_tx(&from, tx, utxo_set) {
+                    Ok(true) => parent_ids.push(tx_id),
+                    Ok(false) => {}
+                    Err(misbehavior) => self.misbehaving(&from, misbehavior),
                 }
+            }
+        }
+    }
+
+    fn send_block(&self, addr: String, block: &Block) {
+        let payload = bincode::serialize(&BlockSend {
+            addr_from: self.inner.node_address.clone(),
+            block: block.serialize(),
+        })
+        .unwrap();
+
+        self.send_data(addr, "block", &payload);
+    }
+
+    fn handle_block(
+        &self,
+        peer: &str,
+        request: &[u8],
+        bc: &mut Blockchain,
+    ) -> Result<(), Misbehavior> {
+        let payload: BlockSend = decode("block", request)?;
+        let block: Block = decode("block", &payload.block)?;
+        self.process_block(peer, block, bc)
+    }
+
+    // checks a block received in full or rebuilt from a compact block and adds it to the chain
+    fn process_block(
+        &self,
+        peer: &str,
+        block: Block,
+        bc: &mut Blockchain,
+    ) -> Result<(), Misbehavior> {
+        // the merkle root of the proof of work needs at least one transaction
+        if block.get_transactions().is_empty() {
+            return Err(Misbehavior::new(
+                INVALID_BLOCK_SCORE,
+                "block without transactions".to_string(),
+            ));
+        }
+        if !ProofOfWork::new_proof_of_work(block.clone()).validate() {
+            return Err(Misbehavior::new(
+                INVALID_BLOCK_SCORE,
+                format!(
+                    "block {} with invalid proof of work",
+                    hex::encode(block.get_hash())
+                ),
+            ));
+        }
+
+        println!("Received a new block!");
+        let block_hash = block.get_hash();
+        self.inner
+            .block_requests
+            .lock()
+            .unwrap()
+            .received(&block_hash);
+        self.inner
+            .partial_blocks
+            .lock()
+            .unwrap()
+            .remove(&block_hash);
+
+        // downloaded blocks wait until all blocks below them arrived
+        let validated_header = self
+            .inner
+            .headers_in_transit
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|header| header.get_hash() == block_hash)
+            .cloned();
+        if let Some(header) = validated_header {
+            // the hash does not cover the height, so the whole header must match
+            if block.get_header() != header {
+                return Err(Misbehavior::new(
+                    INVALID_BLOCK_SCORE,
+                    format!(
+                        "block {} does not match its header",
+                        hex::encode(&block_hash)
+                    ),
+                ));
+            }
+            self.inner
+                .blocks_downloaded
+                .lock()
+                .unwrap()
+                .insert(block_hash, (block, peer.to_string()));
+            self.connect_downloaded_blocks(peer, bc);
+            return Ok(());
+        }
+
+        let prev_block_hash = block.get_prev_block_hash();
+        if !prev_block_hash.is_empty() && !bc.has_block(&prev_block_hash) {
+            let mut orphan_blocks = self.inner.orphan_blocks.lock().unwrap();
+            orphan_blocks.add(block);
+            println!(
+                "Block {} is an orphan, {} orphans in the pool",
+                hex::encode(&block_hash),
+                orphan_blocks.len()
+            );
+            // ask the sender for the blocks between our chain and the orphan
+            self.send_get_headers(peer.to_string(), bc.get_block_locator(), block_hash);
+            return Ok(());
+        }
+
+        let tip_hash = bc.get_tip_hash();
+        let connected = self.connect_block(block, bc);
+        // a new tip is passed on, so blocks reach nodes the miner is not connected to
+        if bc.get_tip_hash() != tip_hash {
+            self.relay_tip(peer, bc);
+        }
+        self.connect_downloaded_blocks(peer, bc);
+        connected
+    }
+
+    // announces a new tip to every full node peer but the one it came from
+    fn relay_tip(&self, from: &str, bc: &Blockchain) {
+        if let Some(block) = bc.get_block(&bc.get_tip_hash()) {
+            self.relay_block(Some(from), &block);
+        }
+    }
+
+    /*announces a block as a compact block, so peers only download the
+    transactions they have not seen yet */
+    fn relay_block(&self, from: Option<&str>, block: &Block) {
+        let payload = bincode::serialize(&CmpctBlock {
+            addr_from: self.inner.node_address.clone(),
+            block: CompactBlock::new(block, random_nonce()),
+        })
+        .unwrap();
+
+        let mut peers = self.inner.peers.lock().unwrap();
+        for (addr, peer) in peers.iter_mut() {
+            let full_node = peer
+                .get_version()
+                .is_some_and(|version| version.services & NODE_NETWORK != 0);
+            if Some(addr.as_str()) != from && peer.is_handshake_complete() && full_node {
+                peer.send("cmpctblock", payload.clone());
+            }
+        }
+    }
+
+    /*rebuilds an announced block from the mempool and asks the sender
+    for the transactions that are missing */
+    fn handle_cmpct_block(
+        &self,
+        peer: &str,
+        request: &[u8],
+        bc: &mut Blockchain,
+    ) -> Result<(), Misbehavior> {
+        let payload: CmpctBlock = decode("cmpctblock", request)?;
+        let compact = payload.block;
+        let header = compact.header.clone();
+        let block_hash = header.get_hash();
+        if compact.tx_count() == 0 {
+            return Err(Misbehavior::new(
+                INVALID_BLOCK_SCORE,
+                "block without transactions".to_string(),
+            ));
+        }
+        if !ProofOfWork::from_header(header.clone()).validate() {
+            return Err(Misbehavior::new(
+                INVALID_HEADER_SCORE,
+                format!(
+                    "compact block {} with invalid proof of work",
+                    hex::encode(&block_hash)
+                ),
+            ));
+        }
+        if let Some(session) = self.inner.peers.lock().unwrap().get_mut(peer) {
+            session.update_best_known_height(header.get_height());
+        }
+        if bc.has_block(&block_hash)
+            || self
+                .inner
+                .partial_blocks
+                .lock()
+                .unwrap()
+                .contains_key(&block_hash)
+        {
+            return Ok(());
+        }
+        // blocks we cannot connect yet go the way of headers-first sync
+        if !bc.has_block(&header.get_prev_block_hash()) {
+            self.send_get_headers(peer.to_string(), bc.get_block_locator(), block_hash);
+            return Ok(());
+        }
+
+        let partial = {
+            let mempool = self.inner.mempool.lock().unwrap();
+            PartialBlock::new(&compact, mempool.transactions())
+        }
+        .map_err(|e| {
+            Misbehavior::new(
+                MALFORMED_MESSAGE_SCORE,
+                format!("compact block {}: {}", hex::encode(&block_hash), e),
+            )
+        })?;
+        let missing = partial.missing();
+        if missing.is_empty() {
+            return self.complete_partial_block(peer, partial, bc);
+        }
+
+        let mut partial_blocks = self.inner.partial_blocks.lock().unwrap();
+        if partial_blocks.len() >= MAX_PARTIAL_BLOCKS {
+            drop(partial_blocks);
+            self.send_get_data(peer.to_string(), "block", vec![block_hash]);
+            return Ok(());
+        }
+        println!(
+            "Rebuilding block {}, {} of {} transactions missing",
+            hex::encode(&block_hash),
+            missing.len(),
+            compact.tx_count()
+        );
+        partial_blocks.insert(block_hash.clone(), (peer.to_string(), partial));
+        drop(partial_blocks);
+        let payload = bincode::serialize(&GetBlockTxn {
+            addr_from: self.inner.node_address.clone(),
+            block_hash,
+            indexes: missing,
+        })
+        .unwrap();
+        self.send_data(peer.to_string(), "getblocktxn", &payload);
+        Ok(())
+    }
 
-                let cb_tx = transaction::new_coinbase_tx(MINING_ADDRESS.clone(), "".to_string());
-                txs.push(cb_tx);
+    // a rebuilt block that does not match its header had a short id collision and is downloaded in full
+    fn complete_partial_block(
+        &self,
+        peer: &str,
+        partial: PartialBlock,
+        bc: &mut Blockchain,
+    ) -> Result<(), Misbehavior> {
+        let block = match partial.to_block() {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+        if !ProofOfWork::new_proof_of_work(block.clone()).validate() {
+            println!(
+                "Rebuilt block {} does not match its header, downloading it",
+                hex::encode(partial.get_hash())
+            );
+            self.send_get_data(peer.to_string(), "block", vec![partial.get_hash()]);
+            return Ok(());
+        }
+        self.process_block(peer, block, bc)
+    }
 
-                let new_block = bc.mine_block(txs.clone());
-                let utxo_set = UtxoSet::new(bc.clone());
-                utxo_set.reindex();
-                println!("New block is mined!");
+    fn handle_get_block_txn(
+        &self,
+        peer: &str,
+        request: &[u8],
+        bc: &Blockchain,
+    ) -> Result<(), Misbehavior> {
+        let payload: GetBlockTxn = decode("getblocktxn", request)?;
+        let block = match bc.get_block(&payload.block_hash) {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+        let block_txs = block.get_transactions();
+        let mut transactions = vec![];
+        for index in payload.indexes {
+            match block_txs.get(index as usize) {
+                Some(tx) => transactions.push(tx.clone()),
+                None => {
+                    return Err(Misbehavior::new(
+                        MALFORMED_MESSAGE_SCORE,
+                        format!(
+                            "getblocktxn index {} of block {} with {} transactions",
+                            index,
+                            hex::encode(&payload.block_hash),
+                            block_txs.len()
+                        ),
+                    ))
+                }
+            }
+        }
+        let payload = bincode::serialize(&BlockTxn {
+            addr_from: self.inner.node_address.clone(),
+            block_hash: payload.block_hash,
+            transactions,
+        })
+        .unwrap();
+        self.send_data(peer.to_string(), "blocktxn", &payload);
+        Ok(())
+    }
 
-                for tx in txs {
-                    let tx_id = hex::encode(tx.get_id());
-                    MEMPOOL.remove(&tx_id);
+    fn handle_block_txn(
+        &self,
+        peer: &str,
+        request: &[u8],
+        bc: &mut Blockchain,
+    ) -> Result<(), Misbehavior> {
+        let payload: BlockTxn = decode("blocktxn", request)?;
+        let mut partial = {
+            let mut partial_blocks = self.inner.partial_blocks.lock().unwrap();
+            match partial_blocks.get(&payload.block_hash) {
+                Some((from, _)) if from == peer => {}
+                _ => return Ok(()), // not asked from this peer, or completed meanwhile
+            }
+            partial_blocks.remove(&payload.block_hash).unwrap().1
+        };
+        partial.fill(payload.transactions).map_err(|e| {
+            Misbehavior::new(
+                MALFORMED_MESSAGE_SCORE,
+                format!("blocktxn for {}: {}", hex::encode(&payload.block_hash), e),
+            )
+        })?;
+        self.complete_partial_block(peer, partial, bc)
+    }
+
+    // connects the downloaded blocks in height order, as far as there is no gap
+    fn connect_downloaded_blocks(&self, peer: &str, bc: &mut Blockchain) {
+        let tip_hash = bc.get_tip_hash();
+        loop {
+            let (block, from) = {
+                let mut headers_in_transit = self.inner.headers_in_transit.lock().unwrap();
+                headers_in_transit.retain(|header| !bc.has_block(&header.get_hash()));
+                let block_hash = match headers_in_transit.first() {
+                    Some(header) => header.get_hash(),
+                    None => break,
+                };
+                match self
+                    .inner
+                    .blocks_downloaded
+                    .lock()
+                    .unwrap()
+                    .remove(&block_hash)
+                {
+                    Some(downloaded) => downloaded,
+                    None => break,
                 }
+            };
+            if let Err(misbehavior) = self.connect_block(block, bc) {
+                self.misbehaving(&from, misbehavior);
+            }
+        }
+        if bc.get_tip_hash() != tip_hash {
+            self.relay_tip(peer, bc);
+        }
+
+        if self.inner.headers_in_transit.lock().unwrap().is_empty() {
+            self.inner.blocks_downloaded.lock().unwrap().clear();
+            self.sync_mempool(bc);
+        } else {
+            self.request_blocks();
+        }
+    }
+
+    // updates the UTXO set and the mempool after the tip moved
+    fn sync_mempool(&self, bc: &Blockchain) {
+        let mut mempool = self.inner.mempool.lock().unwrap();
+        if mempool.get_tip() == bc.get_tip_hash() {
+            return;
+        }
+        let utxo_set = UtxoSet::new(bc.clone());
+        utxo_set.update_to_tip();
+        for tx in mempool.sync_with_chain(&utxo_set) {
+            println!(
+                "Removed transaction {} from the mempool, it conflicts with the chain",
+                hex::encode(tx.get_id())
+            );
+        }
+        drop(mempool);
 
-                for node in KNOWN_NODES.clone() {
-                    if node != NODE_ADDRESS {
-                        send_inv(node, "block", vec![new_block.get_hash().clone()]);
+        // the new blocks may confirm the parents of orphans
+        let orphans = self.inner.orphan_txs.lock().unwrap().take_all();
+        let mut accepted = vec![];
+        for (tx, from) in orphans {
+            let tx_id = tx.get_id();
+            match self.accept_tx(&from, tx, &utxo_set) {
+                Ok(true) => accepted.push(tx_id),
+                Ok(false) => {}
+                Err(misbehavior) => self.misbehaving(&from, misbehavior),
+            }
+        }
+        self.accept_orphans(accepted, &utxo_set);
+    }
+
+    /*adds a block to the chain, followed by the orphans waiting for it.
+    An invalid block is dropped with the headers and orphans built on it,
+    the error is for the peer that sent it. */
+    fn connect_block(&self, block: Block, bc: &mut Blockchain) -> Result<(), Misbehavior> {
+        let first_hash = block.get_hash();
+        let mut result = Ok(());
+        let mut invalid = vec![];
+        {
+            let mut orphan_blocks = self.inner.orphan_blocks.lock().unwrap();
+            let mut blocks = vec![block];
+            while let Some(block) = blocks.pop() {
+                let block_hash = block.get_hash();
+                if let Err(e) = bc.check_block(&block) {
+                    println!("Rejected block {}: {}", hex::encode(&block_hash), e);
+                    // orphans were sent by other peers, only the first block is the caller's
+                    if block_hash == first_hash {
+                        result = Err(Misbehavior::new(
+                            INVALID_BLOCK_SCORE,
+                            format!("invalid block {}: {}", hex::encode(&block_hash), e),
+                        ));
+                    }
+                    let mut descendants = vec![block_hash];
+                    while let Some(hash) = descendants.pop() {
+                        descendants.extend(
+                            orphan_blocks
+                                .take_children(&hash)
+                                .iter()
+                                .map(|child| child.get_hash()),
+                        );
+                        invalid.push(hash);
                     }
+                    continue;
+                }
+                bc.add_block(block);
+                // the next block is checked against the UTXO set of this one
+                UtxoSet::new(bc.clone()).update_to_tip();
+                blocks.extend(orphan_blocks.take_children(&block_hash));
+            }
+        }
+        if !invalid.is_empty() {
+            self.forget_blocks(invalid);
+        }
+        result
+    }
+
+    /*stops downloading invalid blocks and the blocks whose headers build on
+    them, otherwise their headers would stay first in line forever */
+    fn forget_blocks(&self, block_hashes: Vec<Vec<u8>>) {
+        let mut forgotten: HashSet<Vec<u8>> = block_hashes.into_iter().collect();
+        // ascending by height, so a parent is forgotten before its children
+        self.inner
+            .headers_in_transit
+            .lock()
+            .unwrap()
+            .retain(|header| {
+                if forgotten.contains(&header.get_hash())
+                    || forgotten.contains(&header.get_prev_block_hash())
+                {
+                    forgotten.insert(header.get_hash());
+                    return false;
                 }
+                true
+            });
+        let mut blocks_downloaded = self.inner.blocks_downloaded.lock().unwrap();
+        let mut block_requests = self.inner.block_requests.lock().unwrap();
+        for block_hash in &forgotten {
+            blocks_downloaded.remove(block_hash);
+            block_requests.received(block_hash);
+        }
+    }
 
-                if MEMPOOL.len() > 0 {
+    fn handle_get_addr(&self, peer: &str) -> Result<(), Misbehavior> {
+        let mut peers = self.inner.peers.lock().unwrap();
+        let session = match peers.get_mut(peer) {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        // answering outbound peers would let them map which nodes we connect to
+        if session.is_outbound() {
+            return Ok(());
+        }
+        let addresses = self
+            .inner
+            .addr_book
+            .lock()
+            .unwrap()
+            .sample(MAX_ADDR_TO_SEND);
+        session.send("addr", self.addr_payload(addresses));
+        Ok(())
+    }
+
+    fn handle_addr(&self, peer: &str, request: &[u8]) -> Result<(), Misbehavior> {
+        let payload: Addr = decode("addr", request)?;
+        if payload.addr_list.len() > MAX_ADDR_TO_SEND {
+            return Err(Misbehavior::new(
+                MALFORMED_MESSAGE_SCORE,
+                format!("{} addresses in one message", payload.addr_list.len()),
+            ));
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let relay = payload.addr_list.len() <= ADDR_RELAY_MAX;
+        let mut new_addresses = vec![];
+        {
+            let mut addr_book = self.inner.addr_book.lock().unwrap();
+            for mut address in payload.addr_list {
+                address.addr = match address.addr.parse::<SocketAddr>() {
+                    Ok(addr) => addr.to_string(),
+                    Err(_) => continue,
+                };
+                if address.addr == self.inner.node_address {
                     continue;
-                } else {
-                    break;
+                }
+                // timestamps from the future are not trusted
+                if address.timestamp > now + 10 * 60 {
+                    address.timestamp = now - 5 * 24 * 60 * 60;
+                }
+                if addr_book.add(address.addr.clone(), address.timestamp)
+                    && now - address.timestamp < 10 * 60
+                {
+                    new_addresses.push(address);
                 }
             }
+            println!("There are {} known nodes now", addr_book.len());
+        }
+
+        // only relaying addresses new to us stops them from circling forever
+        if relay && !new_addresses.is_empty() {
+            self.relay_addr(peer, new_addresses);
+        }
+        Ok(())
+    }
+
+    // passes freshly announced addresses on to a few random full node peers
+    fn relay_addr(&self, from: &str, addresses: Vec<NetAddress>) {
+        let payload = self.addr_payload(addresses);
+        let mut peers = self.inner.peers.lock().unwrap();
+        let mut candidates: Vec<String> = peers
+            .iter()
+            .filter(|(addr, peer)| {
+                *addr != from
+                    && peer.is_handshake_complete()
+                    && peer
+                        .get_version()
+                        .is_some_and(|version| version.services & NODE_NETWORK != 0)
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for _ in 0..ADDR_RELAY_FANOUT {
+            if candidates.is_empty() {
+                break;
+            }
+            let target = candidates.swap_remove(random_nonce() as usize % candidates.len());
+            if let Some(peer) = peers.get_mut(&target) {
+                peer.send("addr", payload.clone());
+            }
         }
     }
+
+    fn addr_payload(&self, addr_list: Vec<NetAddress>) -> Vec<u8> {
+        bincode::serialize(&Addr { addr_list }).unwrap()
+    }
+
+    fn local_net_address(&self) -> NetAddress {
+        NetAddress {
+            addr: self.inner.node_address.clone(),
+            timestamp: chrono::Utc::now().timestamp(),
+        }
+    }
+}
+
+/*Headers-first synchronization: a node describes its chain with a
+block locator, the peer answers with the headers that follow it. */
+#[derive(Serialize, Deserialize)]
+struct GetHeaders {
+    addr_from: String,
+    locator: Vec<Vec<u8>>,
+    stop_hash: Vec<u8>, // empty to get as many headers as possible
+}
+
+#[derive(Serialize, Deserialize)]
+struct Headers {
+    addr_from: String,
+    headers: Vec<BlockHeader>,
+}
+
+/*Bitcoin uses inv to show other nodes what blocks or
+transactions current node has.  */
+#[derive(Serialize, Deserialize)]
+struct Inv {
+    addr_from: String,
+    inv_type: String, //whether these are blocks or transactions.
+    items: Vec<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GetData {
+    addr_from: String,
+    kind: String,
+    items: Vec<Vec<u8>>,
+}
+
+// the items of a getdata the node does not have
+#[derive(Serialize, Deserialize)]
+struct NotFound {
+    addr_from: String,
+    kind: String,
+    items: Vec<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Tx {
+    addr_from: String,
+    transaction: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -306,86 +2027,576 @@ struct BlockSend {
     block: Vec<u8>,
 }
 
-fn send_block(addr: String, block: &Block) {
-    let payload = bincode::serialize(&BlockSend {
-        addr_from: unsafe { NODE_ADDRESS.clone() },
-        block: block.serialize(),
-    })
-    .unwrap();
+#[derive(Serialize, Deserialize)]
+struct CmpctBlock {
+    addr_from: String,
+    block: CompactBlock,
+}
 
-    let mut request = command_to_bytes("block");
-    request.extend(payload);
-    send_data(addr, request);
+// asks for the transactions of a compact block the receiver could not find
+#[derive(Serialize, Deserialize)]
+struct GetBlockTxn {
+    addr_from: String,
+    block_hash: Vec<u8>,
+    indexes: Vec<u32>, // positions in the block, ascending
 }
 
-fn handle_block(request: &Vec<u8>, bc: &mut Blockchain) {
-    let payload: BlockSend = bincode::deserialize(&request[COMMAND_LENGTH..]).unwrap();
-    let block_data = payload.block;
-    let block = Block::deserialize_block(block_data);
+#[derive(Serialize, Deserialize)]
+struct BlockTxn {
+    addr_from: String,
+    block_hash: Vec<u8>,
+    transactions: Vec<Transaction>, // in the order of the indexes asked for
+}
 
-    println!("Received a new block!");
-    bc.add_block(block);
+#[derive(Serialize, Deserialize)]
+struct Addr {
+    addr_list: Vec<NetAddress>,
+}
 
-    unsafe {
-        if BLOCKS_IN_TRANSIT.len() > 0 {
-            let block_hash = BLOCKS_IN_TRANSIT[0].clone();
-            send_get_data(payload.addr_from, "block", &block_hash);
+/*Why a message of a peer was rejected. The score is added to the
+misbehaviour of the peer: honest mistakes score low, data no honest
+node sends scores high. */
+struct Misbehavior {
+    score: u32,
+    reason: String,
+}
 
-            BLOCKS_IN_TRANSIT = BLOCKS_IN_TRANSIT[1..].to_vec();
-        } else {
-            let utxo_set = UtxoSet::new(bc.clone());
-            utxo_set.reindex();
-        }
+impl Misbehavior {
+    fn new(score: u32, reason: String) -> Misbehavior {
+        Misbehavior { score, reason }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct Addr {
-    addr_list: Vec<String>,
+// decodes the payload of a command, a payload that does not decode is malformed
+fn decode<T: DeserializeOwned>(command: &str, payload: &[u8]) -> Result<T, Misbehavior> {
+    bincode::deserialize(payload).map_err(|e| {
+        Misbehavior::new(
+            MALFORMED_MESSAGE_SCORE,
+            format!("malformed {}: {}", command, e),
+        )
+    })
+}
+
+/*sends a transaction to a node from outside a running node. The wallet
+does the handshake as a light client over a short-lived connection. */
+fn submit_tx(addr: String, tx: &Transaction, transport: Option<&Transport>) -> Result<(), String> {
+    let mut stream = connect_to(&addr).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    let (mut sealer, mut opener) = match transport {
+        Some(transport) => {
+            let session = transport
+                .handshake(&mut stream, true)
+                .map_err(|e| e.to_string())?;
+            (Some(session.sealer), Some(session.opener))
+        }
+        None => (None, None),
+    };
+
+    let version = bincode::serialize(&Version {
+        version: PROTOCOL_VERSION,
+        services: NODE_LIGHT,
+        user_agent: user_agent(),
+        start_height: 0,
+        nonce: random_nonce(),
+        addr_from: String::new(),
+    })
+    .unwrap();
+    transport::write_message(&mut stream, sealer.as_mut(), "version", &version)
+        .map_err(|e| e.to_string())?;
+
+    let (mut version_received, mut verack_received) = (false, false);
+    while !(version_received && verack_received) {
+        match transport::read_message(&mut stream, opener.as_mut()).map_err(|e| e.to_string())? {
+            Some((command, _)) if command == "version" => {
+                version_received = true;
+                transport::write_message(&mut stream, sealer.as_mut(), "verack", &[])
+                    .map_err(|e| e.to_string())?;
+            }
+            Some((command, _)) if command == "verack" => verack_received = true,
+            Some(_) => {}
+            None => return Err(format!("{} closed the connection", addr)),
+        }
+    }
+
+    let payload = bincode::serialize(&Tx {
+        addr_from: String::new(),
+        transaction: bincode::serialize(tx).unwrap(),
+    })
+    .unwrap();
+    transport::write_message(&mut stream, sealer.as_mut(), "tx", &payload)
+        .map_err(|e| e.to_string())
 }
 
-fn handle_addr(request: &Vec<u8>) {
-    let payload: Addr = bincode::deserialize(&request[COMMAND_LENGTH..]).unwrap();
-    unsafe {
-        KNOWN_NODES.extend(payload.addr_list);
-        println!("There are {} known nodes now", KNOWN_NODES.len());
+/*sends a transaction to the first node that takes it: the wallet's own
+node, then the nodes in its address book in random order, then the seed.
+With encrypt the wallet authenticates with the node key of node_id.
+Returns the address of the node. */
+pub fn broadcast_tx(node_id: &str, tx: &Transaction, encrypt: bool) -> Result<String, String> {
+    let transport = if encrypt {
+        Some(Transport::new(transport::load_node_key(node_id)?, vec![]))
+    } else {
+        None
+    };
+    let mut candidates =
+        vec![NodeConfig::new(node_id.to_string(), String::new()).advertised_address()];
+    let known_nodes = addr_book::new_addr_book(node_id).sample(MAX_ADDR_TO_SEND);
+    candidates.extend(known_nodes.into_iter().map(|address| address.addr));
+    candidates.push(SEED_NODE.to_string());
+
+    let mut errors = vec![];
+    for addr in candidates {
+        if errors.iter().any(|(tried, _)| *tried == addr) {
+            continue;
+        }
+        match submit_tx(addr.clone(), tx, transport.as_ref()) {
+            Ok(()) => return Ok(addr),
+            Err(e) => errors.push((addr, e)),
+        }
     }
-    request_blocks();
+    let errors: Vec<String> = errors
+        .into_iter()
+        .map(|(addr, e)| format!("{}: {}", addr, e))
+        .collect();
+    Err(errors.join(", "))
+}
+
+fn user_agent() -> String {
+    format!("/blockchain-rust:{}/", env!("CARGO_PKG_VERSION"))
 }
 
-fn command_to_bytes(command: &str) -> Vec<u8> {
-    let mut bytes = vec![0; COMMAND_LENGTH];
-    for (i, c) in command.chars().enumerate() {
-        bytes[i] = c as u8;
+fn service_names(services: u64) -> String {
+    let mut names = vec![];
+    if services & NODE_NETWORK != 0 {
+        names.push("network");
     }
-    bytes
+    if services & NODE_LIGHT != 0 {
+        names.push("light");
+    }
+    if services & NODE_TX_INDEX != 0 {
+        names.push("txindex");
+    }
+    names.join(" ")
+}
+
+// whether peer is a full node that has the block at height, as far as we know
+fn can_serve_block(peer: &Peer, height: usize) -> bool {
+    peer.is_handshake_complete()
+        && peer
+            .get_version()
+            .is_some_and(|version| version.services & NODE_NETWORK != 0)
+        && peer.get_best_known_height() >= height
+}
+
+// the address to connect to for reaching a listener bound to addr
+// the key of the session with the node listening on addr
+fn find_session(peers: &HashMap<String, Peer>, addr: &str) -> Option<String> {
+    peers
+        .iter()
+        .find(|(_, peer)| peer.is_reachable_at(addr))
+        .map(|(key, _)| key.clone())
 }
 
-fn bytes_to_command(bytes: &[u8]) -> String {
-    let mut command = String::new();
-    for b in bytes {
-        if *b != 0 {
-            command.push(*b as char);
+// connects to the first address addr resolves to that answers within CONNECT_TIMEOUT
+fn connect_to(addr: &str) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to");
+    for socket_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
         }
     }
-    command
+    Err(last_error)
+}
+
+fn reachable_addr(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    addr
+}
+
+fn random_nonce() -> u64 {
+    let mut bytes = [0u8; 8];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    u64::from_le_bytes(bytes)
 }
 
-fn node_is_known(addr: &String) -> bool {
-    unsafe {
-        for node in KNOWN_NODES.clone() {
-            if node.eq(addr) {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use crate::Wallet;
+
+    // starts a node on a port of its own, which connects to seed_nodes only
+    fn start(node_id: &str, seed_nodes: Vec<String>, mining_address: String) -> Node {
+        let mut config = NodeConfig::new(node_id.to_string(), mining_address);
+        config.listen_addrs = vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)];
+        config.seed_nodes = seed_nodes;
+        // blocks are only mined when a test asks
+        config.mining_interval = Duration::from_secs(60 * 60);
+        let node = Node::new(config).unwrap();
+        node.start().unwrap();
+        node
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) -> bool {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(30) {
+            if condition() {
                 return true;
             }
+            thread::sleep(Duration::from_millis(50));
         }
+        false
     }
-    false
-}
 
-fn request_blocks() {
-    unsafe {
-        for node in KNOWN_NODES.clone() {
-            send_get_blocks(node);
+    fn is_connected(node: &Node) -> bool {
+        node.get_peer_info()
+            .iter()
+            .any(|peer| peer.version.is_some())
+    }
+
+    #[test]
+    fn a_new_node_downloads_the_chain_of_its_peer() {
+        let (wallet, mut bc) = test_util::new_chain("sync_seed");
+        test_util::copy_chain(&bc, "sync_seed", "sync_peer");
+        for _ in 0..3 {
+            test_util::mine(&mut bc, vec![], &wallet);
+        }
+        let tip = bc.get_tip_hash();
+        drop(bc);
+
+        let seed = start("sync_seed", vec![], String::new());
+        let peer = start("sync_peer", vec![seed.get_node_address()], String::new());
+        let synced = wait_until(|| peer.get_best_height() == 3);
+        let peer_tip = peer.inner.chain.lock().unwrap().get_tip_hash();
+        peer.stop();
+        seed.stop();
+        assert!(synced);
+        assert_eq!(peer_tip, tip);
+    }
+
+    #[test]
+    fn transactions_and_blocks_are_relayed() {
+        let (wallet, bc) = test_util::new_chain("relay_seed");
+        test_util::copy_chain(&bc, "relay_seed", "relay_peer");
+        let tx = transaction::new_utxo_transaction(
+            &wallet,
+            test_util::address(&Wallet::new_wallet()),
+            3,
+            1,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &UtxoSet::new(bc.clone()),
+        );
+        drop(bc);
+
+        let seed = start("relay_seed", vec![], test_util::address(&wallet));
+        let peer = start("relay_peer", vec![seed.get_node_address()], String::new());
+        let connected = wait_until(|| is_connected(&seed) && is_connected(&peer));
+        let submitted = submit_tx(seed.get_node_address(), &tx, None);
+        let relayed = wait_until(|| {
+            peer.get_mempool()
+                .iter()
+                .any(|pool_tx| pool_tx.get_id() == tx.get_id())
+        });
+        let block = seed.mine_block();
+        let confirmed = wait_until(|| peer.get_best_height() == 1 && peer.get_mempool().is_empty());
+        peer.stop();
+        seed.stop();
+        assert!(connected);
+        assert!(submitted.is_ok());
+        assert!(relayed);
+        assert!(block.is_some_and(|block| block
+            .get_transactions()
+            .iter()
+            .any(|block_tx| block_tx.get_id() == tx.get_id())));
+        assert!(confirmed);
+    }
+
+    #[test]
+    fn only_accepted_and_invalid_transactions_are_remembered() {
+        let (wallet, bc) = test_util::new_chain("recent_txs");
+        let utxo_set = UtxoSet::new(bc.clone());
+        let payee = Wallet::new_wallet();
+        let to = test_util::address(&payee);
+        let tx = transaction::new_utxo_transaction(
+            &wallet,
+            to.clone(),
+            3,
+            1,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &utxo_set,
+        );
+        let mut mutated = tx.clone();
+        mutated.set_script_sig(0, vec![]);
+        let non_final = transaction::new_utxo_transaction(
+            &wallet,
+            to.clone(),
+            3,
+            2,
+            transaction::input_sequence(false, 100, None),
+            100,
+            &utxo_set,
+        );
+        let orphan = test_util::spend_child(
+            &bc,
+            "recent_txs",
+            &tx,
+            &payee,
+            1,
+            1,
+            transaction::SEQUENCE_FINAL,
+        );
+        let coinbase = transaction::new_coinbase_tx(to, "outside a block".to_string(), 0);
+        drop(utxo_set);
+        drop(bc);
+
+        let node = start("recent_txs", vec![], String::new());
+        let bc = node.inner.chain.lock().unwrap().clone();
+        let utxo_set = UtxoSet::new(bc);
+        let seen = |tx: &Transaction| node.inner.recent_txs.lock().unwrap().contains(&tx.get_id());
+        assert!(node.accept_tx("peer", mutated, &utxo_set).is_err());
+        assert!(matches!(
+            node.accept_tx("peer", non_final.clone(), &utxo_set),
+            Ok(false)
+        ));
+        assert!(matches!(
+            node.accept_tx("peer", orphan.clone(), &utxo_set),
+            Ok(false)
+        ));
+        assert!(node.accept_tx("peer", coinbase.clone(), &utxo_set).is_err());
+        let remembered = [&tx, &non_final, &orphan, &coinbase].map(seen);
+        let accepted = node.accept_tx("peer", tx.clone(), &utxo_set);
+        let accepted_seen = seen(&tx);
+        node.stop();
+        assert_eq!(remembered, [false, false, false, true]);
+        assert!(matches!(accepted, Ok(true)));
+        assert!(accepted_seen);
+    }
+
+    #[test]
+    fn a_transaction_spending_a_spent_confirmed_output_is_not_an_orphan() {
+        let (wallet, mut bc) = test_util::new_chain("double_spend");
+        let to = test_util::address(&Wallet::new_wallet());
+        let utxo_set = UtxoSet::new(bc.clone());
+        let confirmed = transaction::new_utxo_transaction(
+            &wallet,
+            to.clone(),
+            3,
+            1,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &utxo_set,
+        );
+        let double_spend = transaction::new_utxo_transaction(
+            &wallet,
+            to,
+            4,
+            1,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &utxo_set,
+        );
+        test_util::mine(&mut bc, vec![confirmed], &wallet);
+        drop(utxo_set);
+        drop(bc);
+
+        let node = start("double_spend", vec![], String::new());
+        let bc = node.inner.chain.lock().unwrap().clone();
+        let accepted = node.accept_tx("peer", double_spend.clone(), &UtxoSet::new(bc));
+        let orphaned = node
+            .inner
+            .orphan_txs
+            .lock()
+            .unwrap()
+            .contains(&double_spend.get_id());
+        node.stop();
+        assert!(matches!(accepted, Ok(false)));
+        assert!(!orphaned);
+    }
+
+    // connects to addr and sends a version of a light client speaking protocol version
+    fn send_version(addr: &str, version: u32) -> TcpStream {
+        let mut stream = connect_to(addr).unwrap();
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).unwrap();
+        let payload = bincode::serialize(&Version {
+            version,
+            services: NODE_LIGHT,
+            user_agent: "/test/".to_string(),
+            start_height: 0,
+            nonce: random_nonce(),
+            addr_from: String::new(),
+        })
+        .unwrap();
+        transport::write_message(&mut stream, None, "version", &payload).unwrap();
+        stream
+    }
+
+    #[test]
+    fn the_handshake_exchanges_versions() {
+        let (_, bc) = test_util::new_chain("handshake");
+        drop(bc);
+        let node = start("handshake", vec![], String::new());
+        let mut stream = send_version(&node.get_node_address(), PROTOCOL_VERSION);
+        let (command, payload) = transport::read_message(&mut stream, None).unwrap().unwrap();
+        let version: Version = bincode::deserialize(&payload).unwrap();
+        let verack = transport::read_message(&mut stream, None).unwrap();
+        transport::write_message(&mut stream, None, "verack", &[]).unwrap();
+        let known = wait_until(|| {
+            node.get_peer_info().iter().any(|peer| {
+                peer.version.as_ref().is_some_and(|version| {
+                    version.services == NODE_LIGHT && version.user_agent == "/test/"
+                })
+            })
+        });
+
+        let mut obsolete = send_version(&node.get_node_address(), MIN_PEER_PROTO_VERSION - 1);
+        let disconnected = transport::read_message(&mut obsolete, None);
+        node.stop();
+        assert_eq!(command, "version");
+        assert_eq!(version.services, NODE_NETWORK);
+        assert_eq!(version.user_agent, user_agent());
+        assert!(verack.is_some_and(|(command, _)| command == "verack"));
+        assert!(known);
+        assert!(matches!(disconnected, Ok(None) | Err(_)));
+    }
+
+    #[test]
+    fn a_peer_breaking_the_protocol_is_dropped() {
+        let (_, bc) = test_util::new_chain("misbehavior");
+        drop(bc);
+        let node = start("misbehavior", vec![], String::new());
+        let mut stream = connect_to(&node.get_node_address()).unwrap();
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).unwrap();
+        for _ in 0..BAN_THRESHOLD / PROTOCOL_VIOLATION_SCORE {
+            if transport::write_message(&mut stream, None, "getaddr", &[]).is_err() {
+                break;
+            }
+        }
+        let disconnected = transport::read_message(&mut stream, None);
+        node.stop();
+        assert!(matches!(disconnected, Ok(None) | Err(_)));
+    }
+
+    #[test]
+    fn the_advertised_address_is_a_specific_one() {
+        let mut config = NodeConfig::new("3005".to_string(), String::new());
+        assert_eq!(config.advertised_address(), "127.0.0.1:3005");
+        let any_v6: SocketAddr = "[::]:3005".parse().unwrap();
+        let v6: SocketAddr = "[::1]:3006".parse().unwrap();
+        config.listen_addrs = vec![any_v6];
+        assert_eq!(config.advertised_address(), "");
+        assert_eq!(reachable_addr(any_v6), "[::1]:3005".parse().unwrap());
+        config.listen_addrs = vec![any_v6, v6];
+        assert_eq!(config.advertised_address(), "[::1]:3006");
+        config.external_addr = Some("203.0.113.1:3000".parse().unwrap());
+        assert_eq!(config.advertised_address(), "203.0.113.1:3000");
+    }
+
+    #[test]
+    fn nodes_connect_over_ipv6() {
+        let (_, bc) = test_util::new_chain("ipv6_seed");
+        test_util::copy_chain(&bc, "ipv6_seed", "ipv6_peer");
+        drop(bc);
+        let mut config = NodeConfig::new("ipv6_seed".to_string(), String::new());
+        config.listen_addrs = vec![SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)];
+        config.seed_nodes = vec![];
+        let seed = Node::new(config).unwrap();
+        seed.start().unwrap();
+        let peer = start("ipv6_peer", vec![seed.get_node_address()], String::new());
+        let connected = wait_until(|| is_connected(&seed) && is_connected(&peer));
+        let seed_addr = seed.get_node_address();
+        let peer_info = peer.get_peer_info();
+        peer.stop();
+        seed.stop();
+        assert!(seed_addr.starts_with("[::1]:"));
+        assert!(connected);
+        assert_eq!(peer_info[0].addr, seed_addr);
+    }
+
+    // a session with a full node at height, handshake done, and the socket of the other end
+    fn full_node_session(height: usize) -> (Peer, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (remote, _) = listener.accept().unwrap();
+        let (events, _) = mpsc::channel();
+        let mut peer = Peer::spawn(stream, true, None, events).unwrap();
+        peer.set_version(PeerVersion {
+            version: PROTOCOL_VERSION,
+            services: NODE_NETWORK,
+            user_agent: user_agent(),
+            start_height: height,
+        });
+        peer.set_verack_received();
+        peer.update_best_known_height(height);
+        (peer, remote)
+    }
+
+    #[test]
+    fn blocks_are_spread_over_the_peers() {
+        let (wallet, mut bc) = test_util::new_chain("parallel_download_source");
+        let count = 2 * MAX_BLOCKS_IN_FLIGHT_PER_PEER + 1;
+        let headers: Vec<BlockHeader> = (0..count)
+            .map(|_| test_util::mine(&mut bc, vec![], &wallet).get_header())
+            .collect();
+        drop(bc);
+        let (_, bc) = test_util::new_chain("parallel_download");
+        drop(bc);
+
+        let node = Node::new(NodeConfig {
+            listen_addrs: vec![],
+            ..NodeConfig::new("parallel_download".to_string(), String::new())
+        })
+        .unwrap();
+        let mut remotes = vec![];
+        for height in [count, MAX_BLOCKS_IN_FLIGHT_PER_PEER] {
+            let (peer, remote) = full_node_session(height);
+            node.inner
+                .peers
+                .lock()
+                .unwrap()
+                .insert(peer.get_addr(), peer);
+            remotes.push(remote);
+        }
+        *node.inner.headers_in_transit.lock().unwrap() = headers;
+        node.request_blocks();
+
+        let block_requests = node.inner.block_requests.lock().unwrap();
+        let peers = node.inner.peers.lock().unwrap();
+        let mut counts: Vec<(usize, usize)> = peers
+            .values()
+            .map(|peer| {
+                (
+                    peer.get_best_known_height(),
+                    block_requests.count_for(&peer.get_addr()),
+                )
+            })
+            .collect();
+        counts.sort();
+        // each block goes to the least busy peer having it, up to the limit of each peer
+        assert_eq!(
+            counts,
+            vec![
+                (
+                    MAX_BLOCKS_IN_FLIGHT_PER_PEER,
+                    MAX_BLOCKS_IN_FLIGHT_PER_PEER / 2
+                ),
+                (count, MAX_BLOCKS_IN_FLIGHT_PER_PEER)
+            ]
+        );
+        for remote in &mut remotes {
+            remote.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).unwrap();
+            let (command, payload) = transport::read_message(remote, None).unwrap().unwrap();
+            let get_data: GetData = bincode::deserialize(&payload).unwrap();
+            assert_eq!(command, "getdata");
+            assert_eq!(get_data.kind, "block");
         }
     }
 }
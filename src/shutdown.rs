@@ -0,0 +1,21 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request(_signal: libc::c_int) {
+    // a signal handler may do little more than set a flag, the node is stopped elsewhere
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// makes SIGINT and SIGTERM request a shutdown instead of ending the process
+pub fn install_handler() {
+    let handler = request as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+pub fn is_requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,95 @@
+use crate::transaction;
+use crate::Block;
+use crate::Blockchain;
+use crate::Transaction;
+use crate::UtxoSet;
+use crate::Wallet;
+use std::fs;
+use std::path::Path;
+use std::sync::Once;
+use std::thread;
+use std::time::Duration;
+
+static DATA_DIR: Once = Once::new();
+
+/*Nodes keep their files in the working directory, so the tests run in
+one of their own under target, emptied first. Tests sharing it use
+different node IDs. */
+pub fn enter_data_dir() {
+    DATA_DIR.call_once(|| {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("target/test-data");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+    });
+}
+
+pub fn address(wallet: &Wallet) -> String {
+    String::from_utf8(wallet.get_address()).unwrap()
+}
+
+// a new chain of node_id whose genesis block pays a new wallet
+pub fn new_chain(node_id: &str) -> (Wallet, Blockchain) {
+    enter_data_dir();
+    let wallet = Wallet::new_wallet();
+    let bc = Blockchain::create_blockchain(address(&wallet), node_id.to_string()).unwrap();
+    UtxoSet::new(bc.clone()).reindex();
+    (wallet, bc)
+}
+
+// a block is timed after the median time past, which may be this very millisecond
+pub fn wait_for_block_time() {
+    thread::sleep(Duration::from_millis(2));
+}
+
+// mines txs and a coinbase paying the subsidy to wallet on top of the tip
+pub fn mine(bc: &mut Blockchain, txs: Vec<Transaction>, wallet: &Wallet) -> Block {
+    wait_for_block_time();
+    let height = bc.get_best_height() + 1;
+    let coinbase = transaction::new_coinbase_tx(address(wallet), format!("Height {}", height), 0);
+    let block = bc.mine_block([vec![coinbase], txs].concat()).unwrap();
+    UtxoSet::new(bc.clone()).update(block.clone());
+    block
+}
+
+/*a transaction of payer spending what parent pays it. A wallet only spends
+from the UTXO set, so it is built on a copy of bc, the chain of node_id,
+with parent confirmed, and is valid on bc with parent unconfirmed. */
+pub fn spend_child(
+    bc: &Blockchain,
+    node_id: &str,
+    parent: &Transaction,
+    payer: &Wallet,
+    amount: i64,
+    fee: i64,
+    sequence: u32,
+) -> Transaction {
+    let copy_id = format!("{}_{}", node_id, &hex::encode(parent.get_id())[..8]);
+    copy_chain(bc, node_id, &copy_id);
+    let mut copy = Blockchain::new_blockchain(copy_id).unwrap();
+    mine(&mut copy, vec![parent.clone()], &Wallet::new_wallet());
+    let to = address(&Wallet::new_wallet());
+    transaction::new_utxo_transaction(payer, to, amount, fee, sequence, 0, &UtxoSet::new(copy))
+}
+
+// gives node to a copy of bc, the chain of node from, so both start from the same blocks
+pub fn copy_chain(bc: &Blockchain, from: &str, to: &str) {
+    bc.get_db().flush().unwrap();
+    copy_dir(
+        Path::new(&format!("blockchain_{}.db", from)),
+        Path::new(&format!("blockchain_{}.db", to)),
+    );
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let path = entry.unwrap().path();
+        let target = to.join(path.file_name().unwrap());
+        if path.is_dir() {
+            copy_dir(&path, &target);
+        } else {
+            fs::copy(&path, &target).unwrap();
+        }
+    }
+}
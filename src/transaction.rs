@@ -1,20 +1,55 @@
+use crate::mempool;
+use crate::script;
 use crate::wallet;
 use crate::UtxoSet;
 use crate::Wallet;
-use bs58;
-use ring::signature;
+use ring::signature::{self, KeyPair};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::process;
 
-const SUBSIDY: i64 = 10; // the amount of reward
+pub const SUBSIDY: i64 = 10; // the amount of reward
+pub const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+const SEQUENCE_LOCK_TIME: u32 = 0xffff_fffe; // enables the lock time without other meaning
+const MAX_REPLACEABLE_SEQUENCE: u32 = 0xffff_fffd; // an input with a sequence up to this one lets its transaction be replaced
+
+/*A sequence without the disable flag is a relative lock: the input can
+only be mined a number of blocks, or of 512 second units with the type
+flag, after the output it spends. */
+const SEQUENCE_LOCK_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_LOCK_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_LOCK_MASK: u32 = 0xffff;
+const SEQUENCE_LOCK_GRANULARITY: u32 = 9; // 512 seconds per unit
+pub const LOCK_TIME_THRESHOLD: u32 = 500_000_000; // lock times below are block heights, the others unix times
+pub const CHANGE_INDEX: usize = 1; // the output of a new transaction returning the change, after the payment
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Transaction {
     id: Vec<u8>,
     vin: Vec<TXInput>,
     vout: Vec<TXOutput>,
+    lock_time: u32, // the last block height or time the transaction cannot be mined at, 0 for none
+}
+
+// how long after the output it spends is confirmed an input can be mined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RelativeLock {
+    Blocks(u32),
+    Seconds(i64),
+}
+
+impl RelativeLock {
+    // the sequence of an input with this lock, times are rounded up to 512 second units
+    pub fn to_sequence(self) -> u32 {
+        match self {
+            RelativeLock::Blocks(blocks) => blocks.min(SEQUENCE_LOCK_MASK),
+            RelativeLock::Seconds(seconds) => {
+                let units = (seconds.max(0) as u64).div_ceil(1 << SEQUENCE_LOCK_GRANULARITY);
+                SEQUENCE_LOCK_TYPE_FLAG | units.min(SEQUENCE_LOCK_MASK as u64) as u32
+            }
+        }
+    }
 }
 
 impl Transaction {
@@ -32,6 +67,7 @@ impl Transaction {
         self.vin.len() == 1 && self.vin[0].txid.len() == 0 && self.vin[0].vout == -1
     }
 
+    // signs the inputs spending pay-to-pubkey-hash outputs
     pub fn sign(&mut self, private_key: &Vec<u8>, prev_txs: &HashMap<String, Transaction>) {
         if self.is_coinbase() {
             return;
@@ -42,19 +78,35 @@ impl Transaction {
             }
         }
 
-        let mut tx_copy = self.trimmed_copy();
-        for (in_id, vin) in self.vin.iter_mut().enumerate() {
-            let prev_tx = prev_txs.get(&hex::encode(&vin.txid)).unwrap();
-            tx_copy.vin[in_id].signature = Vec::new();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[vin.vout as usize].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[in_id].pub_key = Vec::new();
-
-            let tx_bytes = bincode::serialize(&tx_copy).unwrap();
+        let prev_outs: Vec<TXOutput> = self
+            .vin
+            .iter()
+            .map(|vin| {
+                prev_txs.get(&hex::encode(&vin.txid)).unwrap().vout[vin.vout as usize].clone()
+            })
+            .collect();
+        self.sign_inputs(private_key, &prev_outs);
+    }
+
+    // signs the inputs spending pay-to-pubkey-hash outputs, given in input order
+    pub fn sign_inputs(&mut self, private_key: &Vec<u8>, prev_outs: &[TXOutput]) {
+        let pub_key = public_key(private_key);
+        for (in_id, prev_out) in prev_outs.iter().enumerate().take(self.vin.len()) {
+            let tx_bytes = self.signature_message(in_id, &prev_out.script_pubkey);
             let signature = ecdsa_sign(private_key, &tx_bytes);
-            vin.signature = signature;
+            self.vin[in_id].script_sig = script::p2pkh_unlock(&signature, &pub_key);
         }
     }
+
+    /*what the signatures of an input sign: the transaction without
+    unlocking scripts, the locking script it spends in their place */
+    pub fn signature_message(&self, in_id: usize, script_pubkey: &[u8]) -> Vec<u8> {
+        let mut tx_copy = self.trimmed_copy();
+        tx_copy.vin[in_id].script_sig = script_pubkey.to_vec();
+        tx_copy.id = tx_copy.hash();
+        bincode::serialize(&tx_copy).unwrap()
+    }
+
     // creates a trimmed copy of Transaction to be used in signing
     pub fn trimmed_copy(&self) -> Transaction {
         let mut inputs = Vec::new();
@@ -63,20 +115,18 @@ impl Transaction {
             inputs.push(TXInput {
                 txid: vin.txid.clone(),
                 vout: vin.vout,
-                signature: vec![],
-                pub_key: vec![],
+                script_sig: vec![],
+                sequence: vin.sequence,
             });
         }
         for vout in &self.vout {
-            outputs.push(TXOutput {
-                value: vout.value,
-                pub_key_hash: vout.pub_key_hash.clone(),
-            });
+            outputs.push(vout.clone());
         }
         Transaction {
             id: self.id.clone(),
             vin: inputs,
             vout: outputs,
+            lock_time: self.lock_time,
         }
     }
 
@@ -84,27 +134,62 @@ impl Transaction {
         if self.is_coinbase() {
             return true;
         }
+        let mut prev_outs = vec![];
         for vin in &self.vin {
-            if prev_txs.get(&hex::encode(&vin.txid)).is_none() {
-                eprintln!("ERROR: Previous transaction is not correct");
-            }
+            // an input spending an unknown output is invalid, not a reason to panic
+            match prev_txs.get(&hex::encode(&vin.txid)).and_then(|prev_tx| {
+                usize::try_from(vin.vout)
+                    .ok()
+                    .and_then(|i| prev_tx.vout.get(i))
+            }) {
+                Some(prev_out) => prev_outs.push(prev_out.clone()),
+                None => {
+                    eprintln!("ERROR: Previous transaction is not correct");
+                    return false;
+                }
+            };
         }
+        self.verify_inputs(&prev_outs)
+    }
 
-        let mut tx_copy = self.trimmed_copy();
-        for (in_id, vin) in self.vin.iter().enumerate() {
-            let prev_tx = prev_txs.get(&hex::encode(&vin.txid)).unwrap();
-            tx_copy.vin[in_id].signature = Vec::new();
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[vin.vout as usize].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[in_id].pub_key = Vec::new();
-
-            let tx_bytes = bincode::serialize(&tx_copy).unwrap();
-            let verify = ecdsa_sign_verify(&vin.pub_key, &tx_bytes, &vin.signature);
-            if verify == false {
-                return false;
-            }
+    pub fn verify_inputs(&self, prev_outs: &[TXOutput]) -> bool {
+        self.verify_scripts(prev_outs).is_ok()
+    }
+
+    // runs the unlocking script of every input with the locking script of the output it spends, given in input order
+    pub fn verify_scripts(&self, prev_outs: &[TXOutput]) -> Result<(), String> {
+        if prev_outs.len() != self.vin.len() {
+            return Err("an output to spend per input is needed".to_string());
+        }
+        for (in_id, (vin, prev_out)) in self.vin.iter().zip(prev_outs).enumerate() {
+            let tx_bytes = self.signature_message(in_id, &prev_out.script_pubkey);
+            let check_sig = |signature: &[u8], pub_key: &[u8]| {
+                ecdsa_sign_verify(&pub_key.to_vec(), &tx_bytes, &signature.to_vec())
+            };
+            script::verify_script(&vin.script_sig, &prev_out.script_pubkey, &check_sig)
+                .map_err(|e| format!("input {}: {}", in_id, e))?;
+        }
+        Ok(())
+    }
+
+    // whether the id is the hash of the transaction without unlocking scripts, as computed when it was created
+    pub fn has_valid_id(&self) -> bool {
+        if self.is_coinbase() {
+            let mut tx_copy = self.clone();
+            tx_copy.id = Vec::new();
+            return tx_copy.hash() == self.id;
         }
-        true
+        let mut tx_copy = self.trimmed_copy();
+        tx_copy.id = Vec::new();
+        tx_copy.hash() == self.id
+    }
+
+    /*whether the transaction opts in to replace-by-fee: until it is mined,
+    a transaction spending the same outputs and paying more may replace it */
+    pub fn is_replaceable(&self) -> bool {
+        self.vin
+            .iter()
+            .any(|vin| vin.sequence <= MAX_REPLACEABLE_SEQUENCE)
     }
 
     pub fn get_id(&self) -> Vec<u8> {
@@ -118,24 +203,49 @@ impl Transaction {
     pub fn get_vin(&self) -> Vec<TXInput> {
         self.vin.clone()
     }
+
+    pub fn get_lock_time(&self) -> u32 {
+        self.lock_time
+    }
+
+    /*whether the lock time lets the transaction into a block at height with
+    a timestamp of time seconds. Inputs all with SEQUENCE_FINAL disable it. */
+    pub fn is_final(&self, height: usize, time: i64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        let limit = if self.lock_time < LOCK_TIME_THRESHOLD {
+            height as i64
+        } else {
+            time
+        };
+        (self.lock_time as i64) < limit || self.vin.iter().all(|vin| vin.sequence == SEQUENCE_FINAL)
+    }
+
+    // sets the unlocking script of an input, which leaves the id as it is
+    pub fn set_script_sig(&mut self, in_id: usize, script_sig: Vec<u8>) {
+        self.vin[in_id].script_sig = script_sig;
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TXOutput {
     value: i64,
-    pub_key_hash: Vec<u8>,
+    script_pubkey: Vec<u8>, // the locking script, see script.rs
 }
 
 impl TXOutput {
-    // simply locks an output
+    // locks an output to the public key hash or the redeem script hash of an address
     pub fn lock(&mut self, address: Vec<u8>) {
-        let pub_key_hash = bs58::decode(address).into_vec().unwrap();
-        self.pub_key_hash =
-            pub_key_hash[1..pub_key_hash.len() - wallet::ADDRESS_CHECK_SUM_LEN].to_vec();
+        self.script_pubkey = wallet::locking_script(&String::from_utf8(address).unwrap());
     }
 
     pub fn is_locked_with_key(&self, pub_key_hash: &Vec<u8>) -> bool {
-        self.pub_key_hash.eq(pub_key_hash)
+        self.get_pub_key_hash().as_ref() == Some(pub_key_hash)
+    }
+
+    pub fn is_locked_with_script(&self, script_pubkey: &[u8]) -> bool {
+        self.script_pubkey == script_pubkey
     }
 
     pub fn get_value(&self) -> i64 {
@@ -145,30 +255,43 @@ impl TXOutput {
     pub fn new_tx_output(value: i64, address: String) -> TXOutput {
         let mut tx_output = TXOutput {
             value,
-            pub_key_hash: Vec::new(),
+            script_pubkey: Vec::new(),
         };
         tx_output.lock(address.into_bytes());
         tx_output
     }
 
-    pub fn get_pub_key_hash(&self) -> Vec<u8> {
-        self.pub_key_hash.clone()
+    // an output locked by any script
+    pub fn new_script_output(value: i64, script_pubkey: Vec<u8>) -> TXOutput {
+        TXOutput {
+            value,
+            script_pubkey,
+        }
+    }
+
+    // the public key hash the output pays to, None unless it is locked with the default template
+    pub fn get_pub_key_hash(&self) -> Option<Vec<u8>> {
+        script::parse_p2pkh(&self.script_pubkey)
+    }
+
+    pub fn get_script_pubkey(&self) -> Vec<u8> {
+        self.script_pubkey.clone()
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TXInput {
-    txid: Vec<u8>,      // previous transaction id
-    vout: i64,          //Vout stores an index of an output in the transaction.
-    signature: Vec<u8>, // signature
-    pub_key: Vec<u8>,   // public key
+    txid: Vec<u8>,       // previous transaction id
+    vout: i64,           //Vout stores an index of an output in the transaction.
+    script_sig: Vec<u8>, // the unlocking script, arbitrary data in a coinbase
+    sequence: u32,       // SEQUENCE_FINAL unless the transaction may be replaced
 }
 
 impl TXInput {
     //  checks that an input uses a specific key to unlock an output
     pub fn uses_key(&self, pub_key_hash: &Vec<u8>) -> bool {
-        let locking_hash = wallet::hash_pub_key(&self.pub_key);
-        locking_hash.eq(pub_key_hash)
+        self.get_pub_key()
+            .is_some_and(|pub_key| wallet::hash_pub_key(&pub_key).eq(pub_key_hash))
     }
 
     pub fn get_txid(&self) -> Vec<u8> {
@@ -179,49 +302,110 @@ impl TXInput {
         self.vout
     }
 
-    pub fn get_pub_key(&self) -> Vec<u8> {
-        self.pub_key.clone()
+    // the public key of a pay-to-pubkey-hash unlocking script
+    pub fn get_pub_key(&self) -> Option<Vec<u8>> {
+        script::parse_p2pkh_unlock(&self.script_sig)
+    }
+
+    pub fn get_script_sig(&self) -> Vec<u8> {
+        self.script_sig.clone()
+    }
+
+    pub fn get_sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    pub fn get_relative_lock(&self) -> Option<RelativeLock> {
+        if self.sequence & SEQUENCE_LOCK_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = self.sequence & SEQUENCE_LOCK_MASK;
+        if self.sequence & SEQUENCE_LOCK_TYPE_FLAG != 0 {
+            Some(RelativeLock::Seconds(
+                (value as i64) << SEQUENCE_LOCK_GRANULARITY,
+            ))
+        } else {
+            Some(RelativeLock::Blocks(value))
+        }
     }
 }
 
-// creates a new coinbase transaction
-pub fn new_coinbase_tx(to: String, mut data: String) -> Transaction {
+/*creates a new coinbase transaction, paying the subsidy and the fees of
+the block. Miners put the block height in data, so coinbases paying the
+same address in different blocks have different ids. */
+pub fn new_coinbase_tx(to: String, mut data: String, fees: i64) -> Transaction {
     if data == "" {
         data = format!("Reward to '{}'", to);
     }
     let txin = TXInput {
         txid: vec![],
         vout: -1,
-        signature: vec![],
-        pub_key: data.into_bytes(),
+        script_sig: data.into_bytes(),
+        sequence: SEQUENCE_FINAL,
     };
-    let txout = TXOutput::new_tx_output(SUBSIDY, to);
+    let txout = TXOutput::new_tx_output(SUBSIDY + fees, to);
     let mut tx = Transaction {
         id: vec![],
         vin: vec![txin],
         vout: vec![txout],
+        lock_time: 0,
     };
     tx.id = tx.hash();
     tx
 }
 
-//  a general transaction
+/*a general transaction, the inputs exceed the outputs by fee, which goes
+to the miner. Its inputs get sequence, see input_sequence. */
 pub fn new_utxo_transaction(
     wallet: &Wallet,
     to: String,
     amount: i64,
+    fee: i64,
+    sequence: u32,
+    lock_time: u32,
     utxo_set: &UtxoSet,
 ) -> Transaction {
+    let pub_key_hash = wallet::hash_pub_key(&wallet.public_key);
+    let mut tx = match new_unsigned_transaction(
+        &script::p2pkh(&pub_key_hash),
+        to,
+        amount,
+        fee,
+        sequence,
+        lock_time,
+        utxo_set,
+    ) {
+        Ok(tx) => tx,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(-1);
+        }
+    };
+    utxo_set
+        .get_blockchain()
+        .sign_transaction(&mut tx, &wallet.get_private_key());
+    tx
+}
+
+/*a transaction paying amount to "to" and fee to the miner from outputs
+locked with script_pubkey, the change going back to it. Its inputs are
+not signed yet. */
+pub fn new_unsigned_transaction(
+    script_pubkey: &[u8],
+    to: String,
+    amount: i64,
+    fee: i64,
+    sequence: u32,
+    lock_time: u32,
+    utxo_set: &UtxoSet,
+) -> Result<Transaction, String> {
     let mut txs_inputs = Vec::new();
     let mut txs_outputs = Vec::new();
 
-    let pub_key_hash = wallet::hash_pub_key(&wallet.public_key);
-
-    let (acc, valid_outputs) = utxo_set.find_spendable_outputs(&pub_key_hash, amount);
+    let (acc, valid_outputs) = utxo_set.find_spendable_outputs(script_pubkey, amount + fee);
 
-    if acc < amount {
-        eprintln!("Error: Not enough funds");
-        process::exit(-1);
+    if acc < amount + fee {
+        return Err("Not enough funds".to_string());
     }
 
     for (txid, outs) in valid_outputs.iter() {
@@ -230,32 +414,172 @@ pub fn new_utxo_transaction(
             let input = TXInput {
                 txid: hex::decode(txid.clone()).unwrap(),
                 vout: *out as i64,
-                signature: vec![],
-                pub_key: wallet.public_key.clone(),
+                script_sig: vec![],
+                sequence,
             };
             txs_inputs.push(input);
         }
     }
 
-    let from = String::from_utf8(wallet.get_address()).unwrap();
     // transfer utxo to the "to" address
-    txs_outputs.push(TXOutput::new_tx_output(amount, to.clone()));
-
-    // change coins
-    if acc > amount {
-        txs_outputs.push(TXOutput::new_tx_output(acc - amount, from.clone()));
+    txs_outputs.push(TXOutput::new_tx_output(amount, to));
+
+    // change coins, at CHANGE_INDEX
+    if acc > amount + fee {
+        txs_outputs.push(TXOutput::new_script_output(
+            acc - amount - fee,
+            script_pubkey.to_vec(),
+        ));
     }
 
     let mut tx = Transaction {
         id: Vec::new(),
         vin: txs_inputs,
         vout: txs_outputs,
+        lock_time,
     };
     tx.id = tx.hash();
-    utxo_set
-        .get_blockchain()
-        .sign_transaction(&mut tx, &wallet.get_private_key());
-    tx
+    Ok(tx)
+}
+
+/*the sequence of the inputs of a new transaction. A relative lock also
+signals replace-by-fee, a lock time needs a sequence below SEQUENCE_FINAL
+to count. */
+pub fn input_sequence(
+    replaceable: bool,
+    lock_time: u32,
+    relative_lock: Option<RelativeLock>,
+) -> u32 {
+    match relative_lock {
+        Some(relative_lock) => relative_lock.to_sequence(),
+        None if replaceable => MAX_REPLACEABLE_SEQUENCE,
+        None if lock_time > 0 => SEQUENCE_LOCK_TIME,
+        None => SEQUENCE_FINAL,
+    }
+}
+
+/*builds the replacement of an unconfirmed replaceable transaction of
+the wallet, spending the same inputs and paying fee in total, or the
+least the mempool accepts for a replacement. The difference to the old fee
+is taken from the change output. Unconfirmed are the other transactions the
+wallet sent: they may be the parents of tx, or its descendants, which the
+replacement evicts and has to pay for as well. */
+pub fn bump_fee(
+    tx: &Transaction,
+    wallet: &Wallet,
+    fee: Option<i64>,
+    utxo_set: &UtxoSet,
+    unconfirmed: &HashMap<String, Transaction>,
+) -> Result<Transaction, String> {
+    if !tx.is_replaceable() {
+        return Err("the transaction does not signal replace-by-fee".to_string());
+    }
+    let prev_outs = find_prev_outs(tx, utxo_set, unconfirmed)
+        .ok_or("its inputs are spent or unknown, it may be confirmed already".to_string())?;
+    let old_fee = fee_of(tx, &prev_outs);
+    let replaced_fee = old_fee + descendants_fee(tx, utxo_set, unconfirmed);
+
+    let script_pubkey = script::p2pkh(&wallet::hash_pub_key(&wallet.public_key));
+    if !tx
+        .vout
+        .get(CHANGE_INDEX)
+        .is_some_and(|out| out.is_locked_with_script(&script_pubkey))
+    {
+        return Err("the transaction has no change output to take the fee from".to_string());
+    }
+    // the minimum fee depends on the size of the replacement, which depends on the fee
+    let mut new_fee = fee.unwrap_or(replaced_fee);
+    loop {
+        let bumped = with_fee(tx, new_fee - old_fee, wallet, &prev_outs)?;
+        let min_fee =
+            mempool::replacement_fee(replaced_fee, bincode::serialize(&bumped).unwrap().len());
+        if new_fee >= min_fee {
+            return Ok(bumped);
+        }
+        if fee.is_some() {
+            return Err(format!(
+                "the new fee must be at least {}, the {} of the transaction and its descendants plus the incremental relay fee",
+                min_fee, replaced_fee
+            ));
+        }
+        new_fee = min_fee;
+    }
+}
+
+// a signed copy of tx paying more in fees out of its change
+fn with_fee(
+    tx: &Transaction,
+    more: i64,
+    wallet: &Wallet,
+    prev_outs: &[TXOutput],
+) -> Result<Transaction, String> {
+    let mut bumped = Transaction {
+        id: Vec::new(),
+        vin: tx.trimmed_copy().vin,
+        vout: tx.vout.clone(),
+        lock_time: tx.lock_time,
+    };
+    let remaining = bumped.vout[CHANGE_INDEX].value - more;
+    if remaining < 0 {
+        return Err(format!(
+            "the change of {} cannot pay {} more in fees",
+            bumped.vout[CHANGE_INDEX].value, more
+        ));
+    }
+    if remaining == 0 {
+        bumped.vout.remove(CHANGE_INDEX);
+    } else {
+        bumped.vout[CHANGE_INDEX].value = remaining;
+    }
+    bumped.id = bumped.hash();
+    bumped.sign_inputs(&wallet.get_private_key(), prev_outs);
+    Ok(bumped)
+}
+
+// the outputs tx spends, from the UTXO set or from unconfirmed transactions
+fn find_prev_outs(
+    tx: &Transaction,
+    utxo_set: &UtxoSet,
+    unconfirmed: &HashMap<String, Transaction>,
+) -> Option<Vec<TXOutput>> {
+    tx.vin
+        .iter()
+        .map(|vin| {
+            utxo_set.find_output(&vin.txid, vin.vout).or_else(|| {
+                let parent = unconfirmed.get(&hex::encode(&vin.txid))?;
+                parent.vout.get(usize::try_from(vin.vout).ok()?).cloned()
+            })
+        })
+        .collect()
+}
+
+fn fee_of(tx: &Transaction, prev_outs: &[TXOutput]) -> i64 {
+    prev_outs.iter().map(|out| out.value).sum::<i64>()
+        - tx.vout.iter().map(|out| out.value).sum::<i64>()
+}
+
+// the fees of the unconfirmed transactions spending from tx, directly or not
+fn descendants_fee(
+    tx: &Transaction,
+    utxo_set: &UtxoSet,
+    unconfirmed: &HashMap<String, Transaction>,
+) -> i64 {
+    let mut fee = 0;
+    let mut seen = vec![tx.id.clone()];
+    let mut stack = vec![tx.id.clone()];
+    while let Some(id) = stack.pop() {
+        for child in unconfirmed.values() {
+            if seen.contains(&child.id) || !child.vin.iter().any(|vin| vin.txid == id) {
+                continue;
+            }
+            if let Some(prev_outs) = find_prev_outs(child, utxo_set, unconfirmed) {
+                fee += fee_of(child, &prev_outs);
+            }
+            seen.push(child.id.clone());
+            stack.push(child.id.clone());
+        }
+    }
+    fee
 }
 
 pub fn ecdsa_sign(private_key: &Vec<u8>, data: &Vec<u8>) -> Vec<u8> {
@@ -268,8 +592,203 @@ pub fn ecdsa_sign(private_key: &Vec<u8>, data: &Vec<u8>) -> Vec<u8> {
     key_pair.sign(&rng, data).unwrap().as_ref().to_vec()
 }
 
+// the public key of a PKCS#8 private key
+pub fn public_key(private_key: &[u8]) -> Vec<u8> {
+    signature::EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, private_key)
+        .unwrap()
+        .public_key()
+        .as_ref()
+        .to_vec()
+}
+
 pub fn ecdsa_sign_verify(public_key: &Vec<u8>, data: &Vec<u8>, signature: &Vec<u8>) -> bool {
     let public_key =
         signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, public_key);
     public_key.verify(data, signature).is_ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mempool::Mempool;
+    use crate::test_util;
+    use crate::MempoolLimits;
+
+    fn replaceable() -> u32 {
+        input_sequence(true, 0, None)
+    }
+
+    fn sent(txs: &[&Transaction]) -> HashMap<String, Transaction> {
+        txs.iter()
+            .map(|tx| (hex::encode(tx.get_id()), (*tx).clone()))
+            .collect()
+    }
+
+    #[test]
+    fn a_transaction_with_an_unconfirmed_parent_is_bumped() {
+        let (wallet, bc) = test_util::new_chain("bump_unconfirmed_parent");
+        let utxo_set = UtxoSet::new(bc.clone());
+        let payer = Wallet::new_wallet();
+        let parent = new_utxo_transaction(
+            &wallet,
+            test_util::address(&payer),
+            5,
+            1,
+            SEQUENCE_FINAL,
+            0,
+            &utxo_set,
+        );
+        let child = test_util::spend_child(
+            &bc,
+            "bump_unconfirmed_parent",
+            &parent,
+            &payer,
+            1,
+            1,
+            replaceable(),
+        );
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        mempool.add(parent.clone(), &utxo_set).unwrap();
+        mempool.add(child.clone(), &utxo_set).unwrap();
+
+        let bumped = bump_fee(&child, &payer, None, &utxo_set, &sent(&[&parent, &child])).unwrap();
+        let replaced = mempool.add(bumped.clone(), &utxo_set).unwrap();
+        assert_eq!(replaced.len(), 1);
+        assert_eq!(replaced[0].get_id(), child.get_id());
+        assert!(mempool.get_fee(&bumped.get_id()).unwrap() > 1);
+    }
+
+    #[test]
+    fn a_bump_pays_for_the_descendants_and_its_own_size() {
+        let (wallet, bc) = test_util::new_chain("bump_descendants");
+        let utxo_set = UtxoSet::new(bc.clone());
+        let payer = Wallet::new_wallet();
+        let parent = new_utxo_transaction(
+            &wallet,
+            test_util::address(&payer),
+            5,
+            1,
+            replaceable(),
+            0,
+            &utxo_set,
+        );
+        let child = test_util::spend_child(
+            &bc,
+            "bump_descendants",
+            &parent,
+            &payer,
+            1,
+            2,
+            SEQUENCE_FINAL,
+        );
+        let sent_txs = sent(&[&parent, &child]);
+        let mut mempool = Mempool::new(bc.get_tip_hash(), MempoolLimits::default());
+        mempool.add(parent.clone(), &utxo_set).unwrap();
+        mempool.add(child, &utxo_set).unwrap();
+
+        // the fee of the parent and the child is not enough
+        assert!(bump_fee(&parent, &wallet, Some(3), &utxo_set, &sent_txs).is_err());
+        let bumped = bump_fee(&parent, &wallet, None, &utxo_set, &sent_txs).unwrap();
+        let size = bincode::serialize(&bumped).unwrap().len();
+        let min_fee = mempool::replacement_fee(3, size);
+        assert_eq!(
+            bumped.vout[CHANGE_INDEX].value,
+            parent.vout[CHANGE_INDEX].value - (min_fee - 1)
+        );
+        assert_eq!(mempool.add(bumped.clone(), &utxo_set).unwrap().len(), 2);
+        assert_eq!(mempool.get_fee(&bumped.get_id()), Some(min_fee));
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn a_transaction_without_change_is_not_bumped() {
+        let (wallet, bc) = test_util::new_chain("bump_no_change");
+        let utxo_set = UtxoSet::new(bc.clone());
+        let to = test_util::address(&Wallet::new_wallet());
+        let tx = new_utxo_transaction(&wallet, to, SUBSIDY - 1, 1, replaceable(), 0, &utxo_set);
+        assert_eq!(tx.vout.len(), 1);
+        assert!(bump_fee(&tx, &wallet, Some(2), &utxo_set, &HashMap::new()).is_err());
+    }
+
+    fn locked_tx(lock_time: u32, sequence: u32) -> Transaction {
+        Transaction {
+            id: vec![],
+            vin: vec![TXInput {
+                txid: vec![1; 32],
+                vout: 0,
+                script_sig: vec![],
+                sequence,
+            }],
+            vout: vec![],
+            lock_time,
+        }
+    }
+
+    #[test]
+    fn without_lock_time_a_transaction_is_final() {
+        assert!(locked_tx(0, SEQUENCE_LOCK_TIME).is_final(0, 0));
+    }
+
+    #[test]
+    fn height_lock_time_passes_after_the_block() {
+        let tx = locked_tx(100, SEQUENCE_LOCK_TIME);
+        assert!(!tx.is_final(99, i64::MAX));
+        assert!(!tx.is_final(100, i64::MAX));
+        assert!(tx.is_final(101, 0));
+    }
+
+    #[test]
+    fn time_lock_time_passes_after_the_time() {
+        let lock_time = LOCK_TIME_THRESHOLD + 1000;
+        let tx = locked_tx(lock_time, SEQUENCE_LOCK_TIME);
+        assert!(!tx.is_final(usize::MAX, lock_time as i64));
+        assert!(tx.is_final(0, lock_time as i64 + 1));
+    }
+
+    #[test]
+    fn final_sequences_disable_the_lock_time() {
+        assert!(locked_tx(100, SEQUENCE_FINAL).is_final(1, 0));
+    }
+
+    #[test]
+    fn relative_locks_round_trip_through_the_sequence() {
+        for lock in [
+            RelativeLock::Blocks(0),
+            RelativeLock::Blocks(144),
+            RelativeLock::Seconds(512),
+            RelativeLock::Seconds(512 * 100),
+        ] {
+            let tx = locked_tx(0, lock.to_sequence());
+            assert_eq!(tx.vin[0].get_relative_lock(), Some(lock));
+        }
+        // times round up to whole 512 second units, both kinds to the largest one
+        let seconds = locked_tx(0, RelativeLock::Seconds(1000).to_sequence());
+        assert_eq!(
+            seconds.vin[0].get_relative_lock(),
+            Some(RelativeLock::Seconds(1024))
+        );
+        let blocks = locked_tx(0, RelativeLock::Blocks(u32::MAX).to_sequence());
+        assert_eq!(
+            blocks.vin[0].get_relative_lock(),
+            Some(RelativeLock::Blocks(SEQUENCE_LOCK_MASK))
+        );
+    }
+
+    #[test]
+    fn relative_locks_are_off_with_the_disable_flag() {
+        for sequence in [SEQUENCE_FINAL, SEQUENCE_LOCK_TIME, MAX_REPLACEABLE_SEQUENCE] {
+            assert_eq!(locked_tx(0, sequence).vin[0].get_relative_lock(), None);
+        }
+    }
+
+    #[test]
+    fn input_sequence_signals_what_is_asked() {
+        let relative_lock = RelativeLock::Blocks(10);
+        assert_eq!(input_sequence(false, 0, None), SEQUENCE_FINAL);
+        assert_eq!(input_sequence(false, 100, None), SEQUENCE_LOCK_TIME);
+        assert_eq!(input_sequence(true, 100, None), MAX_REPLACEABLE_SEQUENCE);
+        let sequence = input_sequence(false, 0, Some(relative_lock));
+        assert_eq!(sequence, relative_lock.to_sequence());
+        assert!(locked_tx(0, sequence).is_replaceable());
+    }
+}
@@ -0,0 +1,424 @@
+use crate::message;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::agreement::{self, EphemeralPrivateKey, X25519};
+use ring::error::Unspecified;
+use ring::hkdf::{Prk, Salt, HKDF_SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, ED25519};
+use sha2::{Digest, Sha256};
+use std::env::current_dir;
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+const NODE_KEY_FILE: &str = "nodekey_{}.dat";
+const PROTOCOL_NAME: &[u8] = b"blockchain-rust transport v1"; // binds the keys to this protocol
+const KEY_LENGTH: usize = 32; // of X25519 and Ed25519 public keys
+const SIGNATURE_LENGTH: usize = 64;
+
+/*The static key a node is known by. Other nodes pin its public key
+in their allowlist; it signs every key exchange of the node. */
+pub struct NodeKey {
+    key_pair: Ed25519KeyPair,
+}
+
+/*loads the node key from the data dir, creating it on first use. The
+file is readable by the owner only, anyone reading it can pass as the node. */
+pub fn load_node_key(node_id: &str) -> Result<NodeKey, String> {
+    let path = current_dir()
+        .unwrap()
+        .join(NODE_KEY_FILE.replace("{}", node_id));
+    let pkcs8 = match fs::read(&path) {
+        Ok(pkcs8) => pkcs8,
+        Err(_) => {
+            let pkcs8 =
+                Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|e| e.to_string())?;
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+                .and_then(|mut file| file.write_all(pkcs8.as_ref()))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            pkcs8.as_ref().to_vec()
+        }
+    };
+    let key_pair =
+        Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(NodeKey { key_pair })
+}
+
+impl NodeKey {
+    pub fn get_public_key(&self) -> Vec<u8> {
+        self.key_pair.public_key().as_ref().to_vec()
+    }
+}
+
+/*Encrypted and authenticated sessions between nodes.
+
+Both sides send an ephemeral X25519 key in a plain transport message.
+The shared secret is expanded with HKDF into one ChaCha20-Poly1305 key
+per direction. Then each side sends its node key and an Ed25519
+signature over both ephemeral keys, encrypted, so a man in the middle
+can neither read the session nor pass as either node. From then on
+every framed message travels sealed in a length prefixed record, with
+a counter as nonce. */
+pub struct Transport {
+    node_key: NodeKey,
+    allowed_peers: Vec<Vec<u8>>, // node keys accepted, empty to accept any node
+}
+
+// the keys of an established session
+pub struct Session {
+    pub sealer: Sealer,
+    pub opener: Opener,
+    pub remote_key: Vec<u8>, // the node key of the peer
+}
+
+impl Transport {
+    pub fn new(node_key: NodeKey, allowed_peers: Vec<Vec<u8>>) -> Transport {
+        Transport {
+            node_key,
+            allowed_peers,
+        }
+    }
+
+    pub fn get_public_key(&self) -> Vec<u8> {
+        self.node_key.get_public_key()
+    }
+
+    // runs the key exchange on a new connection, initiator is the side that connected
+    pub fn handshake(
+        &self,
+        stream: &mut (impl Read + Write),
+        initiator: bool,
+    ) -> Result<Session, Error> {
+        let rng = SystemRandom::new();
+        let ephemeral = EphemeralPrivateKey::generate(&X25519, &rng).map_err(crypto_error)?;
+        let ephemeral_public = ephemeral
+            .compute_public_key()
+            .map_err(crypto_error)?
+            .as_ref()
+            .to_vec();
+        message::write_message(stream, "transport", &ephemeral_public)?;
+        let remote_ephemeral = match message::read_message(stream)? {
+            Some((command, payload)) if command == "transport" && payload.len() == KEY_LENGTH => {
+                payload
+            }
+            Some((command, _)) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("expected an encrypted handshake, got {}", command),
+                ))
+            }
+            None => return Err(closed_during_handshake()),
+        };
+
+        let (initiator_ephemeral, responder_ephemeral) = if initiator {
+            (&ephemeral_public, &remote_ephemeral)
+        } else {
+            (&remote_ephemeral, &ephemeral_public)
+        };
+        let transcript = Sha256::new()
+            .chain_update(PROTOCOL_NAME)
+            .chain_update(initiator_ephemeral)
+            .chain_update(responder_ephemeral)
+            .finalize()
+            .to_vec();
+        let (send_key, receive_key) = agreement::agree_ephemeral(
+            ephemeral,
+            &agreement::UnparsedPublicKey::new(&X25519, &remote_ephemeral),
+            Unspecified,
+            |secret| {
+                let prk = Salt::new(HKDF_SHA256, &transcript).extract(secret);
+                let initiator_key = derive_key(&prk, b"initiator")?;
+                let responder_key = derive_key(&prk, b"responder")?;
+                if initiator {
+                    Ok((initiator_key, responder_key))
+                } else {
+                    Ok((responder_key, initiator_key))
+                }
+            },
+        )
+        .map_err(crypto_error)?;
+        let mut sealer = Sealer::new(send_key);
+        let mut opener = Opener::new(receive_key);
+
+        let mut auth = self.get_public_key();
+        auth.extend(
+            self.node_key
+                .key_pair
+                .sign(&signed_transcript(&transcript, initiator))
+                .as_ref(),
+        );
+        sealer.write_message(stream, "auth", &auth)?;
+        let auth = match opener.read_message(stream)? {
+            Some((command, payload))
+                if command == "auth" && payload.len() == KEY_LENGTH + SIGNATURE_LENGTH =>
+            {
+                payload
+            }
+            Some((command, _)) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("expected auth, got {}", command),
+                ))
+            }
+            None => return Err(closed_during_handshake()),
+        };
+        let (remote_key, remote_signature) = auth.split_at(KEY_LENGTH);
+        signature::UnparsedPublicKey::new(&ED25519, remote_key)
+            .verify(
+                &signed_transcript(&transcript, !initiator),
+                remote_signature,
+            )
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid node key signature"))?;
+        if !self.allowed_peers.is_empty() && !self.allowed_peers.iter().any(|key| key == remote_key)
+        {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("node key {} is not allowed", hex::encode(remote_key)),
+            ));
+        }
+
+        Ok(Session {
+            sealer,
+            opener,
+            remote_key: remote_key.to_vec(),
+        })
+    }
+}
+
+// encrypts the messages of one direction of a session
+pub struct Sealer {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Sealer {
+    fn new(key: LessSafeKey) -> Sealer {
+        Sealer { key, counter: 0 }
+    }
+
+    pub fn write_message(
+        &mut self,
+        stream: &mut impl Write,
+        command: &str,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        let mut record = vec![];
+        message::write_message(&mut record, command, payload)?;
+        let nonce = next_nonce(&mut self.counter)?;
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut record)
+            .map_err(crypto_error)?;
+        let mut data = (record.len() as u32).to_le_bytes().to_vec();
+        data.extend(record);
+        stream.write_all(&data)?;
+        stream.flush()
+    }
+}
+
+// decrypts the messages of one direction of a session
+pub struct Opener {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Opener {
+    fn new(key: LessSafeKey) -> Opener {
+        Opener { key, counter: 0 }
+    }
+
+    // reads one record, None when the peer closed the connection between two records
+    pub fn read_message(
+        &mut self,
+        stream: &mut impl Read,
+    ) -> Result<Option<(String, Vec<u8>)>, Error> {
+        let mut length = [0u8; 4];
+        if let Err(e) = stream.read_exact(&mut length[..1]) {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                return Ok(None);
+            }
+            return Err(e);
+        }
+        stream.read_exact(&mut length[1..])?;
+        let length = u32::from_le_bytes(length) as usize;
+        if length > message::MAX_FRAME_SIZE + CHACHA20_POLY1305.tag_len() {
+            return Err(Error::new(ErrorKind::InvalidData, "record is too large"));
+        }
+
+        let mut record = vec![0; length];
+        stream.read_exact(&mut record)?;
+        let nonce = next_nonce(&mut self.counter)?;
+        let mut frame: &[u8] = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut record)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "record failed authentication"))?;
+        match message::read_message(&mut frame)? {
+            Some(message) => Ok(Some(message)),
+            None => Err(Error::new(ErrorKind::InvalidData, "empty record")),
+        }
+    }
+}
+
+// writes a message, sealed if the session is encrypted
+pub fn write_message(
+    stream: &mut impl Write,
+    sealer: Option<&mut Sealer>,
+    command: &str,
+    payload: &[u8],
+) -> Result<(), Error> {
+    match sealer {
+        Some(sealer) => sealer.write_message(stream, command, payload),
+        None => message::write_message(stream, command, payload),
+    }
+}
+
+// reads a message, opening it if the session is encrypted
+pub fn read_message(
+    stream: &mut impl Read,
+    opener: Option<&mut Opener>,
+) -> Result<Option<(String, Vec<u8>)>, Error> {
+    match opener {
+        Some(opener) => opener.read_message(stream),
+        None => message::read_message(stream),
+    }
+}
+
+fn derive_key(prk: &Prk, info: &[u8]) -> Result<LessSafeKey, Unspecified> {
+    let info = [info];
+    let okm = prk.expand(&info, &CHACHA20_POLY1305)?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+// what a side signs: its role, so a signature cannot be reflected, and the transcript
+fn signed_transcript(transcript: &[u8], initiator: bool) -> Vec<u8> {
+    let mut data = vec![u8::from(initiator)];
+    data.extend(transcript);
+    data
+}
+
+// the nonce of the next record, a key never seals two records with the same nonce
+fn next_nonce(counter: &mut u64) -> Result<Nonce, Error> {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - 8..].copy_from_slice(&counter.to_le_bytes());
+    *counter = counter
+        .checked_add(1)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "session nonces exhausted"))?;
+    Ok(Nonce::assume_unique_for_key(nonce))
+}
+
+fn crypto_error(_: Unspecified) -> Error {
+    Error::other("cryptographic operation failed")
+}
+
+fn closed_during_handshake() -> Error {
+    Error::new(
+        ErrorKind::UnexpectedEof,
+        "connection closed during the encrypted handshake",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::fs::PermissionsExt;
+    use std::thread;
+
+    fn new_node_key() -> NodeKey {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        NodeKey {
+            key_pair: Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap(),
+        }
+    }
+
+    // runs the handshake of both sides over a loopback connection
+    fn handshake(
+        initiator: Transport,
+        responder: Transport,
+    ) -> (
+        Result<Session, Error>,
+        Result<Session, Error>,
+        TcpStream,
+        TcpStream,
+    ) {
+        let ln = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = ln.local_addr().unwrap();
+        let responding = thread::spawn(move || {
+            let (mut stream, _) = ln.accept().unwrap();
+            (responder.handshake(&mut stream, false), stream)
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let initiated = initiator.handshake(&mut stream, true);
+        let (responded, accepted) = responding.join().unwrap();
+        (initiated, responded, stream, accepted)
+    }
+
+    #[test]
+    fn sessions_carry_messages_both_ways() {
+        let initiator = Transport::new(new_node_key(), vec![]);
+        let responder = Transport::new(new_node_key(), vec![]);
+        let (initiator_key, responder_key) =
+            (initiator.get_public_key(), responder.get_public_key());
+        let (initiated, responded, mut stream, mut accepted) = handshake(initiator, responder);
+        let (mut initiated, mut responded) = (initiated.unwrap(), responded.unwrap());
+        assert_eq!(initiated.remote_key, responder_key);
+        assert_eq!(responded.remote_key, initiator_key);
+
+        initiated
+            .sealer
+            .write_message(&mut stream, "ping", b"12345678")
+            .unwrap();
+        let received = responded.opener.read_message(&mut accepted).unwrap();
+        assert_eq!(received, Some(("ping".to_string(), b"12345678".to_vec())));
+        responded
+            .sealer
+            .write_message(&mut accepted, "pong", b"12345678")
+            .unwrap();
+        let received = initiated.opener.read_message(&mut stream).unwrap();
+        assert_eq!(received, Some(("pong".to_string(), b"12345678".to_vec())));
+    }
+
+    #[test]
+    fn node_keys_outside_the_allowlist_are_refused() {
+        let initiator = Transport::new(new_node_key(), vec![]);
+        let responder = Transport::new(new_node_key(), vec![new_node_key().get_public_key()]);
+        let (_, responded, _, _) = handshake(initiator, responder);
+        assert_eq!(
+            responded.err().map(|e| e.kind()),
+            Some(ErrorKind::PermissionDenied)
+        );
+    }
+
+    #[test]
+    fn tampered_records_fail_authentication() {
+        let mut sealer = Sealer::new(LessSafeKey::new(
+            UnboundKey::new(&CHACHA20_POLY1305, &[7; 32]).unwrap(),
+        ));
+        let mut opener = Opener::new(LessSafeKey::new(
+            UnboundKey::new(&CHACHA20_POLY1305, &[7; 32]).unwrap(),
+        ));
+        let mut record = vec![];
+        sealer.write_message(&mut record, "tx", b"payload").unwrap();
+        let last = record.len() - 1;
+        record[last] ^= 1;
+        let error = opener.read_message(&mut record.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn the_node_key_is_created_once_and_readable_by_the_owner_only() {
+        test_util::enter_data_dir();
+        let node_key = load_node_key("transport_node_key").unwrap();
+        let path = current_dir()
+            .unwrap()
+            .join(NODE_KEY_FILE.replace("{}", "transport_node_key"));
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let reloaded = load_node_key("transport_node_key").unwrap();
+        assert_eq!(reloaded.get_public_key(), node_key.get_public_key());
+    }
+}
@@ -1,10 +1,22 @@
 use crate::Block;
 use crate::Blockchain;
+use crate::BlockchainIterator;
 use crate::TXOutput;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 const UTXO_TREE: &str = "chainstate";
+const UNDO_TREE: &str = "undo"; // block hash -> the UTXO entries the block changed, as they were before
+const TX_INDEX_TREE: &str = "tx_index"; // transaction id -> hash of the main chain block it is in
+const UTXO_TIP_KEY: &str = "chainstate_tip"; // key for the hash of the block the UTXO set is at
 
+// an entry of the UTXO set as it was before a block changed it, None if there was none
+type UndoEntry = (Vec<u8>, Option<Vec<Option<TXOutput>>>);
+
+/*The unspent outputs of the main chain, kept in their own tree of the
+chain db. It maps a transaction id to its outputs, with spent outputs set
+to None so the others keep their index. Every block applied keeps undo
+data, so the set follows the tip through reorgs without a rebuild. The
+ids of the main chain transactions are kept along, spent or not. */
 pub struct UtxoSet {
     blockchain: Blockchain,
 }
@@ -14,11 +26,20 @@ impl UtxoSet {
         UtxoSet { blockchain }
     }
 
-    // rebuilds the UTXO set
+    // rebuilds the UTXO set and the transaction index, the undo data of the blocks below is dropped
     pub fn reindex(&self) {
         let db = self.blockchain.get_db();
         let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
         utxo_tree.clear().unwrap();
+        db.open_tree(UNDO_TREE).unwrap().clear().unwrap();
+        let tx_index = db.open_tree(TX_INDEX_TREE).unwrap();
+        tx_index.clear().unwrap();
+        let mut iterator = BlockchainIterator::iterator(&self.blockchain);
+        while let Some(block) = iterator.next() {
+            for tx in block.get_transactions() {
+                tx_index.insert(tx.get_id(), block.get_hash()).unwrap();
+            }
+        }
 
         let utxo_map = self.blockchain.find_utxo();
         for (tx_hex, outs) in &utxo_map {
@@ -26,12 +47,60 @@ impl UtxoSet {
             let value = bincode::serialize(outs).unwrap();
             utxo_tree.insert(txid, value).unwrap();
         }
+        db.insert(UTXO_TIP_KEY, self.blockchain.get_tip_hash())
+            .unwrap();
+    }
+
+    // the hash of the block the UTXO set is at, None if it was built before it was recorded
+    pub fn get_tip(&self) -> Option<Vec<u8>> {
+        let db = self.blockchain.get_db();
+        db.get(UTXO_TIP_KEY).unwrap().map(|tip| tip.to_vec())
+    }
+
+    /*moves the UTXO set to the tip of the chain, undoing the blocks that
+    left the main chain and applying the ones that joined it. It is rebuilt
+    if it is at an unknown block, or undo data or the index is missing. */
+    pub fn update_to_tip(&self) {
+        let tip = self.blockchain.get_tip_hash();
+        let db = self.blockchain.get_db();
+        if db.open_tree(TX_INDEX_TREE).unwrap().is_empty() {
+            return self.reindex();
+        }
+        let utxo_tip = match self.get_tip() {
+            Some(utxo_tip) if utxo_tip == tip => return,
+            Some(utxo_tip) if self.blockchain.has_block(&utxo_tip) => utxo_tip,
+            _ => return self.reindex(),
+        };
+        let (disconnected, connected) = self.blockchain.find_fork(&utxo_tip);
+        for block in &disconnected {
+            if !self.undo(block) {
+                return self.reindex();
+            }
+        }
+        for block in connected {
+            self.update(block);
+        }
+    }
+
+    // the outputs of transaction txid, spent ones None, if any is unspent
+    pub fn find_outputs(&self, txid: &[u8]) -> Option<Vec<Option<TXOutput>>> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let outs_bytes = utxo_tree.get(txid).unwrap()?;
+        Some(bincode::deserialize(&outs_bytes).unwrap())
+    }
+
+    // whether transaction txid is in a block of the main chain
+    pub fn is_confirmed(&self, txid: &[u8]) -> bool {
+        let db = self.blockchain.get_db();
+        let tx_index = db.open_tree(TX_INDEX_TREE).unwrap();
+        tx_index.contains_key(txid).unwrap()
     }
 
     // finds and returns unspent outputs to reference in inputs
     pub fn find_spendable_outputs(
         &self,
-        pub_key_hash: &Vec<u8>,
+        script_pubkey: &[u8],
         amount: i64,
     ) -> (i64, HashMap<String, Vec<usize>>) {
         let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
@@ -42,9 +111,13 @@ impl UtxoSet {
         for item in utxo_tree.iter() {
             let (k, v) = item.unwrap();
             let txid = hex::encode(k);
-            let outs: Vec<TXOutput> = bincode::deserialize(&v).unwrap();
+            let outs: Vec<Option<TXOutput>> = bincode::deserialize(&v).unwrap();
             for (idx, out) in outs.iter().enumerate() {
-                if out.is_locked_with_key(pub_key_hash) && accumulated < amount {
+                let out = match out {
+                    Some(out) => out,
+                    None => continue,
+                };
+                if out.is_locked_with_script(script_pubkey) && accumulated < amount {
                     accumulated += out.get_value();
                     unspent_outputs
                         .entry(txid.clone())
@@ -57,17 +130,17 @@ impl UtxoSet {
         (accumulated, unspent_outputs)
     }
 
-    // finds UTXO for a public key hash
-    pub fn find_utxo(&self, pub_key_hash: &Vec<u8>) -> Vec<TXOutput> {
+    // finds UTXO locked with a script
+    pub fn find_utxo(&self, script_pubkey: &[u8]) -> Vec<TXOutput> {
         let mut utxo: Vec<TXOutput> = Vec::new();
         let db = self.blockchain.get_db();
         let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
 
         for item in utxo_tree.iter() {
             let (_, v) = item.unwrap();
-            let outs: Vec<TXOutput> = bincode::deserialize(&v).unwrap();
-            for out in outs {
-                if out.is_locked_with_key(pub_key_hash) {
+            let outs: Vec<Option<TXOutput>> = bincode::deserialize(&v).unwrap();
+            for out in outs.into_iter().flatten() {
+                if out.is_locked_with_script(script_pubkey) {
                     utxo.push(out);
                 }
             }
@@ -76,24 +149,43 @@ impl UtxoSet {
         utxo
     }
 
+    // returns the output vout of transaction txid if it is unspent
+    pub fn find_output(&self, txid: &[u8], vout: i64) -> Option<TXOutput> {
+        let outs = self.find_outputs(txid)?;
+        let index = usize::try_from(vout).ok()?;
+        outs.get(index).cloned().flatten()
+    }
+
     /*updates the UTXO set with transactions from the Block
     The Block is considered to be the tip of a blockchain */
     pub fn update(&self, block: Block) {
         let db = self.blockchain.get_db();
         let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let tx_index = db.open_tree(TX_INDEX_TREE).unwrap();
+        let mut undo: Vec<UndoEntry> = vec![];
+        let mut saved = HashSet::new();
+        // each entry is saved for the undo data before the block first changes it
+        let mut save = |txid: Vec<u8>| {
+            if saved.insert(txid.clone()) {
+                let outs = utxo_tree
+                    .get(&txid)
+                    .unwrap()
+                    .map(|outs_bytes| bincode::deserialize(&outs_bytes).unwrap());
+                undo.push((txid, outs));
+            }
+        };
 
         for tx in block.get_transactions() {
             if !tx.is_coinbase() {
                 for vin in tx.get_vin() {
-                    let mut updated_outs: Vec<TXOutput> = Vec::new();
+                    save(vin.get_txid());
                     let outs_bytes = utxo_tree.get(vin.get_txid()).unwrap().unwrap();
-                    let outs: Vec<TXOutput> = bincode::deserialize(&outs_bytes).unwrap();
-                    for (out_idx, out) in outs.iter().enumerate() {
-                        if out_idx != vin.get_vout() as usize {
-                            updated_outs.push(out.clone());
-                        }
+                    let mut updated_outs: Vec<Option<TXOutput>> =
+                        bincode::deserialize(&outs_bytes).unwrap();
+                    if let Some(out) = updated_outs.get_mut(vin.get_vout() as usize) {
+                        *out = None;
                     }
-                    if updated_outs.len() == 0 {
+                    if updated_outs.iter().all(|out| out.is_none()) {
                         utxo_tree.remove(vin.get_txid()).unwrap();
                     } else {
                         let outs_bytes = bincode::serialize(&updated_outs).unwrap();
@@ -102,13 +194,48 @@ impl UtxoSet {
                 }
             }
 
-            let mut new_outputs: Vec<TXOutput> = Vec::new();
+            save(tx.get_id());
+            tx_index.insert(tx.get_id(), block.get_hash()).unwrap();
+            let mut new_outputs: Vec<Option<TXOutput>> = Vec::new();
             for out in tx.get_vout() {
-                new_outputs.push(out.clone());
+                new_outputs.push(Some(out.clone()));
             }
             let outs_bytes = bincode::serialize(&new_outputs).unwrap();
             utxo_tree.insert(tx.get_id(), outs_bytes).unwrap();
         }
+
+        let undo_tree = db.open_tree(UNDO_TREE).unwrap();
+        undo_tree
+            .insert(block.get_hash(), bincode::serialize(&undo).unwrap())
+            .unwrap();
+        db.insert(UTXO_TIP_KEY, block.get_hash()).unwrap();
+    }
+
+    /*restores the UTXO set from before block, which must be the block it
+    is at. Returns false if there is no undo data for the block. */
+    fn undo(&self, block: &Block) -> bool {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let undo_tree = db.open_tree(UNDO_TREE).unwrap();
+        let undo: Vec<UndoEntry> = match undo_tree.remove(block.get_hash()).unwrap() {
+            Some(undo_bytes) => bincode::deserialize(&undo_bytes).unwrap(),
+            None => return false,
+        };
+        for (txid, outs) in undo {
+            match outs {
+                Some(outs) => utxo_tree
+                    .insert(txid, bincode::serialize(&outs).unwrap())
+                    .unwrap(),
+                None => utxo_tree.remove(txid).unwrap(),
+            };
+        }
+        let tx_index = db.open_tree(TX_INDEX_TREE).unwrap();
+        for tx in block.get_transactions() {
+            tx_index.remove(tx.get_id()).unwrap();
+        }
+        db.insert(UTXO_TIP_KEY, block.get_prev_block_hash())
+            .unwrap();
+        true
     }
 
     pub fn get_blockchain(&self) -> &Blockchain {
@@ -125,3 +252,87 @@ impl UtxoSet {
         count
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+    use crate::transaction;
+    use crate::wallet;
+    use crate::Wallet;
+
+    #[test]
+    fn the_utxo_set_follows_a_reorg_without_a_rebuild() {
+        let (wallet, mut bc) = test_util::new_chain("utxo_reorg");
+        let genesis = bc.get_block(&bc.get_tip_hash()).unwrap();
+        let to = Wallet::new_wallet();
+        let tx = transaction::new_utxo_transaction(
+            &wallet,
+            test_util::address(&to),
+            3,
+            0,
+            transaction::SEQUENCE_FINAL,
+            0,
+            &UtxoSet::new(bc.clone()),
+        );
+        test_util::mine(&mut bc, vec![tx.clone()], &wallet);
+        let utxo_set = UtxoSet::new(bc.clone());
+        assert_eq!(utxo_set.get_tip(), Some(bc.get_tip_hash()));
+        assert!(utxo_set.find_output(&tx.get_id(), 0).is_some());
+        assert!(utxo_set.is_confirmed(&tx.get_id()));
+
+        // a longer chain without tx replaces the block with it
+        let miner = Wallet::new_wallet();
+        let mut prev_hash = genesis.get_hash();
+        for height in 1..=2 {
+            test_util::wait_for_block_time();
+            let coinbase = transaction::new_coinbase_tx(
+                test_util::address(&miner),
+                format!("Height {}", height),
+                0,
+            );
+            let block = Block::new_block(vec![coinbase], prev_hash, height);
+            prev_hash = block.get_hash();
+            bc.add_block(block);
+        }
+        let utxo_set = UtxoSet::new(bc.clone());
+        utxo_set.update_to_tip();
+        assert_eq!(utxo_set.get_tip(), Some(bc.get_tip_hash()));
+        assert!(utxo_set.find_output(&tx.get_id(), 0).is_none());
+        assert!(!utxo_set.is_confirmed(&tx.get_id()));
+        let genesis_coinbase = &genesis.get_transactions()[0];
+        assert!(utxo_set
+            .find_output(&genesis_coinbase.get_id(), 0)
+            .is_some());
+
+        let outputs = |utxo_set: &UtxoSet| {
+            [&wallet, &to, &miner]
+                .iter()
+                .map(|w| {
+                    let script = wallet::locking_script(&test_util::address(w));
+                    utxo_set.find_utxo(&script).len()
+                })
+                .collect::<Vec<_>>()
+        };
+        let followed = outputs(&utxo_set);
+        assert_eq!(followed, vec![1, 0, 2]);
+        utxo_set.reindex();
+        assert_eq!(outputs(&utxo_set), followed);
+    }
+
+    #[test]
+    fn a_utxo_set_without_undo_data_is_rebuilt() {
+        let (wallet, mut bc) = test_util::new_chain("utxo_rebuild");
+        test_util::mine(&mut bc, vec![], &wallet);
+        let utxo_set = UtxoSet::new(bc.clone());
+        // as left by a version that did not record where the set is
+        bc.get_db().remove(UTXO_TIP_KEY).unwrap();
+        bc.get_db().open_tree(UTXO_TREE).unwrap().clear().unwrap();
+        utxo_set.update_to_tip();
+        assert_eq!(utxo_set.get_tip(), Some(bc.get_tip_hash()));
+        assert_eq!(utxo_set.count_transactions(), 2);
+        assert!(utxo_set.is_confirmed(
+            &bc.get_block(&bc.get_tip_hash()).unwrap().get_transactions()[0].get_id()
+        ));
+    }
+}
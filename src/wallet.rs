@@ -1,3 +1,4 @@
+use crate::script;
 use bs58;
 use ring::rand::SystemRandom;
 use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
@@ -8,9 +9,12 @@ use sha2::{Digest, Sha256};
 /* Bitcoin Address
 Version  Public key hash                           Checksum
 00       62E907B15CBF27D5425399EBF6F0FB50EBB88F18  C29B7D93
-*/
+
+Addresses with version 05 hold the hash of a redeem script instead, as
+multisig addresses do. */
 
 const VERSION: u8 = 0x00;
+const SCRIPT_VERSION: u8 = 0x05;
 pub const WALLET_FILE: &str = "wallet_{}.dat";
 pub const ADDRESS_CHECK_SUM_LEN: usize = 4;
 
@@ -97,3 +101,23 @@ pub fn calc_address(pub_hash_key: &Vec<u8>) -> String {
     payload.extend(check_sum);
     bs58::encode(payload).into_string()
 }
+
+// calculate address from the hash of a redeem script
+pub fn calc_script_address(script_hash: &[u8]) -> String {
+    let mut payload = vec![SCRIPT_VERSION];
+    payload.extend(script_hash);
+    let check_sum = check_sum(&payload);
+    payload.extend(check_sum);
+    bs58::encode(payload).into_string()
+}
+
+// the locking script of outputs paying to a valid address
+pub fn locking_script(address: &str) -> Vec<u8> {
+    let payload = bs58::decode(address).into_vec().unwrap();
+    let hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
+    if payload[0] == SCRIPT_VERSION {
+        script::p2sh(hash)
+    } else {
+        script::p2pkh(hash)
+    }
+}
@@ -1,11 +1,17 @@
 use crate::wallet;
+use crate::Transaction;
 use crate::Wallet;
 use bincode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::env::current_dir;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Write};
 
+const SENT_TXS_FILE: &str = "sent_txs_{}.dat"; // the transactions the wallets submitted, by hex id
+const REDEEM_SCRIPTS_FILE: &str = "redeem_scripts_{}.dat"; // the redeem scripts of multisig addresses, by address
+
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
 }
@@ -52,6 +58,14 @@ impl Wallets {
         None
     }
 
+    // returns the Wallet owning a public key
+    pub fn find_wallet(&self, pub_key: &[u8]) -> Option<Wallet> {
+        self.wallets
+            .values()
+            .find(|wallet| wallet.public_key == pub_key)
+            .cloned()
+    }
+
     // adds a Wallet to Wallets
     pub fn create_wallet(&mut self) -> String {
         let wallet = Wallet::new_wallet();
@@ -73,3 +87,53 @@ impl Wallets {
         file.write_all(&wallets).unwrap();
     }
 }
+
+// remembers a transaction submitted to the network, so its fee can be bumped later
+pub fn save_sent_tx(node_id: &str, tx: &Transaction) -> Result<(), String> {
+    let mut sent_txs: HashMap<String, Transaction> = load_map(SENT_TXS_FILE, node_id);
+    sent_txs.insert(hex::encode(tx.get_id()), tx.clone());
+    save_map(SENT_TXS_FILE, node_id, &sent_txs)
+}
+
+// returns a transaction the wallets submitted
+pub fn find_sent_tx(node_id: &str, txid: &str) -> Option<Transaction> {
+    load_map(SENT_TXS_FILE, node_id).remove(txid)
+}
+
+// the transactions the wallets submitted, by hex id
+pub fn get_sent_txs(node_id: &str) -> HashMap<String, Transaction> {
+    load_map(SENT_TXS_FILE, node_id)
+}
+
+// remembers the redeem script of a multisig address, so its outputs can be spent
+pub fn save_redeem_script(
+    node_id: &str,
+    address: &str,
+    redeem_script: &[u8],
+) -> Result<(), String> {
+    let mut redeem_scripts = get_redeem_scripts(node_id);
+    redeem_scripts.insert(address.to_string(), redeem_script.to_vec());
+    save_map(REDEEM_SCRIPTS_FILE, node_id, &redeem_scripts)
+}
+
+// the redeem scripts of the multisig addresses created with these wallets, by address
+pub fn get_redeem_scripts(node_id: &str) -> HashMap<String, Vec<u8>> {
+    load_map(REDEEM_SCRIPTS_FILE, node_id)
+}
+
+fn load_map<T: DeserializeOwned>(file: &str, node_id: &str) -> HashMap<String, T> {
+    let path = current_dir().unwrap().join(file.replace("{}", node_id));
+    fs::read(path)
+        .ok()
+        .and_then(|data| bincode::deserialize(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_map<T: Serialize>(
+    file: &str,
+    node_id: &str,
+    map: &HashMap<String, T>,
+) -> Result<(), String> {
+    let path = current_dir().unwrap().join(file.replace("{}", node_id));
+    fs::write(path, bincode::serialize(map).unwrap()).map_err(|e| e.to_string())
+}